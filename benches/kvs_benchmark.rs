@@ -1,21 +1,52 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::client::KvsClient;
+use kvs::durability::Durability;
 use kvs::engines::{KvStore, SledKvStore, KvsEngine};
+use kvs::server::{KvsServer, ServerMode};
+use kvs::thread_pool::naive_thread_pool::NaiveThreadPool;
+use kvs::thread_pool::rayon_thread_pool::RayonThreadPool;
+use kvs::thread_pool::shared_queue_thread_pool::SharedQueueThreadPool;
+use kvs::thread_pool::ThreadPool;
 
 use rand::distributions::{Alphanumeric, DistString};
 use rand::rngs::StdRng;
 use rand::SeedableRng;
 
+///
+/// Every durability policy the write benchmarks are run under
+///
+fn durabilities() -> [(&'static str, Durability); 3] {
+    [
+        ("sync", Durability::Sync),
+        (
+            "periodic",
+            Durability::Periodic {
+                interval: Duration::from_millis(100),
+                bytes: 1024 * 1024,
+            },
+        ),
+        ("buffered", Durability::Buffered),
+    ]
+}
+
 fn kv_store(c: &mut Criterion) {
+    for (name, durability) in durabilities() {
+        kv_store_with_durability(c, name, durability);
+    }
+}
+
+fn kv_store_with_durability(c: &mut Criterion, name: &str, durability: Durability) {
 
     let _ = std::fs::remove_dir_all("./logs");
     let _ = std::fs::create_dir("./logs");
 
     let mut rng = StdRng::seed_from_u64(1234);
 
-    println!("Opening sled");
-    let mut kv_store = KvStore::open(None, PathBuf::from("./logs")).unwrap();
+    println!("Opening kvs");
+    let mut kv_store = KvStore::open_with_durability(None, PathBuf::from("./logs"), durability).unwrap();
 
     println!("Creating keys");
     let mut keys: Vec<String> = Vec::new();
@@ -34,7 +65,7 @@ fn kv_store(c: &mut Criterion) {
     let mut i = 0;
 
     println!("Benchmarking writes");
-    c.bench_function("kv_write", |b| {
+    c.bench_function(&format!("kv_write_{}", name), |b| {
 	    b.iter(|| {
             i %= keys.len();
 		    kv_store.set(keys.get(i).unwrap().clone(), values.get(i).unwrap().clone()).unwrap();
@@ -43,7 +74,7 @@ fn kv_store(c: &mut Criterion) {
 	});
 
     println!("Benchmarking reads");
-    c.bench_function("kv_read", |b| {
+    c.bench_function(&format!("kv_read_{}", name), |b| {
 	    b.iter(|| {
             i %= keys.len();
 		    kv_store.get(keys.get(i).unwrap().clone()).unwrap();
@@ -52,7 +83,147 @@ fn kv_store(c: &mut Criterion) {
 	});
 }
 
+///
+/// Compare reads through the file against reads from memory maps, over a
+/// log large enough that nearly every record lives in a sealed file
+///
+fn kv_store_mmap_reads(c: &mut Criterion) {
+
+    let _ = std::fs::remove_dir_all("./logs");
+    let _ = std::fs::create_dir("./logs");
+
+    let mut rng = StdRng::seed_from_u64(1234);
+
+    println!("Opening kvs");
+    let kv_store = KvStore::open_with_durability(None, PathBuf::from("./logs"), Durability::Buffered).unwrap();
+
+    println!("Creating keys");
+    let mut keys: Vec<String> = Vec::new();
+    let dist = Alphanumeric{};
+
+    for _ in 0..8192 {
+        keys.push(dist.sample_string(&mut rng, 16));
+    }
+
+    println!("Writing values");
+    for key in &keys {
+        kv_store.set(key.clone(), dist.sample_string(&mut rng, 1024)).unwrap();
+    }
+
+    for (name, enabled) in [("read", false), ("mmap", true)] {
+        let kv_store = kv_store.clone().with_mmap_reads(enabled);
+        let mut i = 0;
+
+        println!("Benchmarking {} reads", name);
+        c.bench_function(&format!("kv_sealed_read_{}", name), |b| {
+            b.iter(|| {
+                i %= keys.len();
+                kv_store.get(keys.get(i).unwrap().clone()).unwrap();
+                i += 1;
+            });
+        });
+    }
+}
+
+///
+/// Compare the threaded and async servers with many connections open, each
+/// read from in turn by a handful of client threads
+///
+fn server_modes(c: &mut Criterion) {
+    let modes = [
+        ("threaded", ServerMode::Threaded, "127.0.0.1:4200"),
+        ("async", ServerMode::Async { worker_threads: 4 }, "127.0.0.1:4201"),
+    ];
+
+    for (name, mode, addr) in modes {
+        let path = PathBuf::from(format!("./server-logs-{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::create_dir(&path);
+
+        println!("Starting {} server", name);
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let kv_store = KvStore::open_with_durability(None, path, Durability::Buffered).unwrap();
+        for i in 0..1024 {
+            kv_store.set(format!("key{}", i), "v".repeat(1024)).unwrap();
+        }
+        let server = KvsServer::new(addr.to_owned(), logger.clone(), kv_store).with_mode(mode);
+        std::thread::spawn(move || server.run());
+        std::thread::sleep(Duration::from_millis(200));
+
+        let mut clients: Vec<KvsClient> = (0..256)
+            .map(|_| KvsClient::new(logger.clone(), addr.to_owned()).unwrap())
+            .collect();
+
+        println!("Benchmarking {} server", name);
+        c.bench_function(&format!("server_get_256_connections_{}", name), |b| {
+            b.iter(|| {
+                std::thread::scope(|s| {
+                    for (thread, clients) in clients.chunks_mut(32).enumerate() {
+                        s.spawn(move || {
+                            for (i, client) in clients.iter_mut().enumerate() {
+                                client.get(format!("key{}", thread * 32 + i)).unwrap();
+                            }
+                        });
+                    }
+                });
+            });
+        });
+    }
+}
+
+fn server_pools(c: &mut Criterion) {
+    server_pool::<NaiveThreadPool>(c, "naive", "127.0.0.1:4210");
+    server_pool::<SharedQueueThreadPool>(c, "shared_queue", "127.0.0.1:4211");
+    server_pool::<RayonThreadPool>(c, "rayon", "127.0.0.1:4212");
+}
+
+///
+/// Run the threaded server on pool P, under 8 client threads which each
+/// connect, alternate between writes and reads of their own keys, then
+/// disconnect, so connections are handed to the pool throughout
+///
+fn server_pool<P: ThreadPool + Send + Sync + 'static>(c: &mut Criterion, name: &str, addr: &str) {
+    let path = PathBuf::from(format!("./server-logs-{}", name));
+    let _ = std::fs::remove_dir_all(&path);
+    let _ = std::fs::create_dir(&path);
+
+    println!("Starting server on {} pool", name);
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let kv_store = KvStore::open_with_durability(None, path, Durability::Buffered).unwrap();
+    let server = KvsServer::new(addr.to_owned(), logger.clone(), kv_store).with_pool(P::new(8).unwrap());
+    std::thread::spawn(move || server.run());
+    std::thread::sleep(Duration::from_millis(200));
+
+    println!("Benchmarking {} pool", name);
+    c.bench_function(&format!("server_mixed_load_{}_pool", name), |b| {
+        b.iter(|| {
+            std::thread::scope(|s| {
+                for thread in 0..8 {
+                    let logger = logger.clone();
+                    s.spawn(move || {
+                        let mut client = KvsClient::new(logger, addr.to_owned()).unwrap();
+                        for i in 0..10 {
+                            let key = format!("key{}-{}", thread, i % 5);
+                            if i % 2 == 0 {
+                                client.set(key, "v".repeat(256)).unwrap();
+                            } else {
+                                client.get(key).unwrap();
+                            }
+                        }
+                    });
+                }
+            });
+        });
+    });
+}
+
 fn sled_store(c: &mut Criterion) {
+    for (name, durability) in durabilities() {
+        sled_store_with_durability(c, name, durability);
+    }
+}
+
+fn sled_store_with_durability(c: &mut Criterion, name: &str, durability: Durability) {
 
     let _ = std::fs::remove_dir_all("./logs");
     let _ = std::fs::create_dir("./logs");
@@ -60,7 +231,7 @@ fn sled_store(c: &mut Criterion) {
     let mut rng = StdRng::seed_from_u64(1234);
 
     println!("Opening sled");
-    let mut sled_store = SledKvStore::open("./logs").unwrap();
+    let mut sled_store = SledKvStore::open_with_durability("./logs", durability).unwrap();
 
     println!("Creating keys");
     let mut keys: Vec<String> = Vec::new();
@@ -79,7 +250,7 @@ fn sled_store(c: &mut Criterion) {
     let mut i = 0;
 
     println!("Benchmarking writes");
-    c.bench_function("sled_write", |b| {
+    c.bench_function(&format!("sled_write_{}", name), |b| {
 	    b.iter(|| {
             i %= keys.len();
 		    sled_store.set(keys.get(i).unwrap().clone(), values.get(i).unwrap().clone()).unwrap();
@@ -88,7 +259,7 @@ fn sled_store(c: &mut Criterion) {
 	});
 
     println!("Benchmarking reads");
-    c.bench_function("sled_read", |b| {
+    c.bench_function(&format!("sled_read_{}", name), |b| {
 	    b.iter(|| {
             i %= keys.len();
 		    sled_store.get(keys.get(i).unwrap().clone()).unwrap();
@@ -98,5 +269,5 @@ fn sled_store(c: &mut Criterion) {
 }
 
 
-criterion_group!(benches, sled_store, kv_store);
+criterion_group!(benches, sled_store, kv_store, kv_store_mmap_reads, server_modes, server_pools);
 criterion_main!(benches);
\ No newline at end of file
@@ -1,15 +1,30 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 use slog::{Drain, o};
 
-use kvs::client::KvsClient;
+use kvs::client::{KvsClient, ScanOptions};
+use kvs::tls::ClientTls;
 
 #[derive(Debug, Parser)] // requires `derive` feature
 #[command(name = env!("CARGO_PKG_NAME"), about = env!("CARGO_PKG_DESCRIPTION"), author = env!("CARGO_PKG_AUTHORS"), version = env!("CARGO_PKG_VERSION"))]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Connect over TLS, trusting servers with a certificate issued by one
+    /// of the PEM CA certificates in this file
+    #[arg(long = "tls-ca", global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM certificate chain to present to servers which require one
+    #[arg(long = "tls-cert", global = true, requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long = "tls-key", global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -35,6 +50,66 @@ enum Commands {
         #[arg(value_name = "KEY")]
         key: String,
 
+        #[arg(long = "addr", default_value = "127.0.0.1:4000")]
+        addr: String
+    },
+    /// Get several keys in one request
+    #[command(name = "mget")]
+    MultiGet {
+        #[arg(value_name = "KEY", required = true)]
+        keys: Vec<String>,
+
+        #[arg(long = "addr", default_value = "127.0.0.1:4000")]
+        addr: String
+    },
+    /// Set several keys in one request
+    #[command(name = "mset")]
+    MultiSet {
+        #[arg(value_name = "KEY VALUE", required = true)]
+        pairs: Vec<String>,
+
+        #[arg(long = "addr", default_value = "127.0.0.1:4000")]
+        addr: String
+    },
+    /// Remove several keys in one request
+    #[command(name = "mrm")]
+    MultiRm {
+        #[arg(value_name = "KEY", required = true)]
+        keys: Vec<String>,
+
+        #[arg(long = "addr", default_value = "127.0.0.1:4000")]
+        addr: String
+    },
+    /// List keys and values in ascending key order
+    Scan {
+        /// Only list keys starting with PREFIX
+        #[arg(long = "prefix")]
+        prefix: Option<String>,
+
+        /// Only list keys from START onwards
+        #[arg(long = "start")]
+        start: Option<String>,
+
+        /// Only list keys before END
+        #[arg(long = "end")]
+        end: Option<String>,
+
+        /// Entries fetched from the server per request
+        #[arg(long = "page-size", default_value_t = 100)]
+        page_size: u32,
+
+        #[arg(long = "addr", default_value = "127.0.0.1:4000")]
+        addr: String
+    },
+    Stats {
+        #[arg(long = "addr", default_value = "127.0.0.1:4000")]
+        addr: String
+    },
+    /// Limit the server's compaction I/O, or lift the limit if no rate is given
+    CompactionRate {
+        #[arg(value_name = "BYTES_PER_SEC")]
+        bytes_per_second: Option<u64>,
+
         #[arg(long = "addr", default_value = "127.0.0.1:4000")]
         addr: String
     },
@@ -48,9 +123,18 @@ fn main() -> Result<()> {
     
     let cli = Cli::parse();
 
+    let tls = match &cli.tls_ca {
+        Some(ca) => {
+            let identity = cli.tls_cert.as_deref().zip(cli.tls_key.as_deref());
+            Some(ClientTls::from_pem_files(ca, identity)?)
+        }
+        None => None,
+    };
+    let connect = |addr: String| KvsClient::connect(logger, addr, tls.as_ref());
+
     match cli.command {
         Commands::Get { key, addr } => {
-            let mut client = KvsClient::new(logger, addr)?;
+            let mut client = connect(addr)?;
             match client.get(key)? {
                 Some(value) => {
                     println!("Found value: {}", value);
@@ -61,15 +145,96 @@ fn main() -> Result<()> {
             }
         },
         Commands::Set { key, value, addr } => {
-            let mut client = KvsClient::new(logger, addr)?;
+            let mut client = connect(addr)?;
             client.set(key.clone(), value.clone())?;
             println!("Set {} => {}", key, value);
         }
         Commands::Rm { key, addr } => {
-            let mut client = KvsClient::new(logger, addr)?;
+            let mut client = connect(addr)?;
             client.rm(key.clone())?;
             println!("Removed {}", key);
         }
+        Commands::MultiGet { keys, addr } => {
+            let mut client = connect(addr)?;
+            let results = client.multi_get(keys.clone())?;
+            report(keys.into_iter().zip(results).map(|(key, result)| {
+                result.map(|value| match value {
+                    Some(value) => format!("{}: {}", key, value),
+                    None => format!("{}: Missing value", key),
+                }).map_err(|err| (key, err))
+            }))?;
+        }
+        Commands::MultiSet { pairs, addr } => {
+            if pairs.len() % 2 != 0 {
+                return Err(Error::new(ErrorKind::InvalidInput, "mset takes pairs of KEY VALUE"));
+            }
+            let pairs: Vec<(String, String)> = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            let mut client = connect(addr)?;
+            let results = client.multi_set(pairs.clone())?;
+            report(pairs.into_iter().zip(results).map(|((key, value), result)| {
+                result.map(|_| format!("Set {} => {}", key, value)).map_err(|err| (key, err))
+            }))?;
+        }
+        Commands::MultiRm { keys, addr } => {
+            let mut client = connect(addr)?;
+            let results = client.multi_rm(keys.clone())?;
+            report(keys.into_iter().zip(results).map(|(key, result)| {
+                result.map(|_| format!("Removed {}", key)).map_err(|err| (key, err))
+            }))?;
+        }
+        Commands::Scan { prefix, start, end, page_size, addr } => {
+            let mut options = ScanOptions::new().with_page_size(page_size);
+            if let Some(prefix) = prefix {
+                options = options.with_prefix(prefix);
+            }
+            if let Some(start) = start {
+                options = options.with_start(start);
+            }
+            if let Some(end) = end {
+                options = options.with_end(end);
+            }
+            let mut client = connect(addr)?;
+            for entry in client.scan(options) {
+                let (key, value) = entry?;
+                println!("{} => {}", key, value);
+            }
+        }
+        Commands::Stats { addr } => {
+            let mut client = connect(addr)?;
+            print!("{}", client.stats()?);
+        }
+        Commands::CompactionRate { bytes_per_second, addr } => {
+            let mut client = connect(addr)?;
+            client.set_compaction_rate(bytes_per_second)?;
+            match bytes_per_second {
+                Some(bytes_per_second) => println!("Compaction limited to {} bytes/s", bytes_per_second),
+                None => println!("Compaction rate limit removed"),
+            }
+        }
     };
     Ok(())
-}
\ No newline at end of file
+}
+
+///
+/// Print the outcome for each key of a multi-key command, failing if any key
+/// failed
+///
+fn report(results: impl Iterator<Item = std::result::Result<String, (String, Error)>>) -> Result<()> {
+    let mut failed = 0;
+    for result in results {
+        match result {
+            Ok(line) => println!("{}", line),
+            Err((key, err)) => {
+                eprintln!("{}: {}", key, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::other(format!("{} keys failed", failed)));
+    }
+    Ok(())
+}
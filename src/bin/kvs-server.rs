@@ -1,24 +1,141 @@
 use std::{
-    io::Error,
+    io::{Error, ErrorKind},
     path::PathBuf,
+    time::Duration,
 };
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use kvs::{
-    engines::{KvStore, SledKvStore},
-    server::KvsServer,
+    durability::Durability,
+    engines::{record_engine, resolve_engine, KvStore, KvsEngine, SledKvStore},
+    limits::{ConnectionLimits, Limits},
+    server::{KvsServer, ServerMode},
+    thread_pool::{
+        rayon_thread_pool::RayonThreadPool, shared_queue_thread_pool::SharedQueueThreadPool,
+        ThreadPool,
+    },
+    tls::ServerTls,
 };
 use slog::{o, Drain};
 use std::io::Result;
 
+#[derive(Clone, Copy, ValueEnum)]
+enum DurabilityMode {
+    /// Sync every write before acknowledging it
+    Sync,
+    /// Sync every --sync-interval-ms or --sync-bytes, whichever comes first
+    Periodic,
+    /// Leave writeback to the OS
+    Buffered,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    /// Serve each connection on its own thread
+    Threaded,
+    /// Multiplex connections on a small pool of threads
+    Async,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PoolKind {
+    /// Start a new thread for every connection
+    Naive,
+    /// Fixed set of threads taking connections from a shared queue
+    SharedQueue,
+    /// Fixed set of rayon threads
+    Rayon,
+}
+
 #[derive(Parser)] // requires `derive` feature
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[arg(default_value = "127.0.0.1:4000")]
     addr: String,
 
+    /// Storage engine, kvs or sled. Defaults to the engine the data
+    /// directory already holds, or kvs for a new directory
     #[arg(long = "engine")]
     engine: Option<String>,
+
+    #[arg(long = "durability", value_enum, default_value = "sync")]
+    durability: DurabilityMode,
+
+    #[arg(long = "sync-interval-ms", default_value_t = 100)]
+    sync_interval_ms: u64,
+
+    #[arg(long = "sync-bytes", default_value_t = 1024 * 1024)]
+    sync_bytes: u64,
+
+    #[arg(long = "max-key-size", default_value_t = Limits::default().max_key_size)]
+    max_key_size: usize,
+
+    #[arg(long = "max-value-size", default_value_t = Limits::default().max_value_size)]
+    max_value_size: usize,
+
+    /// Largest request accepted, checked before the request is read
+    #[arg(long = "max-frame-size", default_value_t = Limits::default().max_frame_size)]
+    max_frame_size: u64,
+
+    /// Close connections left idle this long between requests, or never
+    /// with 0
+    #[arg(long = "idle-timeout-ms", default_value_t = as_millis(ConnectionLimits::default().idle_timeout))]
+    idle_timeout_ms: u64,
+
+    /// Close connections whose request takes this long to arrive in full,
+    /// or never with 0
+    #[arg(long = "read-timeout-ms", default_value_t = as_millis(ConnectionLimits::default().read_timeout))]
+    read_timeout_ms: u64,
+
+    /// Close connections which take this long to accept a response, or
+    /// never with 0
+    #[arg(long = "write-timeout-ms", default_value_t = as_millis(ConnectionLimits::default().write_timeout))]
+    write_timeout_ms: u64,
+
+    /// Most connections open at once, beyond which connections are turned
+    /// away with an error. Unlimited by default
+    #[arg(long = "max-connections")]
+    max_connections: Option<usize>,
+
+    /// Requests served on one connection before it is closed. Unlimited by
+    /// default
+    #[arg(long = "max-requests-per-connection")]
+    max_requests_per_connection: Option<u64>,
+
+    /// Serve reads of sealed log files from memory maps. Only used by the
+    /// kvs engine
+    #[arg(long = "mmap-reads")]
+    mmap_reads: bool,
+
+    #[arg(long = "mode", value_enum, default_value = "threaded")]
+    mode: Mode,
+
+    /// Threads multiplexing connections in async mode. Defaults to the
+    /// number of CPUs
+    #[arg(long = "async-threads", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    async_threads: Option<usize>,
+
+    /// Thread pool serving connections in threaded mode
+    #[arg(long = "pool", value_enum, default_value = "naive")]
+    pool: PoolKind,
+
+    /// Threads in the shared-queue or rayon pool, each serving one
+    /// connection at a time. Defaults to the number of CPUs
+    #[arg(long = "threads", value_parser = clap::value_parser!(u16).range(1..))]
+    threads: Option<u16>,
+
+    /// Serve over TLS with the PEM certificate chain in this file
+    #[arg(long = "tls-cert", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long = "tls-key", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require clients to present a certificate issued by one of the PEM
+    /// CA certificates in this file
+    #[arg(long = "tls-ca", requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
@@ -28,17 +145,121 @@ fn main() -> Result<()> {
     let logger = slog::Logger::root(drain, o!("module" => "server"));
 
     let cli = Cli::parse();
+    let path = PathBuf::from("./log");
+
+    let durability = match cli.durability {
+        DurabilityMode::Sync => Durability::Sync,
+        DurabilityMode::Periodic => Durability::Periodic {
+            interval: Duration::from_millis(cli.sync_interval_ms),
+            bytes: cli.sync_bytes,
+        },
+        DurabilityMode::Buffered => Durability::Buffered,
+    };
+
+    let limits = Limits {
+        max_key_size: cli.max_key_size,
+        max_value_size: cli.max_value_size,
+        max_frame_size: cli.max_frame_size,
+    };
+
+    let connection_limits = ConnectionLimits {
+        read_timeout: timeout(cli.read_timeout_ms),
+        write_timeout: timeout(cli.write_timeout_ms),
+        idle_timeout: timeout(cli.idle_timeout_ms),
+        max_connections: cli.max_connections,
+        max_requests_per_connection: cli.max_requests_per_connection,
+    };
+
+    let mode = match cli.mode {
+        Mode::Threaded => ServerMode::Threaded,
+        Mode::Async => ServerMode::Async {
+            worker_threads: match cli.async_threads {
+                Some(threads) => threads,
+                None => std::thread::available_parallelism()?.get(),
+            },
+        },
+    };
+
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(ServerTls::from_pem_files(cert, key, cli.tls_ca.as_deref())?),
+        _ => None,
+    };
 
-    match cli.engine.unwrap_or("".to_string()).as_str() {
-        "kvs" => Ok(KvsServer::new(
-            cli.addr,
-            logger.clone(),
-            KvStore::open(Some(logger), PathBuf::from("./log"))?,
-        )
-        .run()?),
-        "sled" => Ok(
-            KvsServer::new(cli.addr, logger, SledKvStore::open(PathBuf::from("./log"))?).run()?,
-        ),
+    if cli.threads.is_some() && (matches!(cli.mode, Mode::Async) || matches!(cli.pool, PoolKind::Naive)) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--threads only applies to the shared-queue and rayon pools in threaded mode",
+        ));
+    }
+    let threads = match cli.threads {
+        Some(threads) => threads,
+        None => u16::try_from(std::thread::available_parallelism()?.get()).unwrap_or(u16::MAX),
+    };
+
+    match resolve_engine(&path, cli.engine.as_deref())?.as_str() {
+        "kvs" => {
+            let engine = KvStore::open_with_durability(Some(logger.clone()), path.clone(), durability)?
+                .with_limits(limits)
+                .with_mmap_reads(cli.mmap_reads);
+            record_engine(&path, "kvs")?;
+            run_on_pool(
+                KvsServer::new(cli.addr, logger, engine)
+                    .with_limits(limits)
+                    .with_connection_limits(connection_limits)
+                    .with_mode(mode),
+                cli.pool,
+                threads,
+                tls,
+            )
+        }
+        "sled" => {
+            let engine = SledKvStore::open_with_durability(&path, durability)?.with_limits(limits);
+            record_engine(&path, "sled")?;
+            run_on_pool(
+                KvsServer::new(cli.addr, logger, engine)
+                    .with_limits(limits)
+                    .with_connection_limits(connection_limits)
+                    .with_mode(mode),
+                cli.pool,
+                threads,
+                tls,
+            )
+        }
         _ => Err(Error::other("Unknown storage engine")),
     }
 }
+
+///
+/// Run server on the kind of thread pool chosen on the command line, until
+/// SIGINT or SIGTERM shuts it down
+///
+fn run_on_pool<Engine: KvsEngine + Sync>(
+    server: KvsServer<Engine>,
+    pool: PoolKind,
+    threads: u16,
+    tls: Option<ServerTls>,
+) -> Result<()> {
+    let server = match tls {
+        Some(tls) => server.with_tls(tls),
+        None => server,
+    };
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.shutdown()).map_err(Error::other)?;
+
+    match pool {
+        PoolKind::Naive => server.run(),
+        PoolKind::SharedQueue => server.with_pool(SharedQueueThreadPool::new(threads)?).run(),
+        PoolKind::Rayon => server.with_pool(RayonThreadPool::new(threads)?).run(),
+    }
+}
+
+///
+/// Timeout of ms milliseconds, where 0 means none
+///
+fn timeout(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+fn as_millis(timeout: Option<Duration>) -> u64 {
+    timeout.map_or(0, |timeout| timeout.as_millis() as u64)
+}
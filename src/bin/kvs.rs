@@ -1,9 +1,13 @@
 use std::io::Result;
 use std::io::Write;
-use std::path::Path;
+use std::io::Error;
+use std::path::{Path, PathBuf};
 
 use clap::{Parser, Subcommand};
-use kvs::engines::KvsEngine;
+use kvs::dump::{DumpOptions, OperationKind};
+use kvs::engines::{detect_engine, record_engine, resolve_engine, KvStore, KvsEngine, SledKvStore};
+use kvs::migrate::{MigrateCheckpoint, MigrateProgress};
+use slog::Logger;
 use slog::o;
 use slog::Drain;
 
@@ -12,6 +16,11 @@ use slog::Drain;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Open the store without modifying it, rejecting set and rm. Works
+    /// while a server has the store open
+    #[arg(long = "read-only", global = true)]
+    read_only: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -30,6 +39,137 @@ enum Commands {
         #[arg(value_name = "KEY")]
         key: String,
     },
+    /// Validate the MANIFEST and log files of a store without opening it
+    Check {
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+
+        /// Truncate corrupt tails and drop missing files from the MANIFEST
+        #[arg(long = "repair")]
+        repair: bool,
+    },
+    /// Print the MANIFEST and the records stored in a log
+    Dump {
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+
+        /// Only walk a single log file
+        #[arg(long = "file")]
+        file: Option<u16>,
+
+        #[arg(long = "key")]
+        key: Option<String>,
+
+        #[arg(long = "min-index")]
+        min_index: Option<u64>,
+
+        #[arg(long = "max-index")]
+        max_index: Option<u64>,
+
+        #[arg(long = "op", value_enum)]
+        operation: Option<OperationKind>,
+
+        /// Print values in full instead of truncating them
+        #[arg(long = "full")]
+        full: bool,
+
+        /// Print one JSON object per line
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Copy every key from one store into another, possibly of a different engine
+    Migrate {
+        /// Source store, as <engine>:<dir> where engine is kvs or sled
+        #[arg(long = "from", value_parser = parse_store)]
+        from: (String, PathBuf),
+
+        /// Destination store, as <engine>:<dir> where engine is kvs or sled
+        #[arg(long = "to", value_parser = parse_store)]
+        to: (String, PathBuf),
+    },
+}
+
+fn parse_store(spec: &str) -> std::result::Result<(String, PathBuf), String> {
+    match spec.split_once(':') {
+        Some((engine @ ("kvs" | "sled"), dir)) if !dir.is_empty() => {
+            Ok((engine.to_string(), PathBuf::from(dir)))
+        }
+        _ => Err(format!("expected kvs:<dir> or sled:<dir>, got {}", spec)),
+    }
+}
+
+///
+/// Checkpoint for a migration into dir, kept beside it rather than inside it
+/// so the destination holds nothing but the store's own files
+///
+fn migrate_checkpoint(dir: &Path) -> Result<PathBuf> {
+    let dir = std::fs::canonicalize(dir)?;
+    match dir.file_name() {
+        Some(name) => Ok(dir.with_file_name(format!("{}.MIGRATE", name.to_string_lossy()))),
+        None => Err(Error::other("Destination must not be the root directory")),
+    }
+}
+
+fn run_migrate<S: KvsEngine, D: KvsEngine>(
+    source: S,
+    destination: D,
+    from: &(String, PathBuf),
+    to: &(String, PathBuf),
+) -> Result<()> {
+    record_engine(&from.1, &from.0)?;
+    record_engine(&to.1, &to.0)?;
+    let checkpoint = MigrateCheckpoint {
+        path: migrate_checkpoint(&to.1)?,
+        source: std::fs::canonicalize(&from.1)?,
+        destination_engine: to.0.clone(),
+    };
+
+    let report = kvs::migrate::migrate(
+        &source,
+        &destination,
+        &checkpoint,
+        |progress: &MigrateProgress| {
+            writeln!(std::io::stdout(), "Copied {}/{} keys", progress.done, progress.total)
+        },
+    )?;
+    writeln!(
+        std::io::stdout(),
+        "Migrated {} keys ({} resumed from checkpoint), verified {}",
+        report.copied,
+        report.skipped,
+        report.verified
+    )
+}
+
+fn open_migrate(logger: Logger, from: (String, PathBuf), to: (String, PathBuf)) -> Result<()> {
+    if from.1 == to.1 {
+        return Err(Error::other("Source and destination must be different directories"));
+    }
+    resolve_engine(&from.1, Some(&from.0))?;
+    resolve_engine(&to.1, Some(&to.0))?;
+
+    let kvs_logger = || Some(logger.clone());
+    match (from.0.as_str(), to.0.as_str()) {
+        ("kvs", "kvs") => run_migrate(
+            KvStore::open(kvs_logger(), from.1.clone())?,
+            KvStore::open(kvs_logger(), to.1.clone())?,
+            &from,
+            &to,
+        ),
+        ("kvs", _) => run_migrate(
+            KvStore::open(kvs_logger(), from.1.clone())?,
+            SledKvStore::open(&to.1)?,
+            &from,
+            &to,
+        ),
+        (_, "kvs") => run_migrate(
+            SledKvStore::open(&from.1)?,
+            KvStore::open(kvs_logger(), to.1.clone())?,
+            &from,
+            &to,
+        ),
+        _ => run_migrate(SledKvStore::open(&from.1)?, SledKvStore::open(&to.1)?, &from, &to),
+    }
 }
 
 fn main() -> Result<()> {
@@ -40,8 +180,59 @@ fn main() -> Result<()> {
     let drain = slog_async::Async::new(drain).build().fuse();
     let logger = slog::Logger::root(drain, o!("module" => "Log"));
 
+    if let Commands::Check { dir, repair } = cli.command {
+        let report = kvs::check::check(Some(logger), dir, repair)?;
+        write!(std::io::stdout(), "{}", report)?;
+        if !report.is_ok() {
+            return Err(Error::other("Integrity check failed"));
+        }
+        return Ok(());
+    }
+
+    if let Commands::Dump {
+        dir,
+        file,
+        key,
+        min_index,
+        max_index,
+        operation,
+        full,
+        json,
+    } = cli.command
+    {
+        let options = DumpOptions {
+            file_number: file,
+            key,
+            min_index,
+            max_index,
+            operation,
+            full_values: full,
+            json,
+        };
+        return kvs::dump::dump(dir, &options, &mut std::io::stdout().lock());
+    }
+
+    if let Commands::Migrate { from, to } = cli.command {
+        return open_migrate(logger, from, to);
+    }
+
     let path = Path::new("./log");
-    let kvs = kvs::engines::KvStore::open(Some(logger), path.to_path_buf())?;
+    let kvs = if cli.read_only {
+        // Resolving the engine would create the directory, so only check it
+        if let Some(engine) = detect_engine(path)?.filter(|engine| engine != "kvs") {
+            return Err(Error::other(format!(
+                "engine mismatch: {} holds a {} store but kvs was requested",
+                path.display(),
+                engine
+            )));
+        }
+        KvStore::open_read_only(Some(logger), path.to_path_buf())?
+    } else {
+        resolve_engine(path, Some("kvs"))?;
+        let kvs = KvStore::open(Some(logger), path.to_path_buf())?;
+        record_engine(path, "kvs")?;
+        kvs
+    };
 
     writeln!(std::io::stdout(), "Finished opening kvstore")?;
 
@@ -63,6 +254,9 @@ fn main() -> Result<()> {
             kvs.remove(key.to_string())?;
             writeln!(std::io::stdout(), "Removed {}", key)?;
         }
+        Commands::Check { .. } | Commands::Dump { .. } | Commands::Migrate { .. } => {
+            unreachable!()
+        }
     }
     Ok(())
 }
@@ -0,0 +1,328 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use slog::{info, warn, Logger};
+
+use crate::engines::ENGINE_MARKER;
+use crate::fs::OsFileSystem;
+use crate::log::{
+    DirectoryLock, FileManifestHeader, FileManifestRecord, Log, LogOperation, RecordScanner,
+    MANIFEST_MAGIC_NUMBER,
+};
+
+///
+/// Summary of a single log file listed in the MANIFEST
+///
+pub struct FileReport {
+    pub file_number: u16,
+    pub min_index: u64,
+    pub max_index: u64,
+
+    // Total records which could be read from the file
+    pub records: u64,
+
+    // Records holding the current value of a key
+    pub live_records: u64,
+
+    // Overwritten values and tombstones which compaction could reclaim
+    pub dead_records: u64,
+
+    // Length of the readable prefix of the file. Anything past this offset
+    // is a corrupt tail
+    pub valid_len: u64,
+    pub file_len: u64,
+}
+
+///
+/// Result of an offline integrity check over a log directory
+///
+pub struct CheckReport {
+    pub files: Vec<FileReport>,
+
+    // Every inconsistency found, in the order it was detected
+    pub problems: Vec<String>,
+
+    // Every change made to the directory when repair was requested
+    pub repairs: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for file in &self.files {
+            writeln!(
+                f,
+                "{}.log: index {}..={}, {} records ({} live, {} dead), {}/{} bytes readable",
+                file.file_number,
+                file.min_index,
+                file.max_index,
+                file.records,
+                file.live_records,
+                file.dead_records,
+                file.valid_len,
+                file.file_len
+            )?;
+        }
+        for problem in &self.problems {
+            writeln!(f, "problem: {}", problem)?;
+        }
+        for repair in &self.repairs {
+            writeln!(f, "repaired: {}", repair)?;
+        }
+        if self.is_ok() {
+            writeln!(f, "OK")?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Read the MANIFEST without trusting its contents, validating the header
+/// and each record. Returns None when the MANIFEST cannot be used at all
+///
+pub(crate) fn read_manifest(
+    path: &Path,
+    problems: &mut Vec<String>,
+) -> Result<Option<Vec<FileManifestRecord>>> {
+    let mut file = match File::open(path.join("MANIFEST")) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            problems.push("MANIFEST is missing".to_string());
+            return Ok(None);
+        }
+        Err(err) => return Err(err),
+    };
+
+    let header: FileManifestHeader = match bincode::deserialize_from(&mut file) {
+        Ok(header) => header,
+        Err(err) => {
+            problems.push(format!("MANIFEST header is unreadable: {}", err));
+            return Ok(None);
+        }
+    };
+
+    if header.magic_number != MANIFEST_MAGIC_NUMBER {
+        problems.push(format!(
+            "MANIFEST has bad magic number {:#x}, expected {:#x}",
+            header.magic_number, MANIFEST_MAGIC_NUMBER
+        ));
+        return Ok(None);
+    }
+
+    let mut records = Vec::new();
+    for i in 0..header.entry_count {
+        match bincode::deserialize_from::<_, FileManifestRecord>(&mut file) {
+            Ok(record) => records.push(record),
+            Err(err) => {
+                problems.push(format!(
+                    "MANIFEST record {} of {} is unreadable: {}",
+                    i, header.entry_count, err
+                ));
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some(records))
+}
+
+///
+/// Validate the index ranges in the MANIFEST. Each file must have
+/// min_index <= max_index and no two files may cover the same index
+///
+fn check_ranges(records: &[FileManifestRecord], problems: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    for record in records {
+        if !seen.insert(record.file_number) {
+            problems.push(format!("{}.log is listed more than once", record.file_number));
+        }
+        if record.min_index > record.max_index {
+            problems.push(format!(
+                "{}.log has min_index {} greater than max_index {}",
+                record.file_number, record.min_index, record.max_index
+            ));
+        }
+    }
+
+    for pair in records.windows(2) {
+        if pair[0].max_index >= pair[1].min_index {
+            problems.push(format!(
+                "{}.log (index {}..={}) overlaps {}.log (index {}..={})",
+                pair[0].file_number,
+                pair[0].min_index,
+                pair[0].max_index,
+                pair[1].file_number,
+                pair[1].min_index,
+                pair[1].max_index
+            ));
+        }
+    }
+}
+
+///
+/// Run an offline integrity check against the log stored in path. The
+/// directory should not be in use by a running store. When repair is set,
+/// the directory lock is taken, corrupt tails are truncated back to the last
+/// complete record and files listed in the MANIFEST but missing on disk are
+/// dropped from it
+///
+pub fn check(logger: Option<Logger>, path: PathBuf, repair: bool) -> Result<CheckReport> {
+    let mut report = CheckReport {
+        files: Vec::new(),
+        problems: Vec::new(),
+        repairs: Vec::new(),
+    };
+
+    if let Some(ref logger) = logger {
+        info!(logger, "Checking log directory"; "path" => path.to_str());
+    }
+
+    // Repairs modify files a running store may be appending to
+    let _lock = if repair {
+        Some(DirectoryLock::acquire(&path)?)
+    } else {
+        None
+    };
+
+    let mut records = match read_manifest(&path, &mut report.problems)? {
+        Some(records) => records,
+        None => return Ok(report),
+    };
+    records.sort_by_key(|record| record.min_index);
+
+    check_ranges(&records, &mut report.problems);
+
+    // Anything in the directory other than the MANIFEST and the listed log
+    // files is left over from an interrupted operation or foreign
+    let expected: HashSet<String> = records
+        .iter()
+        .map(|record| format!("{}.log", record.file_number))
+        .chain(["MANIFEST".to_string(), "LOCK".to_string(), ENGINE_MARKER.to_string()])
+        .collect();
+    let mut extra: Vec<String> = std::fs::read_dir(&path)?
+        .map(|entry| entry.map(|e| e.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|name| !expected.contains(name))
+        .collect();
+    extra.sort();
+    for name in extra {
+        report.problems.push(format!("unexpected file {}", name));
+    }
+
+    // Latest record for each key, as (index, position in report.files, is_set)
+    let mut latest: HashMap<String, (u64, usize, bool)> = HashMap::new();
+    let mut missing = Vec::new();
+
+    for (position, record) in records.iter().enumerate() {
+        let file_path = path.join(format!("{}.log", record.file_number));
+        let mut scanner = match RecordScanner::open(&file_path) {
+            Ok(scanner) => scanner,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                report
+                    .problems
+                    .push(format!("{}.log is listed in the MANIFEST but missing", record.file_number));
+                missing.push(record.file_number);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        // The tail file may hold records past the max_index recorded in the
+        // MANIFEST if the store was not shut down cleanly
+        let is_tail = position == records.len() - 1;
+        let mut count = 0;
+        let mut out_of_range = 0;
+
+        for entry in scanner.by_ref() {
+            match entry {
+                Ok((_, log_record)) => {
+                    count += 1;
+                    if log_record.index < record.min_index
+                        || (!is_tail && log_record.index > record.max_index)
+                    {
+                        out_of_range += 1;
+                    }
+                    let (key, is_set) = match log_record.operation {
+                        LogOperation::Set { key, .. } => (key, true),
+                        LogOperation::Rm { key } => (key, false),
+                    };
+                    match latest.get(&key) {
+                        Some((index, _, _)) if *index > log_record.index => {}
+                        _ => {
+                            latest.insert(key, (log_record.index, report.files.len(), is_set));
+                        }
+                    }
+                }
+                Err(err) => report
+                    .problems
+                    .push(format!("{}.log: {}", record.file_number, err)),
+            }
+        }
+
+        if out_of_range > 0 {
+            report.problems.push(format!(
+                "{}.log holds {} records outside index range {}..={}",
+                record.file_number, out_of_range, record.min_index, record.max_index
+            ));
+        }
+
+        if repair && scanner.valid_len() < scanner.file_len() {
+            if let Some(ref logger) = logger {
+                warn!(logger, "Truncating corrupt tail"; "file_number" => record.file_number, "valid_len" => scanner.valid_len());
+            }
+            OpenOptions::new()
+                .write(true)
+                .open(&file_path)?
+                .set_len(scanner.valid_len())?;
+            report.repairs.push(format!(
+                "truncated {}.log from {} to {} bytes",
+                record.file_number,
+                scanner.file_len(),
+                scanner.valid_len()
+            ));
+        }
+
+        report.files.push(FileReport {
+            file_number: record.file_number,
+            min_index: record.min_index,
+            max_index: record.max_index,
+            records: count,
+            live_records: 0,
+            dead_records: count,
+            valid_len: scanner.valid_len(),
+            file_len: scanner.file_len(),
+        });
+    }
+
+    for (_, position, is_set) in latest.values() {
+        if *is_set {
+            report.files[*position].live_records += 1;
+            report.files[*position].dead_records -= 1;
+        }
+    }
+
+    if repair && !missing.is_empty() {
+        records.retain(|record| !missing.contains(&record.file_number));
+        Log::write_manifest(&logger, &OsFileSystem, records, &path)?;
+        for file_number in missing {
+            report
+                .repairs
+                .push(format!("dropped missing {}.log from the MANIFEST", file_number));
+        }
+    }
+
+    if let Some(ref logger) = logger {
+        info!(logger, "Finished checking log directory"; "problems" => report.problems.len());
+    }
+
+    Ok(report)
+}
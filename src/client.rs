@@ -1,36 +1,70 @@
-use std::io::{BufReader, BufWriter, Error, Result, Write};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
 use std::net::TcpStream;
 
 use slog::{info, Logger};
 
-use crate::net::{GetRequest, GetResponse, RmRequest, RmResponse, SetRequest, SetResponse, Request};
+use crate::engines::EngineStats;
+use crate::tls::ClientTls;
+use crate::net::{features, ClientHello, Exception, Frame, FrameKind, Framing, GetRequest, GetResponse, MultiGetRequest, MultiGetResponse, MultiRmRequest, MultiRmResponse, MultiSetRequest, MultiSetResponse, RmRequest, RmResponse, ScanRequest, ScanResponse, ServerHello, SetCompactionRateRequest, SetCompactionRateResponse, SetRequest, SetResponse, StatsRequest, StatsResponse, Request, MIN_PROTOCOL_VERSION, PROTOCOL_MAGIC, PROTOCOL_VERSION};
 
 pub struct KvsClient {
     addr: String,
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
     logger: Logger,
-}
 
-macro_rules! send_request {
-    ($self:expr, $req: ident, $resp: ident, $($arg:tt),+) => {{
-        info!($self.logger, "Sending request"; "addr" => &$self.addr);
+    // Protocol version and features agreed with the server
+    negotiated: ServerHello,
 
-        bincode::serialize_into(&mut $self.writer, &Request::from($req{$($arg),+}))
-            .map_err(|e| Error::other(e.to_string()))?;
-        $self.writer.flush()?;
+    framing: Framing,
 
-        info!($self.logger, "Sent request, waiting for response");
+    // Correlation ID for the next request sent
+    next_id: u64,
+}
 
-        let response: $resp =
-            bincode::deserialize_from(&mut $self.reader).map_err(|e| Error::other(e.to_string()))?;
+///
+/// Operation sent as part of a pipeline
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get(String),
+    Set(String, String),
+    Rm(String),
+}
 
-        info!($self.logger, "Received response");
+///
+/// Successful result of a Command sent as part of a pipeline
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Get(Option<String>),
+    Set,
+    Rm,
+}
+
+impl From<Command> for Request {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Get(key) => Request::Get(GetRequest { key }),
+            Command::Set(key, value) => Request::Set(SetRequest { key, value }),
+            Command::Rm(key) => Request::Rm(RmRequest { key }),
+        }
+    }
+}
 
-        match response {
+macro_rules! decode_response {
+    ($frame:expr, $resp:ident) => {
+        match $frame.decode::<$resp>()? {
             $resp::Ok(value) => Ok(value),
-            $resp::Error(err) => Err(Error::other(err.what))
+            $resp::Error(err) => Err(Error::from(err)),
         }
+    };
+}
+
+macro_rules! send_request {
+    ($self:expr, $req: ident, $resp: ident $(, $arg:tt)*) => {{
+        let frame = $self.round_trip(Request::from($req{$($arg),*}))?;
+        decode_response!(frame, $resp)
     }};
 }
 
@@ -38,17 +72,141 @@ macro_rules! send_request {
 /// KvsClient implementation which sends commands over the network to the target server
 ///
 impl KvsClient {
+    ///
+    /// Connect to the server at addr, agreeing on a protocol version and
+    /// features before returning
+    ///
     pub fn new(logger: Logger, addr: String) -> Result<KvsClient> {
+        KvsClient::connect(logger, addr, None)
+    }
+
+    ///
+    /// Connect to the server at addr as new does, over TLS if tls is given
+    ///
+    pub fn connect(logger: Logger, addr: String, tls: Option<&ClientTls>) -> Result<KvsClient> {
         let conn = TcpStream::connect(&addr)?;
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match tls {
+            Some(tls) => {
+                let (reader, writer) = tls.connect(&addr, &conn)?;
+                (Box::new(reader), Box::new(writer))
+            }
+            None => (Box::new(conn.try_clone()?), Box::new(conn)),
+        };
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        writer.write_all(&PROTOCOL_MAGIC)?;
+        let hello = ClientHello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            features: features::SUPPORTED,
+        };
+        Framing::HANDSHAKE.write(&mut writer, FrameKind::Hello, 0, &hello)?;
+        writer.flush()?;
+
+        let negotiated: ServerHello = match Framing::HANDSHAKE.read(&mut reader, u64::MAX)? {
+            Ok(frame) if frame.kind == Some(FrameKind::Hello) => frame.decode()?,
+            Ok(frame) if frame.kind == Some(FrameKind::Error) => {
+                return Err(Error::from(frame.decode::<Exception>()?))
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "expected a hello frame")),
+        };
+
+        info!(logger, "Connected"; "addr" => &addr, "version" => negotiated.version, "features" => negotiated.features);
 
         Ok(KvsClient {
             addr,
-            reader: BufReader::new(conn.try_clone()?),
-            writer: BufWriter::new(conn),
+            reader,
+            writer,
             logger,
+            framing: Framing::for_version(negotiated.version),
+            negotiated,
+            next_id: 0,
+        })
+    }
+
+    ///
+    /// Send a request and read the frame sent back for it, turning an error
+    /// frame into an error
+    ///
+    fn round_trip(&mut self, request: Request) -> Result<Frame> {
+        let missing = request.required_features() & !self.negotiated.features;
+        if missing != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} needs protocol features {:#x} which the server does not support", request, missing),
+            ));
+        }
+
+        info!(self.logger, "Sending request"; "addr" => &self.addr);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.framing.write(&mut self.writer, FrameKind::Request, id, &request)?;
+        self.writer.flush()?;
+        let frame = receive_replies(self.framing, &mut self.reader, id, 1)?.pop().unwrap()?;
+
+        info!(self.logger, "Received response");
+        Ok(frame)
+    }
+
+    ///
+    /// Send every request without waiting for responses, then collect the
+    /// frame sent back for each, in the order of requests. Fails as a whole
+    /// only if the connection does, while an error frame answering a single
+    /// request becomes the error for that request
+    ///
+    fn exchange(&mut self, requests: &[Request]) -> Result<Vec<Result<Frame>>> {
+        let first_id = self.next_id;
+        self.next_id += requests.len() as u64;
+        let framing = self.framing;
+        let writer = &mut self.writer;
+        let reader = &mut self.reader;
+
+        std::thread::scope(|s| {
+            // Requests are written from their own thread, so responses are
+            // read as they arrive and the server is never left blocked on
+            // sending responses nobody is reading
+            let sender = s.spawn(move || -> Result<()> {
+                for (offset, request) in requests.iter().enumerate() {
+                    framing.write(writer, FrameKind::Request, first_id + offset as u64, request)?;
+                }
+                writer.flush()
+            });
+
+            let replies = receive_replies(framing, reader, first_id, requests.len())?;
+            sender.join().unwrap()?;
+            Ok(replies)
         })
     }
 
+    ///
+    /// Send every command before waiting for any response, so a batch costs
+    /// roughly one round trip rather than one per command. The server runs
+    /// the commands in order. Returns the result of each command in the
+    /// same order, or an error if the connection fails
+    ///
+    pub fn pipeline(&mut self, commands: Vec<Command>) -> Result<Vec<Result<Reply>>> {
+        let requests: Vec<Request> = commands.into_iter().map(Request::from).collect();
+
+        info!(self.logger, "Sending pipelined requests"; "addr" => &self.addr, "count" => requests.len());
+
+        let frames = self.exchange(&requests)?;
+        Ok(requests
+            .iter()
+            .zip(frames)
+            .map(|(request, frame)| {
+                let frame = frame?;
+                match request {
+                    Request::Get(_) => decode_response!(frame, GetResponse).map(Reply::Get),
+                    Request::Set(_) => decode_response!(frame, SetResponse).map(|_| Reply::Set),
+                    Request::Rm(_) => decode_response!(frame, RmResponse).map(|_| Reply::Rm),
+                    _ => unreachable!(),
+                }
+            })
+            .collect())
+    }
+
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
         send_request!(self, GetRequest, GetResponse, key)
     }
@@ -60,4 +218,224 @@ impl KvsClient {
     pub fn rm(&mut self, key: String) -> Result<()> {
         send_request!(self, RmRequest, RmResponse, key)
     }
+
+    ///
+    /// Get every key in one request, returning the result for each key in
+    /// the order of keys
+    ///
+    pub fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Result<Option<String>>>> {
+        let responses = send_request!(self, MultiGetRequest, MultiGetResponse, keys)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| match response {
+                GetResponse::Ok(value) => Ok(value),
+                GetResponse::Error(err) => Err(Error::from(err)),
+            })
+            .collect())
+    }
+
+    ///
+    /// Set every pair in one request, in order, returning the result for
+    /// each pair
+    ///
+    pub fn multi_set(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let responses = send_request!(self, MultiSetRequest, MultiSetResponse, pairs)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| match response {
+                SetResponse::Ok(value) => Ok(value),
+                SetResponse::Error(err) => Err(Error::from(err)),
+            })
+            .collect())
+    }
+
+    ///
+    /// Remove every key in one request, in order, returning the result for
+    /// each key
+    ///
+    pub fn multi_rm(&mut self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        let responses = send_request!(self, MultiRmRequest, MultiRmResponse, keys)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| match response {
+                RmResponse::Ok(value) => Ok(value),
+                RmResponse::Error(err) => Err(Error::from(err)),
+            })
+            .collect())
+    }
+
+    ///
+    /// Visit the entries selected by options in ascending key order, one
+    /// page at a time
+    ///
+    pub fn scan(&mut self, options: ScanOptions) -> Scan<'_> {
+        Scan {
+            client: self,
+            request: ScanRequest {
+                prefix: options.prefix,
+                start: options.start,
+                end: options.end,
+                limit: options.page_size,
+                cursor: None,
+            },
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        send_request!(self, StatsRequest, StatsResponse)
+    }
+
+    ///
+    /// Limit the server's background compaction to bytes_per_second of disk
+    /// I/O, or lift the limit with None
+    ///
+    pub fn set_compaction_rate(&mut self, bytes_per_second: Option<u64>) -> Result<()> {
+        send_request!(self, SetCompactionRateRequest, SetCompactionRateResponse, bytes_per_second)
+    }
+}
+
+///
+/// Which keys a scan visits, and how many it fetches per request
+///
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    page_size: u32,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            prefix: None,
+            start: None,
+            end: None,
+            page_size: 100,
+        }
+    }
+}
+
+impl ScanOptions {
+    pub fn new() -> ScanOptions {
+        ScanOptions::default()
+    }
+
+    ///
+    /// Only visit keys starting with prefix
+    ///
+    pub fn with_prefix(mut self, prefix: String) -> ScanOptions {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    ///
+    /// Only visit keys from start onwards
+    ///
+    pub fn with_start(mut self, start: String) -> ScanOptions {
+        self.start = Some(start);
+        self
+    }
+
+    ///
+    /// Only visit keys before end
+    ///
+    pub fn with_end(mut self, end: String) -> ScanOptions {
+        self.end = Some(end);
+        self
+    }
+
+    ///
+    /// Ask for page_size entries per request. The server may send fewer
+    ///
+    pub fn with_page_size(mut self, page_size: u32) -> ScanOptions {
+        self.page_size = page_size;
+        self
+    }
+}
+
+///
+/// Iterator over the entries visited by a scan, in ascending key order.
+/// Pages are fetched from the server as the iterator reaches them, so keys
+/// written or removed during the scan may or may not be seen
+///
+pub struct Scan<'a> {
+    client: &'a mut KvsClient,
+    request: ScanRequest,
+    page: std::vec::IntoIter<(String, String)>,
+
+    // Set once the last page has been fetched, or fetching one failed
+    done: bool,
+}
+
+impl Scan<'_> {
+    fn fetch_page(&mut self) -> Result<()> {
+        let frame = self.client.round_trip(Request::from(self.request.clone()))?;
+        let page = decode_response!(frame, ScanResponse)?;
+        self.done = page.cursor.is_none();
+        self.request.cursor = page.cursor;
+        self.page = page.entries.into_iter();
+        Ok(())
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.page.next() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+            if let Err(err) = self.fetch_page() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+///
+/// Read the frames answering count requests numbered from first_id, and
+/// return them in the order of the requests
+///
+fn receive_replies(framing: Framing, reader: &mut impl Read, first_id: u64, count: usize) -> Result<Vec<Result<Frame>>> {
+    let mut replies: Vec<Option<Result<Frame>>> = (0..count).map(|_| None).collect();
+    for received in 0..count {
+        let frame = match framing.read(reader, u64::MAX)? {
+            Ok(frame) => frame,
+            Err(oversized) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("frame of {} bytes is too large", oversized.len),
+                ))
+            }
+        };
+
+        // Without correlation IDs the server answers in order
+        let slot = match framing.ids {
+            true => frame.id.wrapping_sub(first_id) as usize,
+            false => received,
+        };
+        let reply = match frame.kind {
+            Some(FrameKind::Response) => Ok(frame),
+            Some(FrameKind::Error) => Err(Error::from(frame.decode::<Exception>()?)),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "expected a response frame")),
+        };
+        match replies.get_mut(slot) {
+            Some(entry @ None) => *entry = Some(reply),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unexpected response to request {}", first_id.wrapping_add(slot as u64)),
+                ))
+            }
+        }
+    }
+    Ok(replies.into_iter().map(Option::unwrap).collect())
 }
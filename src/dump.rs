@@ -0,0 +1,216 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::check::read_manifest;
+use crate::log::{LogOperation, RecordScanner};
+
+///
+/// Number of characters of a value printed when values are truncated
+///
+const TRUNCATED_VALUE_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OperationKind {
+    Set,
+    Rm,
+}
+
+///
+/// Selects which parts of the log are dumped and how they are printed
+///
+#[derive(Default)]
+pub struct DumpOptions {
+    // Only walk this log file instead of the whole log
+    pub file_number: Option<u16>,
+    pub key: Option<String>,
+    pub min_index: Option<u64>,
+    pub max_index: Option<u64>,
+    pub operation: Option<OperationKind>,
+
+    // Print values in full rather than truncated
+    pub full_values: bool,
+
+    // Emit one JSON object per line instead of text
+    pub json: bool,
+}
+
+///
+/// Single line of dump output. Serialized as-is for JSON output
+///
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum DumpEntry<'a> {
+    File {
+        file_number: u16,
+        min_index: u64,
+        max_index: u64,
+    },
+    Record {
+        file_number: u16,
+        index: u64,
+        offset: u64,
+        operation: &'static str,
+        key: &'a str,
+        value: Option<String>,
+    },
+    Corrupt {
+        file_number: u16,
+        offset: u64,
+        error: String,
+    },
+}
+
+impl DumpOptions {
+    fn matches(&self, index: u64, operation: &LogOperation) -> bool {
+        let (kind, key) = match operation {
+            LogOperation::Set { key, .. } => (OperationKind::Set, key),
+            LogOperation::Rm { key } => (OperationKind::Rm, key),
+        };
+        self.min_index.is_none_or(|min| index >= min)
+            && self.max_index.is_none_or(|max| index <= max)
+            && self.operation.is_none_or(|op| op == kind)
+            && self.key.as_ref().is_none_or(|k| k == key)
+    }
+
+    fn format_value(&self, value: &str) -> String {
+        if self.full_values || value.chars().count() <= TRUNCATED_VALUE_LEN {
+            value.to_string()
+        } else {
+            format!(
+                "{}...",
+                value.chars().take(TRUNCATED_VALUE_LEN).collect::<String>()
+            )
+        }
+    }
+
+    fn emit<W: Write>(&self, out: &mut W, entry: &DumpEntry) -> Result<()> {
+        if self.json {
+            serde_json::to_writer(&mut *out, entry).map_err(Error::other)?;
+            return writeln!(out);
+        }
+
+        match entry {
+            DumpEntry::File {
+                file_number,
+                min_index,
+                max_index,
+            } => writeln!(
+                out,
+                "{}.log min_index={} max_index={}",
+                file_number, min_index, max_index
+            ),
+            DumpEntry::Record {
+                index,
+                offset,
+                operation,
+                key,
+                value,
+                ..
+            } => match value {
+                Some(value) => writeln!(
+                    out,
+                    "  index={} offset={} {} key={:?} value={:?}",
+                    index, offset, operation, key, value
+                ),
+                None => writeln!(
+                    out,
+                    "  index={} offset={} {} key={:?}",
+                    index, offset, operation, key
+                ),
+            },
+            DumpEntry::Corrupt { error, .. } => writeln!(out, "  {}", error),
+        }
+    }
+}
+
+///
+/// Print the MANIFEST of the log stored in path, followed by every record
+/// matching the filters in options. Files are walked in index order and
+/// records in the order they appear on disk. A record which fails to
+/// deserialize is reported and ends the walk of that file
+///
+pub fn dump<W: Write>(path: PathBuf, options: &DumpOptions, out: &mut W) -> Result<()> {
+    let mut problems = Vec::new();
+    let mut records = read_manifest(&path, &mut problems)?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, problems.join(", ")))?;
+    records.sort_by_key(|record| record.min_index);
+
+    if !options.json {
+        writeln!(out, "MANIFEST: {} files", records.len())?;
+    }
+    for record in &records {
+        options.emit(
+            out,
+            &DumpEntry::File {
+                file_number: record.file_number,
+                min_index: record.min_index,
+                max_index: record.max_index,
+            },
+        )?;
+    }
+
+    if let Some(file_number) = options.file_number {
+        if !records.iter().any(|record| record.file_number == file_number) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{}.log is not listed in the MANIFEST", file_number),
+            ));
+        }
+    }
+
+    for record in records
+        .iter()
+        .filter(|record| options.file_number.is_none_or(|n| n == record.file_number))
+    {
+        if !options.json {
+            writeln!(out, "{}.log", record.file_number)?;
+        }
+
+        let mut scanner = RecordScanner::open(&path.join(format!("{}.log", record.file_number)))?;
+        let mut error = None;
+        for entry in scanner.by_ref() {
+            let (offset, log_record) = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
+            };
+
+            if !options.matches(log_record.index, &log_record.operation) {
+                continue;
+            }
+
+            let (operation, key, value) = match &log_record.operation {
+                LogOperation::Set { key, value } => ("set", key, Some(options.format_value(value))),
+                LogOperation::Rm { key } => ("rm", key, None),
+            };
+            options.emit(
+                out,
+                &DumpEntry::Record {
+                    file_number: record.file_number,
+                    index: log_record.index,
+                    offset,
+                    operation,
+                    key,
+                    value,
+                },
+            )?;
+        }
+
+        if let Some(err) = error {
+            options.emit(
+                out,
+                &DumpEntry::Corrupt {
+                    file_number: record.file_number,
+                    offset: scanner.valid_len(),
+                    error: err.to_string(),
+                },
+            )?;
+        }
+    }
+
+    Ok(())
+}
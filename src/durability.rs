@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+///
+/// Policy controlling when writes are forced to stable storage. Stronger
+/// policies trade write throughput for a smaller window of data which can be
+/// lost if the machine crashes. All policies flush everything on a clean
+/// shutdown.
+///
+/// KvStore writes every record to its log file before acknowledging it, so
+/// under KvStore a crash of the process alone (rather than the OS or
+/// machine) loses nothing since every write is already in the OS page
+/// cache. SledKvStore buffers writes in the process until it flushes, so
+/// under Buffered and Periodic a crash of the process alone loses the same
+/// writes a crash of the machine would
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Durability {
+    ///
+    /// Sync every write before acknowledging it. A crash loses nothing which
+    /// was acknowledged
+    ///
+    #[default]
+    Sync,
+
+    ///
+    /// Sync from a background flusher every interval, and inline whenever
+    /// more than bytes have been written since the last sync. A crash loses
+    /// at most the writes acknowledged within the last interval or bytes
+    ///
+    Periodic { interval: Duration, bytes: u64 },
+
+    ///
+    /// Never sync explicitly and leave writeback to the OS. A crash loses
+    /// every write the OS has not written back yet, which on Linux is
+    /// typically up to 30 seconds of writes
+    ///
+    Buffered,
+}
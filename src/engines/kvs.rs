@@ -1,14 +1,26 @@
-use std::collections::HashMap;
-use std::io::{ErrorKind, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::thread::JoinHandle;
 
-use crate::engines::KvsEngine;
+use crate::durability::Durability;
+use crate::engines::{CompactionStats, EngineStats, FileStats, KvsEngine};
+use crate::fs::{FileSystem, OsFileSystem};
+use crate::limits::Limits;
 use crate::log::LogOperation::{self, Rm, Set};
-use crate::log::{Log, LogRecord};
+use crate::log::{CompactionControl, LiveRecords, Log, LogRecord};
 
-use slog::{o, Logger};
+use slog::{error, info, o, Logger};
+
+///
+/// Fraction of the sealed files which must be dead records before a
+/// compaction is started automatically
+///
+const COMPACTION_GARBAGE_RATIO: f64 = 0.5;
 
 struct State {
     log: Log,
@@ -16,16 +28,111 @@ struct State {
     ///
     /// Stores our mapping from the key as a string to the index in the log
     /// where the value will be found. A separate lookup into the log is
-    /// required to read the value.
+    /// required to read the value. Ordered by key so scans can walk it.
     ///
-    mapping: Mutex<HashMap<String, u64>>,
+    mapping: Mutex<BTreeMap<String, u64>>,
+
+    // Held by writers from writing a record until the mapping refers to it,
+    // so the mapping itself is never held across disk I/O
+    writing: Mutex<()>,
+
+    // Rate limit of compaction I/O, and cancellation of background
+    // compaction once the last handle is dropped
+    compaction: CompactionControl,
+
+    // Background compaction most recently started by a write
+    compactor: Mutex<Option<JoinHandle<()>>>,
+
+    // KvStore handles sharing this state. The last one to be dropped cancels
+    // the background compaction and waits for it to stop, so the log is
+    // closed once it returns
+    handles: AtomicUsize,
 
     logger: Option<Logger>,
 }
 
+impl State {
+    ///
+    /// Snapshot of the records referenced by the key directory. No write is
+    /// in progress while writing is held, so every record below the
+    /// watermark is already reflected in the mapping
+    ///
+    fn live_records(&self) -> LiveRecords {
+        let _writing = self.writing.lock().unwrap();
+        let mapping = self.mapping.lock().unwrap();
+        LiveRecords {
+            indexes: mapping.values().copied().collect(),
+            watermark: self.log.next_index(),
+        }
+    }
+
+    fn compact(&self) -> Result<()> {
+        let live = self.live_records();
+        self.log.compact_log(&live, &self.compaction)
+    }
+
+    ///
+    /// Compact if enough of the sealed data is dead to make rewriting it
+    /// worthwhile
+    ///
+    fn compact_if_worthwhile(&self) -> Result<()> {
+        let live = self.live_records();
+        let files = self.log.file_stats(&live)?;
+        let sealed = &files[..files.len().saturating_sub(1)];
+        let size: u64 = sealed.iter().map(|file| file.size).sum();
+        let live_bytes: u64 = sealed.iter().map(|file| file.live_bytes).sum();
+
+        if size == 0 || ((size - live_bytes) as f64) < size as f64 * COMPACTION_GARBAGE_RATIO {
+            return Ok(());
+        }
+
+        if let Some(ref logger) = self.logger {
+            info!(logger, "Triggering compaction"; "sealed_bytes" => size, "live_bytes" => live_bytes);
+        }
+        self.log.compact_log(&live, &self.compaction)
+    }
+
+    fn wait_for_compactor(&self) {
+        let compactor = self.compactor.lock().unwrap().take();
+        if let Some(compactor) = compactor {
+            let _ = compactor.join();
+        }
+    }
+}
+
 ///
 pub struct KvStore {
     state: Arc<State>,
+
+    limits: Limits,
+}
+
+///
+/// True if no key can lie between lower and upper. BTreeMap::range panics
+/// given such a range rather than yielding nothing
+///
+fn is_empty_range(lower: &Bound<String>, upper: &Bound<String>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(lower), Bound::Included(upper)) => lower > upper,
+        (
+            Bound::Included(lower) | Bound::Excluded(lower),
+            Bound::Included(upper) | Bound::Excluded(upper),
+        ) => lower >= upper,
+        _ => false,
+    }
+}
+
+///
+/// Fold a record into the summary of the log file it was read from, which
+/// holds the last operation on each key within that file: the index of a set
+/// or None for a remove. Files are replayed in parallel when the log is
+/// opened
+///
+fn replay_record(keys: &mut HashMap<String, Option<u64>>, record: LogRecord) {
+    match record.operation {
+        Rm { key } => keys.insert(key, None),
+        Set { key, .. } => keys.insert(key, Some(record.index)),
+    };
 }
 
 impl KvStore {
@@ -34,36 +141,155 @@ impl KvStore {
     /// will be added later using the public APIs
     ///
     pub fn open(logger: Option<Logger>, path: PathBuf) -> Result<KvStore> {
-        let mut log = Log::open(logger.clone().map(|l| l.new(o!("module" => "log"))), path)?;
-        let mut mapping: HashMap<String, u64> = HashMap::new();
-
-        for record in log.iter()? {
-            if let Ok(rec) = record {
-                match rec.operation {
-                    Rm { key } => mapping.remove(&key),
-                    Set { key, .. } => mapping.insert(key, rec.index),
+        Self::open_with_durability(logger, path, Durability::default())
+    }
+
+    ///
+    /// Open a KvStore which syncs writes to disk according to durability
+    ///
+    pub fn open_with_durability(
+        logger: Option<Logger>,
+        path: PathBuf,
+        durability: Durability,
+    ) -> Result<KvStore> {
+        Self::open_with_fs(logger, path, durability, Arc::new(OsFileSystem))
+    }
+
+    ///
+    /// Open a KvStore which keeps its log on fs rather than the OS
+    /// filesystem, such as a FaultyFileSystem under test
+    ///
+    pub fn open_with_fs(
+        logger: Option<Logger>,
+        path: PathBuf,
+        durability: Durability,
+        fs: Arc<dyn FileSystem>,
+    ) -> Result<KvStore> {
+        let (log, files) = Log::open(
+            logger.clone().map(|l| l.new(o!("module" => "log"))),
+            path,
+            durability,
+            fs,
+            replay_record,
+        )?;
+        Ok(Self::from_log(logger, log, files))
+    }
+
+    ///
+    /// Open an existing KvStore for reading only. Nothing in the directory is
+    /// modified, so the store may sit on read-only storage, and it may be
+    /// opened while a writer has it open. Writes made after opening are not
+    /// seen. Sets and removes fail with ErrorKind::ReadOnlyFilesystem
+    ///
+    pub fn open_read_only(logger: Option<Logger>, path: PathBuf) -> Result<KvStore> {
+        let (log, files) = Log::open_read_only(
+            logger.clone().map(|l| l.new(o!("module" => "log"))),
+            path,
+            Arc::new(OsFileSystem),
+            replay_record,
+        )?;
+        Ok(Self::from_log(logger, log, files))
+    }
+
+    ///
+    /// Build the key directory from the per file summaries produced by
+    /// replay_record. Merging the files in index order leaves the latest
+    /// operation on every key
+    ///
+    fn from_log(
+        logger: Option<Logger>,
+        log: Log,
+        files: Vec<HashMap<String, Option<u64>>>,
+    ) -> KvStore {
+        let mut mapping: BTreeMap<String, u64> = BTreeMap::new();
+        for keys in files {
+            for (key, index) in keys {
+                match index {
+                    Some(index) => mapping.insert(key, index),
+                    None => mapping.remove(&key),
                 };
-            } else {
-                return Err(record.unwrap_err());
             }
         }
 
-        Ok(KvStore {
+        KvStore {
             state: Arc::new(State {
                 logger,
                 log,
                 mapping: Mutex::new(mapping),
+                writing: Mutex::new(()),
+                compaction: CompactionControl::new(),
+                compactor: Mutex::new(None),
+                handles: AtomicUsize::new(1),
             }),
-        })
+            limits: Limits::default(),
+        }
     }
 
-    pub fn compact(&mut self) -> Result<()> {
-        self.state.log.compact_log(|record: &LogRecord| -> bool {
-            match record.operation {
-                Rm { ref key } => self.state.mapping.lock().unwrap().contains_key(key),
-                Set { ref key, .. } => !self.state.mapping.lock().unwrap().contains_key(key),
-            }
-        })
+    ///
+    /// Reject keys and values larger than limits on this handle and any
+    /// cloned from it
+    ///
+    pub fn with_limits(mut self, limits: Limits) -> KvStore {
+        self.limits = limits;
+        self
+    }
+
+    ///
+    /// Serve reads of sealed log files from memory maps instead of reading
+    /// through the file, on this handle and every handle sharing the store.
+    /// Off by default. The tail file is always read through the file.
+    ///
+    /// This saves a read into a buffer per get, but values are still copied
+    /// out of the map into the String returned. Ignored by stores opened
+    /// read only, which do not hold the directory lock that keeps other
+    /// processes from truncating a mapped file
+    ///
+    pub fn with_mmap_reads(self, enabled: bool) -> KvStore {
+        self.state.log.set_mmap_reads(enabled);
+        self
+    }
+
+    ///
+    /// Merge every sealed log file into new files holding only live records,
+    /// then delete the originals. Writes may continue while this runs. Waits
+    /// for any background compaction to finish first
+    ///
+    pub fn compact(&self) -> Result<()> {
+        self.state.wait_for_compactor();
+        self.state.compact()
+    }
+
+    ///
+    /// Start a background compaction once the log has sealed enough files
+    /// since the last one, unless a background compaction is still running
+    ///
+    fn maybe_compact(&self) -> Result<()> {
+        if !self.state.log.compaction_due() {
+            return Ok(());
+        }
+
+        let mut compactor = self.state.compactor.lock().unwrap();
+        if compactor.as_ref().is_some_and(|handle| !handle.is_finished()) {
+            return Ok(());
+        }
+
+        let state = self.state.clone();
+        *compactor = Some(
+            std::thread::Builder::new()
+                .name("compactor".to_string())
+                .spawn(move || {
+                    // Interrupted means the last handle cancelled it on drop
+                    match state.compact_if_worthwhile() {
+                        Err(err) if err.kind() != ErrorKind::Interrupted => {
+                            if let Some(ref logger) = state.logger {
+                                error!(logger, "Compaction failed"; "error" => err.to_string());
+                            }
+                        }
+                        _ => {}
+                    }
+                })?,
+        );
+        Ok(())
     }
 }
 
@@ -72,44 +298,52 @@ impl KvsEngine for KvStore {
     ///
     ///
     fn set(&self, key: String, value: String) -> Result<()> {
+        self.state.log.check_writable()?;
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&value)?;
+
         let op = LogOperation::Set {
             key: key.to_string(),
             value,
         };
-        let position = self.state.log.write(op)?;
-        self.state.mapping.lock().unwrap().insert(key.to_string(), position);
-
-        // let total_size: u64 = self.log.total_size()?;
-        // if total_size > 2048 {
-        //     if let Some(ref logger) = self.logger {
-        //         info!(logger, "Trigging compaction"; "total_size" => total_size);
-        //     }
-        //     self.log.compact_log(|elem| {
-        //         match &elem.operation {
-        //             Set { key, .. } => {
-        //                 self.mapping.contains_key(key) && *self.mapping.get(key).unwrap() == elem.index
-        //             }
-        //             Rm { key } => {
-        //                 !self.mapping.contains_key(key)
-        //             }
-        //         }
-        //     })?;
-        // }
 
-        Ok(())
+        // Hold writing until the mapping refers to the record, so a
+        // compaction never sees a record which is written but not yet
+        // referenced, and writes to a key are applied in log order
+        {
+            let _writing = self.state.writing.lock().unwrap();
+            let position = self.state.log.write(op)?;
+            self.state.mapping.lock().unwrap().insert(key, position);
+        }
+
+        self.maybe_compact()
     }
 
     ///
     fn get(&self, key: String) -> Result<Option<String>> {
-        match self.state.mapping.lock().unwrap().get(&key) {
-            Some(position) => {
-                let record = self.state.log.read(*position)?;
-                match record.operation {
-                    Set { key: _, value } => Ok(Some(value)),
-                    Rm { key: _ } => Err(ErrorKind::InvalidData.into()),
+        self.limits.check_key(&key)?;
+        let mut position = match self.state.mapping.lock().unwrap().get(&key) {
+            Some(position) => *position,
+            None => return Ok(None),
+        };
+
+        // The record is read without holding the mapping. If the key is
+        // written meanwhile a compaction may drop the record, so a failed
+        // read is retried wherever the key has moved to
+        loop {
+            match self.state.log.read(position) {
+                Ok(record) => {
+                    return match record.operation {
+                        Set { key: _, value } => Ok(Some(value)),
+                        Rm { key: _ } => Err(ErrorKind::InvalidData.into()),
+                    }
                 }
+                Err(err) => match self.state.mapping.lock().unwrap().get(&key) {
+                    Some(current) if *current != position => position = *current,
+                    Some(_) => return Err(err),
+                    None => return Ok(None),
+                },
             }
-            None => Ok(None),
         }
     }
 
@@ -117,18 +351,129 @@ impl KvsEngine for KvStore {
     ///
     ///
     fn remove(&self, key: String) -> Result<()> {
-        self.state.log.write(LogOperation::Rm {
-            key: key.to_string(),
-        })?;
-        self.state.mapping.lock().unwrap().remove(&key);
+        self.state.log.check_writable()?;
+        self.limits.check_key(&key)?;
+        {
+            let _writing = self.state.writing.lock().unwrap();
+            self.state.log.write(LogOperation::Rm {
+                key: key.to_string(),
+            })?;
+            self.state.mapping.lock().unwrap().remove(&key);
+        }
+
+        self.maybe_compact()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.state.mapping.lock().unwrap().keys().cloned().collect())
+    }
+
+    ///
+    /// Each batch of keys is read off the ordered mapping, which is only
+    /// locked while the keys are copied out, and their values read after
+    ///
+    fn scan(&self, range: (Bound<String>, Bound<String>), limit: usize) -> Result<Vec<(String, String)>> {
+        let (mut lower, upper) = range;
+        let mut entries = Vec::new();
+        while entries.len() < limit && !is_empty_range(&lower, &upper) {
+            let wanted = limit - entries.len();
+            let keys: Vec<String> = self
+                .state
+                .mapping
+                .lock()
+                .unwrap()
+                .range::<String, _>((lower.as_ref(), upper.as_ref()))
+                .take(wanted)
+                .map(|(key, _)| key.clone())
+                .collect();
+            let exhausted = keys.len() < wanted;
+
+            // A key removed since it was selected is skipped, and the next
+            // batch makes up for it
+            for key in keys {
+                if let Some(value) = self.get(key.clone())? {
+                    entries.push((key.clone(), value));
+                }
+                lower = Bound::Excluded(key);
+            }
+            if exhausted {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let key_count = self.state.mapping.lock().unwrap().len() as u64;
+        let live = self.state.live_records();
+
+        let files = self.state.log.file_stats(&live)?;
+        let progress = self.state.log.compaction_progress();
+        let live_bytes = files.iter().map(|file| file.live_bytes).sum();
+        let total_bytes: u64 = files.iter().map(|file| file.size).sum();
+
+        Ok(EngineStats {
+            key_count,
+            live_bytes,
+            dead_bytes: total_bytes - live_bytes,
+            files: files
+                .iter()
+                .map(|file| FileStats {
+                    file_number: file.file_number,
+                    size: file.size,
+                    garbage_ratio: match file.size {
+                        0 => 0.0,
+                        size => (size - file.live_bytes) as f64 / size as f64,
+                    },
+                })
+                .collect(),
+            writes_since_open: self.state.log.writes(),
+            last_compaction: self.state.log.last_compaction(),
+            compaction: CompactionStats {
+                running: progress.running,
+                bytes_read: progress.bytes_read,
+                bytes_written: progress.bytes_written,
+                files_done: progress.files_done,
+                files_total: progress.files_total,
+                rate_limit: match self.state.compaction.rate_limit.load(Ordering::SeqCst) {
+                    0 => None,
+                    rate_limit => Some(rate_limit),
+                },
+            },
+            disk_full: self.state.log.disk_full(),
+        })
+    }
+
+    fn set_compaction_rate(&self, bytes_per_second: Option<u64>) -> Result<()> {
+        if bytes_per_second == Some(0) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "compaction rate limit must be at least 1 byte per second",
+            ));
+        }
+        self.state
+            .compaction
+            .rate_limit
+            .store(bytes_per_second.unwrap_or(0), Ordering::SeqCst);
         Ok(())
     }
 }
 
 impl Clone for KvStore {
     fn clone(&self) -> Self {
+        self.state.handles.fetch_add(1, Ordering::SeqCst);
         Self {
-            state: self.state.clone()
+            state: self.state.clone(),
+            limits: self.limits,
+        }
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if self.state.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.state.compaction.cancel();
+            self.state.wait_for_compactor();
         }
     }
 }
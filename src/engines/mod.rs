@@ -1,4 +1,13 @@
-use std::io::Result;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::ops::Bound;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fs::{FileSystem, OsFileSystem};
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
@@ -6,6 +15,112 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
 
     fn remove(&self, key: String) -> Result<()>;
+
+    ///
+    /// Every key currently stored, in ascending order
+    ///
+    fn keys(&self) -> Result<Vec<String>>;
+
+    ///
+    /// Up to limit keys within range and their values, in ascending key
+    /// order. Fewer than limit are returned only once the range is exhausted
+    ///
+    fn scan(&self, range: (Bound<String>, Bound<String>), limit: usize) -> Result<Vec<(String, String)>>;
+
+    fn stats(&self) -> Result<EngineStats>;
+
+    ///
+    /// Limit background compaction to bytes_per_second of disk I/O, or lift
+    /// the limit with None. Takes effect on a compaction already running
+    ///
+    fn set_compaction_rate(&self, bytes_per_second: Option<u64>) -> Result<()>;
+}
+
+///
+/// Space accounting for a single file of an engine's on-disk storage
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileStats {
+    pub file_number: u16,
+    pub size: u64,
+
+    // Fraction of the file taken by overwritten values and tombstones
+    pub garbage_ratio: f64,
+}
+
+///
+/// Progress of the compaction in progress, or of the last one to finish
+///
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CompactionStats {
+    pub running: bool,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub files_done: u64,
+    pub files_total: u64,
+
+    // Bytes per second of compaction I/O, or None when unthrottled
+    pub rate_limit: Option<u64>,
+}
+
+///
+/// Point in time statistics on an engine. Engines which cannot account for
+/// garbage report all space as live and no per-file breakdown
+///
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EngineStats {
+    pub key_count: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+    pub files: Vec<FileStats>,
+    pub writes_since_open: u64,
+    pub last_compaction: Option<SystemTime>,
+    pub compaction: CompactionStats,
+
+    // True while writes are rejected because the disk filled up
+    pub disk_full: bool,
+}
+
+impl Display for EngineStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "keys: {}", self.key_count)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "dead bytes: {}", self.dead_bytes)?;
+        writeln!(f, "log files: {}", self.files.len())?;
+        for file in &self.files {
+            writeln!(
+                f,
+                "  {}.log: {} bytes, {:.1}% garbage",
+                file.file_number,
+                file.size,
+                file.garbage_ratio * 100.0
+            )?;
+        }
+        writeln!(f, "writes since open: {}", self.writes_since_open)?;
+        if self.disk_full {
+            writeln!(f, "writes rejected: disk full")?;
+        }
+        match self
+            .last_compaction
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        {
+            Some(since_epoch) => writeln!(f, "last compaction: {}s since epoch", since_epoch.as_secs())?,
+            None => writeln!(f, "last compaction: never")?,
+        }
+        writeln!(
+            f,
+            "compaction: {}, {}/{} files, {} bytes read, {} bytes written, rate limit {}",
+            if self.compaction.running { "running" } else { "idle" },
+            self.compaction.files_done,
+            self.compaction.files_total,
+            self.compaction.bytes_read,
+            self.compaction.bytes_written,
+            match self.compaction.rate_limit {
+                Some(rate_limit) => format!("{} bytes/s", rate_limit),
+                None => "none".to_string(),
+            }
+        )
+    }
 }
 
 mod kvs;
@@ -13,3 +128,100 @@ mod sled;
 
 pub use crate::engines::kvs::KvStore;
 pub use crate::engines::sled::SledKvStore;
+
+///
+/// Name of the file recording which engine owns a data directory
+///
+pub const ENGINE_MARKER: &str = "ENGINE";
+
+const ENGINES: [&str; 2] = ["kvs", "sled"];
+
+///
+/// Determine which engine a data directory was written by. The marker file is
+/// used when present, otherwise directories written before markers existed
+/// are recognised by the files each engine leaves behind. An empty marker is
+/// treated as missing
+///
+pub fn detect_engine(path: &Path) -> Result<Option<String>> {
+    if let Some(engine) = read_marker(path)? {
+        return Ok(Some(engine));
+    }
+
+    if path.join("MANIFEST").exists() {
+        Ok(Some("kvs".to_string()))
+    } else if path.join("conf").exists() && path.join("db").exists() {
+        Ok(Some("sled".to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+///
+/// Engine named by the directory's marker file, if it has a non-empty one
+///
+fn read_marker(path: &Path) -> Result<Option<String>> {
+    match File::open(path.join(ENGINE_MARKER)) {
+        Ok(mut file) => {
+            let mut engine = String::new();
+            file.read_to_string(&mut engine)?;
+            let engine = engine.trim();
+            Ok((!engine.is_empty()).then(|| engine.to_string()))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+///
+/// Pick the engine to open a data directory with, creating the directory if
+/// it is new. A requested engine must agree with the one the directory
+/// already holds. Without a request the existing engine is used, falling
+/// back to kvs for a new directory. The engine is only recorded by
+/// record_engine, once it has opened the directory
+///
+pub fn resolve_engine(path: &Path, requested: Option<&str>) -> Result<String> {
+    let detected = detect_engine(path)?;
+
+    let engine = match (requested, detected.as_deref()) {
+        (Some(requested), Some(detected)) if requested != detected => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "engine mismatch: {} holds a {} store but {} was requested",
+                    path.display(),
+                    detected,
+                    requested
+                ),
+            ))
+        }
+        (Some(engine), _) | (None, Some(engine)) => engine.to_string(),
+        (None, None) => "kvs".to_string(),
+    };
+
+    if !ENGINES.contains(&engine.as_str()) {
+        return Err(Error::other(format!("Unknown storage engine {}", engine)));
+    }
+
+    std::fs::create_dir_all(path)?;
+    Ok(engine)
+}
+
+///
+/// Record engine in the directory's marker file, unless it already holds
+/// one. Should only be called once the engine has opened the directory, so
+/// a failed open leaves the directory unclaimed. The marker is written to a
+/// temporary file and renamed into place, as the MANIFEST is, so a crash
+/// never leaves a torn marker behind
+///
+pub fn record_engine(path: &Path, engine: &str) -> Result<()> {
+    if read_marker(path)?.is_some() {
+        return Ok(());
+    }
+
+    let tmp = path.join(format!("{}.tmp", ENGINE_MARKER));
+    let mut file = File::create(&tmp)?;
+    writeln!(file, "{}", engine)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path.join(ENGINE_MARKER))?;
+    OsFileSystem.sync_dir(path)
+}
@@ -1,30 +1,103 @@
 use std::io::Error;
-use std::io::Result;
+use std::io::{ErrorKind, Result};
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use sled::Db;
 
-use super::KvsEngine;
+use super::{CompactionStats, EngineStats, KvsEngine};
+use crate::durability::Durability;
+use crate::limits::Limits;
 
 pub struct SledKvStore {
     db: Db,
+
+    durability: Durability,
+
+    // Bytes written since the last flush, used by Durability::Periodic
+    unflushed_bytes: Arc<AtomicU64>,
+
+    // Writes made since the store was opened, shared between clones
+    writes: Arc<AtomicU64>,
+
+    limits: Limits,
 }
 
 impl SledKvStore {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<SledKvStore> {
-        let db = sled::open(path)?;
-        Ok(SledKvStore { db })
+        Self::open_with_durability(path, Durability::default())
+    }
+
+    ///
+    /// Open a SledKvStore which flushes writes to disk according to
+    /// durability. Periodic flushing uses sled's own background flusher
+    ///
+    pub fn open_with_durability<P: AsRef<Path>>(
+        path: P,
+        durability: Durability,
+    ) -> Result<SledKvStore> {
+        let flush_every_ms = match durability {
+            Durability::Periodic { interval, .. } => Some(interval.as_millis() as u64),
+            Durability::Sync | Durability::Buffered => None,
+        };
+        let db = sled::Config::new()
+            .path(path)
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        Ok(SledKvStore {
+            db,
+            durability,
+            unflushed_bytes: Arc::new(AtomicU64::new(0)),
+            writes: Arc::new(AtomicU64::new(0)),
+            limits: Limits::default(),
+        })
+    }
+
+    ///
+    /// Reject keys and values larger than limits on this handle and any
+    /// cloned from it
+    ///
+    pub fn with_limits(mut self, limits: Limits) -> SledKvStore {
+        self.limits = limits;
+        self
+    }
+
+    fn flush(&self, written: u64) -> Result<()> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        match self.durability {
+            Durability::Sync => {
+                self.db.flush()?;
+            }
+            Durability::Periodic { bytes, .. } => {
+                if self.unflushed_bytes.fetch_add(written, Ordering::SeqCst) + written >= bytes {
+                    // Claim the bytes counted so far before flushing, so any
+                    // written while the flush runs count towards the next
+                    let claimed = self.unflushed_bytes.swap(0, Ordering::SeqCst);
+                    if let Err(err) = self.db.flush() {
+                        self.unflushed_bytes.fetch_add(claimed, Ordering::SeqCst);
+                        return Err(err.into());
+                    }
+                }
+            }
+            Durability::Buffered => {}
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvStore {
     fn set(&self, key: String, value: String) -> Result<()> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&value)?;
+        let written = (key.len() + value.len()) as u64;
         self.db.insert(key, value.into_bytes())?;
-        self.db.flush()?;
-        Ok(())
+        self.flush(written)
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
+        self.limits.check_key(&key)?;
         self.db
             .get(key)?
             .map(|ivec| String::from_utf8(ivec.to_vec()))
@@ -33,9 +106,61 @@ impl KvsEngine for SledKvStore {
     }
 
     fn remove(&self, key: String) -> Result<()> {
+        self.limits.check_key(&key)?;
+        let written = key.len() as u64;
         self.db.remove(key)?;
-        self.db.flush()?;
-        Ok(())
+        self.flush(written)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.db
+            .iter()
+            .keys()
+            .map(|key| {
+                String::from_utf8(key?.to_vec()).map_err(|e| Error::other(e.to_string()))
+            })
+            .collect()
+    }
+
+    fn scan(&self, range: (Bound<String>, Bound<String>), limit: usize) -> Result<Vec<(String, String)>> {
+        let range = (range.0.map(String::into_bytes), range.1.map(String::into_bytes));
+        self.db
+            .range(range)
+            .take(limit)
+            .map(|entry| {
+                let (key, value) = entry?;
+                let key = String::from_utf8(key.to_vec()).map_err(|e| Error::other(e.to_string()))?;
+                let value = String::from_utf8(value.to_vec()).map_err(|e| Error::other(e.to_string()))?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    ///
+    /// sled does not expose how much of its storage is garbage, so the whole
+    /// on-disk size is reported as live
+    ///
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            key_count: self.db.len() as u64,
+            live_bytes: self.db.size_on_disk()?,
+            dead_bytes: 0,
+            files: Vec::new(),
+            writes_since_open: self.writes.load(Ordering::SeqCst),
+            last_compaction: None,
+            compaction: CompactionStats::default(),
+            disk_full: false,
+        })
+    }
+
+    ///
+    /// sled garbage collects its own storage and offers no way to pace it
+    ///
+    fn set_compaction_rate(&self, _bytes_per_second: Option<u64>) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "the sled engine does not support compaction rate limits",
+        ))
     }
 }
 
@@ -43,6 +168,10 @@ impl Clone for SledKvStore {
     fn clone(&self) -> Self {
         Self {
             db: self.db.clone(),
+            durability: self.durability,
+            unflushed_bytes: self.unflushed_bytes.clone(),
+            writes: self.writes.clone(),
+            limits: self.limits,
         }
     }
 }
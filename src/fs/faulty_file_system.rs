@@ -0,0 +1,404 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::{FileHandle, FileSystem};
+
+///
+/// In-memory FileSystem which simulates crashes and injects faults, for
+/// testing how the log recovers.
+///
+/// Data appended to a file only survives a crash once the file is synced,
+/// and files created, renamed or removed only survive a crash once their
+/// directory is synced. Truncation is treated as immediately durable. After
+/// a crash every operation fails until restart is called, and everything
+/// opened before the crash should be dropped before then
+///
+#[derive(Clone, Default)]
+pub struct FaultyFileSystem {
+    state: Arc<Mutex<State>>,
+
+    // Notified when syncs are unblocked
+    unblocked: Arc<Condvar>,
+}
+
+#[derive(Default)]
+struct State {
+    // Files as seen by the running process
+    files: HashMap<PathBuf, Arc<Mutex<MemFile>>>,
+
+    // Files as they would be found after a crash
+    durable_files: HashMap<PathBuf, Arc<Mutex<MemFile>>>,
+
+    locked: HashSet<PathBuf>,
+
+    crashed: bool,
+
+    // Bumped by every crash, so locks taken before it are not released by
+    // a late drop
+    generation: u64,
+
+    // Mutating operations allowed before a crash is triggered
+    crash_after: Option<u64>,
+
+    // Bytes of unsynced data which each file keeps through a crash
+    torn_bytes: u64,
+
+    fail_renames: bool,
+
+    // Total bytes all files may hold before appends fail with StorageFull
+    capacity: Option<u64>,
+
+    // Syncs wait until this is cleared
+    syncs_blocked: bool,
+
+    // Syncs currently waiting for syncs to be unblocked
+    blocked_syncs: u64,
+}
+
+#[derive(Default)]
+struct MemFile {
+    data: Vec<u8>,
+
+    // Length of the prefix of data which survives a crash
+    synced_len: u64,
+}
+
+struct MemHandle {
+    state: Arc<Mutex<State>>,
+    unblocked: Arc<Condvar>,
+    file: Arc<Mutex<MemFile>>,
+    writable: bool,
+}
+
+struct MemLock {
+    state: Arc<Mutex<State>>,
+    path: PathBuf,
+    generation: u64,
+}
+
+fn crashed_error() -> Error {
+    Error::other("simulated crash")
+}
+
+impl State {
+    ///
+    /// Account for a mutating operation, failing it if the filesystem has
+    /// crashed or crashing now if the countdown has run out
+    ///
+    fn mutate(&mut self) -> Result<()> {
+        if self.crashed {
+            return Err(crashed_error());
+        }
+        match self.crash_after {
+            Some(0) => {
+                self.crash();
+                Err(crashed_error())
+            }
+            Some(remaining) => {
+                self.crash_after = Some(remaining - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.crashed {
+            return Err(crashed_error());
+        }
+        Ok(())
+    }
+
+    fn crash(&mut self) {
+        self.files = self.durable_files.clone();
+        for file in self.files.values() {
+            let mut file = file.lock().unwrap();
+            let len = (file.synced_len + self.torn_bytes).min(file.data.len() as u64);
+            file.data.truncate(len as usize);
+            file.synced_len = len;
+        }
+        self.crashed = true;
+        self.crash_after = None;
+        self.generation += 1;
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.files
+            .values()
+            .map(|file| file.lock().unwrap().data.len() as u64)
+            .sum()
+    }
+}
+
+impl FaultyFileSystem {
+    pub fn new() -> FaultyFileSystem {
+        FaultyFileSystem::default()
+    }
+
+    ///
+    /// Crash now, discarding everything which was not made durable
+    ///
+    pub fn crash(&self) {
+        self.state.lock().unwrap().crash();
+    }
+
+    ///
+    /// Let operations more mutating operations succeed and crash on the one
+    /// after, which fails without taking effect
+    ///
+    pub fn crash_after(&self, operations: u64) {
+        self.state.lock().unwrap().crash_after = Some(operations);
+    }
+
+    pub fn has_crashed(&self) -> bool {
+        self.state.lock().unwrap().crashed
+    }
+
+    ///
+    /// Start over after a crash, as a newly started process would. Directory
+    /// locks held before the crash are released
+    ///
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.crashed = false;
+        state.locked.clear();
+    }
+
+    ///
+    /// Keep up to bytes of the unsynced data of each file through a crash,
+    /// tearing any write which was in flight
+    ///
+    pub fn tear_writes(&self, bytes: u64) {
+        self.state.lock().unwrap().torn_bytes = bytes;
+    }
+
+    ///
+    /// Make every rename fail without taking effect
+    ///
+    pub fn fail_renames(&self, fail: bool) {
+        self.state.lock().unwrap().fail_renames = fail;
+    }
+
+    ///
+    /// Limit the total bytes held by all files. An append which does not fit
+    /// writes what it can and then fails with ErrorKind::StorageFull
+    ///
+    pub fn set_capacity(&self, capacity: Option<u64>) {
+        self.state.lock().unwrap().capacity = capacity;
+    }
+
+    ///
+    /// Make every sync wait until syncs are unblocked again
+    ///
+    pub fn block_syncs(&self, blocked: bool) {
+        self.state.lock().unwrap().syncs_blocked = blocked;
+        if !blocked {
+            self.unblocked.notify_all();
+        }
+    }
+
+    ///
+    /// Number of syncs currently waiting for syncs to be unblocked
+    ///
+    pub fn blocked_syncs(&self) -> u64 {
+        self.state.lock().unwrap().blocked_syncs
+    }
+
+    fn open_handle(&self, path: &Path, writable: bool) -> Result<Arc<dyn FileHandle>> {
+        let state = self.state.lock().unwrap();
+        state.check()?;
+        match state.files.get(path) {
+            Some(file) => Ok(Arc::new(MemHandle {
+                state: self.state.clone(),
+                unblocked: self.unblocked.clone(),
+                file: file.clone(),
+                writable,
+            })),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found", path.display()),
+            )),
+        }
+    }
+}
+
+impl FileSystem for FaultyFileSystem {
+    fn create(&self, path: &Path) -> Result<Arc<dyn FileHandle>> {
+        let mut state = self.state.lock().unwrap();
+        state.mutate()?;
+        let file = Arc::new(Mutex::new(MemFile::default()));
+        state.files.insert(path.to_path_buf(), file.clone());
+        Ok(Arc::new(MemHandle {
+            state: self.state.clone(),
+            unblocked: self.unblocked.clone(),
+            file,
+            writable: true,
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn FileHandle>> {
+        self.open_handle(path, true)
+    }
+
+    fn open_read_only(&self, path: &Path) -> Result<Arc<dyn FileHandle>> {
+        self.open_handle(path, false)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.mutate()?;
+        if state.fail_renames {
+            return Err(Error::other("simulated rename failure"));
+        }
+        match state.files.remove(from) {
+            Some(file) => {
+                state.files.insert(to.to_path_buf(), file);
+                Ok(())
+            }
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found", from.display()),
+            )),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.mutate()?;
+        match state.files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found", path.display()),
+            )),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        state.check()?;
+        Ok(state
+            .files
+            .keys()
+            .filter(|file_path| file_path.parent() == Some(path))
+            .filter_map(|file_path| file_path.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect())
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.mutate()?;
+        let state = &mut *state;
+        state
+            .durable_files
+            .retain(|file_path, _| file_path.parent() != Some(path));
+        for (file_path, file) in &state.files {
+            if file_path.parent() == Some(path) {
+                state.durable_files.insert(file_path.clone(), file.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn lock_dir(&self, path: &Path) -> Result<Box<dyn Any + Send + Sync>> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        if !state.locked.insert(path.to_path_buf()) {
+            return Err(Error::new(
+                ErrorKind::ResourceBusy,
+                format!("directory {} in use", path.display()),
+            ));
+        }
+        Ok(Box::new(MemLock {
+            state: self.state.clone(),
+            path: path.to_path_buf(),
+            generation: state.generation,
+        }))
+    }
+}
+
+impl MemHandle {
+    fn check_writable(&self) -> Result<()> {
+        if !self.writable {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "file opened read-only",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FileHandle for MemHandle {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.state.lock().unwrap().check()?;
+        let file = self.file.lock().unwrap();
+        let start = (offset as usize).min(file.data.len());
+        let read = buf.len().min(file.data.len() - start);
+        buf[..read].copy_from_slice(&file.data[start..start + read]);
+        Ok(read)
+    }
+
+    fn append(&self, buf: &[u8]) -> Result<u64> {
+        self.check_writable()?;
+        let mut state = self.state.lock().unwrap();
+        state.mutate()?;
+        let available = match state.capacity {
+            Some(capacity) => capacity.saturating_sub(state.used_bytes()),
+            None => u64::MAX,
+        };
+
+        let mut file = self.file.lock().unwrap();
+        let offset = file.data.len() as u64;
+        if (buf.len() as u64) > available {
+            file.data.extend_from_slice(&buf[..available as usize]);
+            return Err(Error::new(ErrorKind::StorageFull, "no space left on device"));
+        }
+        file.data.extend_from_slice(buf);
+        Ok(offset)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        self.check_writable()?;
+        self.state.lock().unwrap().mutate()?;
+        let mut file = self.file.lock().unwrap();
+        file.data.truncate(len as usize);
+        file.synced_len = file.synced_len.min(len);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.syncs_blocked {
+            state.blocked_syncs += 1;
+            state = self
+                .unblocked
+                .wait_while(state, |state| state.syncs_blocked)
+                .unwrap();
+            state.blocked_syncs -= 1;
+        }
+        state.mutate()?;
+        drop(state);
+        let mut file = self.file.lock().unwrap();
+        file.synced_len = file.data.len() as u64;
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.state.lock().unwrap().check()?;
+        Ok(self.file.lock().unwrap().data.len() as u64)
+    }
+}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation == self.generation {
+            state.locked.remove(&self.path);
+        }
+    }
+}
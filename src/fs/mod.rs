@@ -0,0 +1,134 @@
+use std::any::Any;
+use std::io::{Read, Result, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+///
+/// Filesystem operations used by the log. Every file and directory access
+/// made by Log and LogFile goes through this trait, so the storage layer can
+/// be run against a simulated filesystem to test crash behaviour
+///
+pub trait FileSystem: Send + Sync {
+    ///
+    /// Create an empty file, truncating any existing file at path
+    ///
+    fn create(&self, path: &Path) -> Result<Arc<dyn FileHandle>>;
+
+    ///
+    /// Open an existing file for reading and appending
+    ///
+    fn open(&self, path: &Path) -> Result<Arc<dyn FileHandle>>;
+
+    ///
+    /// Open an existing file for reading only. Appending to or truncating
+    /// the returned handle fails
+    ///
+    fn open_read_only(&self, path: &Path) -> Result<Arc<dyn FileHandle>>;
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    ///
+    /// Names of every file in the directory at path
+    ///
+    fn read_dir(&self, path: &Path) -> Result<Vec<String>>;
+
+    ///
+    /// Make files created, renamed or removed within the directory at path
+    /// durable
+    ///
+    fn sync_dir(&self, path: &Path) -> Result<()>;
+
+    ///
+    /// Take an exclusive lock over the directory at path, held until the
+    /// returned value is dropped. Fails with ErrorKind::ResourceBusy if the
+    /// directory is already locked
+    ///
+    fn lock_dir(&self, path: &Path) -> Result<Box<dyn Any + Send + Sync>>;
+}
+
+///
+/// Read-only view of the whole contents of a file, as returned by
+/// FileHandle::map
+///
+pub type FileMap = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+///
+/// Open file which is only ever appended to. Reads are positional, so a
+/// handle can be shared between readers without coordinating a cursor
+///
+pub trait FileHandle: Send + Sync {
+    ///
+    /// Read into buf from offset, returning the number of bytes read, which
+    /// is 0 at the end of the file
+    ///
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
+
+    ///
+    /// Append all of buf to the end of the file, returning the offset it was
+    /// written at
+    ///
+    fn append(&self, buf: &[u8]) -> Result<u64>;
+
+    ///
+    /// Cut the file back to len bytes
+    ///
+    fn truncate(&self, len: u64) -> Result<()>;
+
+    fn sync_data(&self) -> Result<()>;
+
+    fn size(&self) -> Result<u64>;
+
+    ///
+    /// Map the current contents of the file into memory for reading. The
+    /// file must not be appended to or truncated, by this or any other
+    /// process, while the map is held.
+    /// Returns None if the filesystem does not support mapping files
+    ///
+    fn map(&self) -> Result<Option<FileMap>> {
+        Ok(None)
+    }
+}
+
+///
+/// Sequential reader over a FileHandle starting from a given offset
+///
+pub(crate) struct HandleReader {
+    handle: Arc<dyn FileHandle>,
+    offset: u64,
+}
+
+impl HandleReader {
+    pub(crate) fn new(handle: Arc<dyn FileHandle>, offset: u64) -> HandleReader {
+        HandleReader { handle, offset }
+    }
+}
+
+impl Read for HandleReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.handle.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for HandleReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.handle.size()?.checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek to a negative offset")
+        })?;
+        Ok(self.offset)
+    }
+}
+
+pub mod os_file_system;
+pub mod faulty_file_system;
+
+pub use os_file_system::OsFileSystem;
+pub use faulty_file_system::FaultyFileSystem;
@@ -0,0 +1,105 @@
+use std::any::Any;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use super::{FileHandle, FileMap, FileSystem};
+use crate::log::DirectoryLock;
+
+///
+/// FileSystem backed by the real OS filesystem
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFileSystem;
+
+struct OsFile {
+    file: File,
+}
+
+impl FileSystem for OsFileSystem {
+    fn create(&self, path: &Path) -> Result<Arc<dyn FileHandle>> {
+        // Truncating requires write rather than append access, so the file
+        // is created first and then reopened for appending
+        File::create(path)?;
+        self.open(path)
+    }
+
+    fn open(&self, path: &Path) -> Result<Arc<dyn FileHandle>> {
+        let file = OpenOptions::new().read(true).append(true).open(path)?;
+        Ok(Arc::new(OsFile { file }))
+    }
+
+    fn open_read_only(&self, path: &Path) -> Result<Arc<dyn FileHandle>> {
+        Ok(Arc::new(OsFile {
+            file: File::open(path)?,
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<String>> {
+        std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().to_string()))
+            .collect()
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn lock_dir(&self, path: &Path) -> Result<Box<dyn Any + Send + Sync>> {
+        Ok(Box::new(DirectoryLock::acquire(path)?))
+    }
+}
+
+impl FileHandle for OsFile {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        std::os::unix::fs::FileExt::read_at(&self.file, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> Result<u64> {
+        // The file is opened in append mode, and the log only appends from a
+        // single writer at a time, so the data lands at the current length
+        let offset = self.size()?;
+        (&self.file).write_all(buf)?;
+        Ok(offset)
+    }
+
+    fn truncate(&self, len: u64) -> Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        self.file.sync_data()
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn map(&self) -> Result<Option<FileMap>> {
+        // Safety: the log only maps sealed files of a directory it holds the
+        // lock on. Sealed files are never appended to again, and the only
+        // other process which may truncate them, kvs check --repair, takes
+        // the lock first. Compaction deletes them, which leaves existing
+        // maps valid. Anything else modifying the files breaks this, and a
+        // read of a truncated page raises SIGBUS
+        let map = unsafe { Mmap::map(&self.file)? };
+        Ok(Some(Arc::new(map)))
+    }
+}
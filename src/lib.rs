@@ -1,14 +1,19 @@
 //! Basic key value store implementation using in-memory storage
 //!
-#![feature(seek_stream_len)]
 #![feature(let_chains)]
-#![feature(btree_cursors)]
 
 
 
 pub mod log;
+pub mod fs;
+pub mod durability;
+pub mod limits;
+pub mod check;
+pub mod dump;
+pub mod migrate;
 pub mod server;
 pub mod net;
+pub mod tls;
 pub mod client;
 pub mod engines;
 pub mod thread_pool;
@@ -0,0 +1,107 @@
+use std::fmt::Display;
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+///
+/// Upper bounds on the size of keys, values and network requests. Requests
+/// over the frame size are rejected by the server before their contents are
+/// allocated, and keys or values over their limit are rejected by both the
+/// server and the engines
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    pub max_frame_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_key_size: 64 * 1024,
+            max_value_size: 16 * 1024 * 1024,
+            max_frame_size: 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_key(&self, key: &str) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(limit_exceeded(format!(
+                "key of {} bytes exceeds the maximum key size of {} bytes",
+                key.len(),
+                self.max_key_size
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn check_value(&self, value: &str) -> Result<()> {
+        if value.len() > self.max_value_size {
+            return Err(limit_exceeded(format!(
+                "value of {} bytes exceeds the maximum value size of {} bytes",
+                value.len(),
+                self.max_value_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+///
+/// Bounds on the server's connections. A connection is closed once it has
+/// waited idle_timeout for a request, or once a request or response takes
+/// longer than read_timeout or write_timeout to arrive or be taken. None
+/// leaves a bound off.
+///
+/// Connections beyond max_connections are turned away with a
+/// LimitExceeded error, as is the request after the first
+/// max_requests_per_connection, which also closes its connection
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub max_connections: Option<usize>,
+    pub max_requests_per_connection: Option<u64>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(300)),
+            max_connections: None,
+            max_requests_per_connection: None,
+        }
+    }
+}
+
+///
+/// Error carried inside an io::Error when a request breaks one of the
+/// configured limits. Use is_limit_exceeded to tell it apart from other
+/// failures
+///
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub what: String,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "limit exceeded: {}", self.what)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+pub fn limit_exceeded(what: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, LimitExceeded { what })
+}
+
+pub fn is_limit_exceeded(err: &Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<LimitExceeded>())
+}
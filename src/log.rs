@@ -1,16 +1,24 @@
 use slog::Logger;
 use slog::*;
-use std::collections::{BTreeMap, HashMap};
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Result, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::sync::atomic::AtomicU64;
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
 
+use crate::durability::Durability;
+use crate::fs::{FileHandle, FileMap, FileSystem, HandleReader};
+use bincode::Options;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -40,13 +48,43 @@ pub(crate) struct LogRecord {
     pub(crate) operation: LogOperation,
 }
 
+///
+/// Size at which the tail file is sealed and a new tail file started. Also
+/// the size at which compaction starts a new output file
+///
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+///
+/// Number of files sealed since the last compaction before the log reports
+/// that another compaction is due
+///
+const COMPACTION_TRIGGER_FILES: u64 = 4;
+
+///
+/// Bytes of compaction I/O between each check of the rate limit and update
+/// of the compaction progress
+///
+const COMPACTION_CHUNK_SIZE: u64 = 64 * 1024;
+
+///
+/// How long the log stays read only after a write fails for lack of space
+/// before another write is let through to check whether space has been
+/// freed
+///
+const DISK_FULL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// Magic number stored in the header of every MANIFEST file
+///
+pub(crate) const MANIFEST_MAGIC_NUMBER: u64 = 0xDEAD_BEEF;
+
 ///
 /// Header indicating the start of a file manifest
 ///
 #[derive(Serialize, Deserialize)]
-struct FileManifestHeader {
-    magic_number: u64,
-    entry_count: u16,
+pub(crate) struct FileManifestHeader {
+    pub(crate) magic_number: u64,
+    pub(crate) entry_count: u16,
 }
 
 ///
@@ -55,10 +93,10 @@ struct FileManifestHeader {
 /// index = 0 and that file is the active file being appended to
 ///
 #[derive(Serialize, Deserialize, Copy, Clone)]
-struct FileManifestRecord {
-    file_number: u16,
-    max_index: u64,
-    min_index: u64,
+pub(crate) struct FileManifestRecord {
+    pub(crate) file_number: u16,
+    pub(crate) max_index: u64,
+    pub(crate) min_index: u64,
 }
 
 ///
@@ -73,9 +111,22 @@ struct LogFile {
     // Mapping from log index to offset into the file
     index_map: HashMap<u64, u64>,
 
-    // Pointer to a File object which can be used to read/write from/to the
-    // file as needed
-    file: Arc<File>,
+    // Handle used to read from and append to the file as needed
+    file: Arc<dyn FileHandle>,
+
+    // Memory map of the file which reads are served from instead of the
+    // handle. Only ever set once the file is sealed
+    map: Option<FileMap>,
+
+    // Length of the file up to the end of the last complete record
+    valid_len: u64,
+
+    // Set when a failed write could not be rolled back, so the file may
+    // hold a partial record past valid_len which must be cut off before
+    // the next write
+    torn: bool,
+
+    fs: Arc<dyn FileSystem>,
 
     logger: Option<Logger>,
 
@@ -88,48 +139,74 @@ impl LogFile {
     /// the file to open and the base path to the directory storing the log
     ///
     /// During the open operation, the log files must be scanned sequentially
-    /// to rebuild the index -> offset mapping used for lookups at runtime
+    /// to rebuild the index -> offset mapping used for lookups at runtime.
+    /// Each record is passed to visit as it is scanned, in index order. The
+    /// tail file may end in a record torn by a crash part way through a
+    /// write, which is truncated away, or skipped over when read_only is
+    /// set. Any other unreadable record fails the open
     ///
     fn open(
         logger: &Option<Logger>,
+        fs: Arc<dyn FileSystem>,
         path: PathBuf,
-        manifest_record: FileManifestRecord,
+        mut manifest_record: FileManifestRecord,
+        is_tail: bool,
+        read_only: bool,
+        visit: &mut dyn FnMut(LogRecord),
     ) -> Result<LogFile> {
-        let log_file_path = path.join(format!("{}.log", manifest_record.file_number));
+        let log_file_path = Self::file_path(&path, manifest_record.file_number);
 
         if let Some(logger) = logger {
             info!(logger, "Opening log file"; "file_name" => log_file_path.to_str());
         }
 
-        let mut file = Arc::new(
-            OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&log_file_path)?,
-        );
-
-        // Upon scanning, build a map from index -> offset for each record in the file
-        file.seek(SeekFrom::Start(0))?;
+        let file = if read_only {
+            fs.open_read_only(&log_file_path)?
+        } else {
+            fs.open(&log_file_path)?
+        };
 
         if let Some(logger) = logger {
             info!(logger, "Scanning log file"; "file_name" => log_file_path.to_str());
         }
 
+        // Upon scanning, build a map from index -> offset for each record in the file
         let mut index_map: HashMap<u64, u64> = HashMap::new();
-        loop {
-            let offset = file.stream_position()?;
-            if offset == file.stream_len()? {
-                break;
+        let mut scanner = RecordScanner::from_handle(file.clone())?;
+        for entry in scanner.by_ref() {
+            match entry {
+                Ok((offset, log_record)) => {
+                    index_map.insert(log_record.index, offset);
+                    visit(log_record);
+                }
+                Err(err) if is_tail => {
+                    if let Some(logger) = logger {
+                        warn!(logger, "Truncating torn tail"; "file_name" => log_file_path.to_str(), "error" => err.to_string());
+                    }
+                }
+                Err(err) => return Err(err),
             }
-            let log_record: LogRecord =
-                bincode::deserialize_from(&mut file).map_err(|e| Error::other(e))?;
-            index_map.insert(log_record.index, offset);
+        }
+
+        if !read_only && scanner.valid_len() < scanner.file_len() {
+            file.truncate(scanner.valid_len())?;
+            file.sync_data()?;
+        }
+
+        // The tail file holds records past the max_index in the MANIFEST if
+        // the log was not closed cleanly
+        if let Some(max_index) = index_map.keys().max() {
+            manifest_record.max_index = manifest_record.max_index.max(*max_index);
         }
 
         Ok(LogFile {
             path,
             manifest_record,
             file,
+            map: None,
+            valid_len: scanner.valid_len(),
+            torn: false,
+            fs,
             index_map,
             logger: logger
                 .clone()
@@ -138,151 +215,460 @@ impl LogFile {
     }
 
     ///
-    /// Create a new log file based on the specification described by the
-    /// provided FileManifestRecord. The max_index will be u64::MAX while
-    /// min_index will be the next index to be inserted into the log file
-    /// upon a successful write
+    /// Create a new, empty log file based on the specification described by
+    /// the provided FileManifestRecord. The min_index should be the first
+    /// index to be written into the file, and max_index is raised by each
+    /// write. Any existing file with the same number is truncated
     ///
     fn create(
         logger: &Option<Logger>,
+        fs: Arc<dyn FileSystem>,
         path: PathBuf,
         manifest_record: FileManifestRecord,
     ) -> Result<LogFile> {
-        let log_file_path = path.join(manifest_record.file_number.to_string());
+        let log_file_path = Self::file_path(&path, manifest_record.file_number);
         Ok(LogFile {
-            path,
             manifest_record,
             index_map: HashMap::new(),
-            file: Arc::new(File::create(&log_file_path)?),
+            file: fs.create(&log_file_path)?,
+            map: None,
+            valid_len: 0,
+            torn: false,
+            fs,
+            path,
             logger: logger
                 .clone()
                 .map(|l| l.new(o!("file_name" => log_file_path.to_string_lossy().to_string()))),
         })
     }
 
+    fn file_path(path: &Path, file_number: u16) -> PathBuf {
+        path.join(format!("{}.log", file_number))
+    }
+
     ///
-    /// Read a log record from the file based on the LogRecord's index. Will
-    /// return an error if the index is not present in this log file
+    /// Delete the file backing this log file
     ///
-    fn read(&mut self, index: u64) -> Result<LogRecord> {
+    fn remove(&self) -> Result<()> {
+        self.fs
+            .remove_file(&Self::file_path(&self.path, self.manifest_record.file_number))
+    }
+
+    ///
+    /// Find the record with the given index in this file, returning where to
+    /// read it from. Will return an error if the index is not present in
+    /// this log file
+    ///
+    fn locate(&self, index: u64) -> Result<RecordLocation> {
         if let Some(ref logger) = self.logger {
             info!(logger, "Reading record"; "index" => index);
         }
 
-        self.file.seek(SeekFrom::Start(
-            *self.index_map.get(&index).ok_or(ErrorKind::NotFound)?,
-        ))?;
-        Ok(bincode::deserialize_from(&*self.file).map_err(|e| Error::other(e.to_string()))?)
+        Ok(RecordLocation {
+            file: self.file.clone(),
+            map: self.map.clone(),
+            offset: *self.index_map.get(&index).ok_or(ErrorKind::NotFound)?,
+        })
     }
 
     ///
-    /// Write a new log record into the tail of the file
+    /// Serve reads from a memory map of the file rather than through its
+    /// handle. Must only be called once the file is sealed. Mapping is only
+    /// an optimisation, so a file which cannot be mapped keeps using the
+    /// handle
+    ///
+    fn map(&mut self) {
+        match self.file.map() {
+            Ok(map) => self.map = map,
+            Err(err) => {
+                if let Some(ref logger) = self.logger {
+                    warn!(logger, "Failed to map log file"; "error" => err.to_string());
+                }
+            }
+        }
+    }
+
+    fn unmap(&mut self) {
+        self.map = None;
+    }
+
     ///
-    fn write(&mut self, record: LogRecord) -> Result<()> {
+    /// Write a new log record into the tail of the file, returning the number
+    /// of bytes appended
+    ///
+    fn write(&mut self, record: LogRecord) -> Result<u64> {
         if let Some(ref logger) = self.logger {
             info!(logger, "Writing record"; "index" => record.index);
         }
 
-        let offset = self.file.seek(SeekFrom::End(0))?;
-        let index = record.index;
-        bincode::serialize_into(self.file.as_ref(), &record)
-            .map_err(|e| Error::other(e.to_string()))?;
-        self.index_map.insert(index, offset);
-        self.manifest_record.max_index = self.manifest_record.max_index.max(index);
+        if self.torn {
+            self.file.truncate(self.valid_len)?;
+            self.torn = false;
+        }
+
+        // Serialized up front so the record is appended in a single write
+        let buf = bincode::serialize(&record).map_err(|e| Error::other(e.to_string()))?;
+        let offset = match self.file.append(&buf) {
+            Ok(offset) => offset,
+            Err(err) => {
+                self.rollback(self.valid_len, record.index);
+                return Err(err);
+            }
+        };
+        self.valid_len = offset + buf.len() as u64;
+        self.index_map.insert(record.index, offset);
+        self.manifest_record.max_index = self.manifest_record.max_index.max(record.index);
 
         if let Some(ref logger) = self.logger {
             info!(logger, "Wrote record"; "index" => record.index);
         }
 
-        Ok(())
+        Ok(buf.len() as u64)
+    }
+
+    ///
+    /// Undo a failed write of the record at index, cutting the file back to
+    /// len, its length before the write. If the file cannot be cut back now
+    /// it is cut back before the next write
+    ///
+    fn rollback(&mut self, len: u64, index: u64) {
+        self.index_map.remove(&index);
+        self.valid_len = len;
+        if let Err(err) = self.file.truncate(len) {
+            if let Some(ref logger) = self.logger {
+                warn!(logger, "Failed to roll back write"; "index" => index, "error" => err.to_string());
+            }
+            self.torn = true;
+        }
     }
 
     fn size(&self) -> Result<u64> {
-        Ok(self.file.metadata()?.len())
+        self.file.size()
     }
 
     ///
-    /// Perform compaction on the file to shrink it. The predicate provided is
-    /// used to determine if a log record should or should not remain in the
-    /// file
+    /// Total bytes taken by the records whose index is in live. Record sizes
+    /// are derived from the gap to the next record's offset, since records
+    /// are laid out back to back in the file
     ///
-    fn compact<F: Fn(&LogRecord) -> bool>(&mut self, predicate: &F) -> Result<()> {
-        if let Some(ref logger) = self.logger {
-            info!(logger, "Compacting file"; "file_number" => self.manifest_record.file_number);
+    fn live_bytes(&self, live: &LiveRecords) -> Result<u64> {
+        let mut offsets: Vec<(u64, u64)> = self
+            .index_map
+            .iter()
+            .map(|(index, offset)| (*offset, *index))
+            .collect();
+        offsets.sort_unstable();
+
+        let size = self.size()?;
+        Ok(offsets
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, index))| live.contains(*index))
+            .map(|(i, (offset, _))| offsets.get(i + 1).map_or(size, |next| next.0) - offset)
+            .sum())
+    }
+}
+
+fn disk_full_error() -> Error {
+    Error::new(ErrorKind::StorageFull, "store read-only: disk full")
+}
+
+///
+/// Deserialize the record stored at offset in file
+///
+///
+/// Place a record was found at, which holds on to the file's handle and map
+/// so it can be read without holding the log's files
+///
+struct RecordLocation {
+    file: Arc<dyn FileHandle>,
+    map: Option<FileMap>,
+    offset: u64,
+}
+
+impl RecordLocation {
+    fn read(&self) -> Result<LogRecord> {
+        match self.map {
+            Some(ref map) => {
+                let record = map
+                    .as_ref()
+                    .as_ref()
+                    .get(self.offset as usize..)
+                    .ok_or(ErrorKind::UnexpectedEof)?;
+                bincode::deserialize(record).map_err(|e| Error::other(e.to_string()))
+            }
+            None => read_record(&self.file, self.offset),
         }
+    }
+}
 
-        let new_file_name = self
-            .path
-            .join(format!("{}.log.tmp", self.manifest_record.file_number));
-        let old_file_name = self
-            .path
-            .join(format!("{}.log", self.manifest_record.file_number));
+fn read_record(file: &Arc<dyn FileHandle>, offset: u64) -> Result<LogRecord> {
+    bincode::deserialize_from(BufReader::new(HandleReader::new(file.clone(), offset)))
+        .map_err(|e| Error::other(e.to_string()))
+}
 
-        let mut output_file = File::options()
-            .write(true)
-            .create(true)
-            .open(&new_file_name)?;
-
-        for record in FileIterator::new(self)? {
-            match record {
-                Ok((record, _)) => {
-                    if predicate(&record) {
-                        bincode::serialize_into(&output_file, &record)
-                            .map_err(|e| Error::other(e))?;
-                    }
+///
+/// Sequential scan over the raw contents of a log file, independent of any
+/// MANIFEST or in-memory index. Yields each record along with the offset it
+/// was read from, and stops after the first record which fails to deserialize.
+/// Used by offline tooling which must cope with damaged files, and to rebuild
+/// the index of a file when it is opened
+///
+pub(crate) struct RecordScanner<R = File> {
+    reader: BufReader<R>,
+
+    // Offset just past the last record which deserialized successfully
+    valid_len: u64,
+
+    file_len: u64,
+
+    failed: bool,
+}
+
+impl RecordScanner<File> {
+    pub(crate) fn open(path: &Path) -> Result<RecordScanner<File>> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        Ok(RecordScanner::new(file, file_len))
+    }
+}
+
+impl RecordScanner<HandleReader> {
+    pub(crate) fn from_handle(handle: Arc<dyn FileHandle>) -> Result<RecordScanner<HandleReader>> {
+        let file_len = handle.size()?;
+        Ok(RecordScanner::new(HandleReader::new(handle, 0), file_len))
+    }
+}
+
+impl<R: Read + Seek> RecordScanner<R> {
+    fn new(reader: R, file_len: u64) -> RecordScanner<R> {
+        RecordScanner {
+            reader: BufReader::new(reader),
+            valid_len: 0,
+            file_len,
+            failed: false,
+        }
+    }
+
+    ///
+    /// Length of the prefix of the file holding complete, readable records
+    ///
+    pub(crate) fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    pub(crate) fn file_len(&self) -> u64 {
+        self.file_len
+    }
+}
+
+impl<R: Read + Seek> Iterator for RecordScanner<R> {
+    type Item = Result<(u64, LogRecord)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.valid_len >= self.file_len {
+            return None;
+        }
+
+        // Bound the read by the bytes remaining so a corrupt length prefix
+        // cannot trigger an enormous allocation
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(self.file_len - self.valid_len);
+
+        let offset = self.valid_len;
+        match options.deserialize_from::<_, LogRecord>(&mut self.reader) {
+            Ok(record) => match self.reader.stream_position() {
+                Ok(position) => {
+                    self.valid_len = position;
+                    Some(Ok((offset, record)))
                 }
                 Err(err) => {
-                    return Err(err);
+                    self.failed = true;
+                    Some(Err(err))
                 }
+            },
+            Err(err) => {
+                self.failed = true;
+                Some(Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("corrupt record at offset {}: {}", offset, err),
+                )))
             }
         }
+    }
+}
 
-        let new_size = output_file.stream_len()?;
+///
+/// Records referenced by the key directory as of a snapshot of it. Records
+/// at or above watermark were written after the snapshot, so are treated as
+/// live whether or not the key directory refers to them yet
+///
+pub(crate) struct LiveRecords {
+    pub(crate) indexes: HashSet<u64>,
+    pub(crate) watermark: u64,
+}
 
-        if let Some(ref logger) = self.logger {
-            info!(logger, "Compacted log file"; "file_name" => old_file_name.to_str(), "original_size" => self.file.stream_len()?, "new_size" => new_size);
+impl LiveRecords {
+    fn contains(&self, index: u64) -> bool {
+        index >= self.watermark || self.indexes.contains(&index)
+    }
+}
+
+///
+/// Space accounting for a single log file
+///
+pub(crate) struct LogFileStats {
+    pub(crate) file_number: u16,
+    pub(crate) size: u64,
+    pub(crate) live_bytes: u64,
+}
+
+///
+/// Progress of the compaction in progress, or of the last one to finish
+///
+#[derive(Clone, Copy, Default)]
+pub(crate) struct CompactionProgress {
+    pub(crate) running: bool,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+    pub(crate) files_done: u64,
+    pub(crate) files_total: u64,
+}
+
+///
+/// Rate limit and cancellation of a compaction, shared with the store so
+/// both can be changed while the compaction runs
+///
+pub(crate) struct CompactionControl {
+    // Bytes per second, or 0 when unthrottled
+    pub(crate) rate_limit: AtomicU64,
+    cancelled: AtomicBool,
+
+    // Wakes a compaction sleeping off its rate limit once cancelled
+    paused: Mutex<()>,
+    wake: Condvar,
+}
+
+impl CompactionControl {
+    pub(crate) fn new() -> CompactionControl {
+        CompactionControl {
+            rate_limit: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            paused: Mutex::new(()),
+            wake: Condvar::new(),
         }
+    }
 
-        std::fs::rename(&old_file_name, &new_file_name)?;
-        self.file = Arc::new(File::open(old_file_name)?);
+    ///
+    /// Stop any compaction running under this control, and every later one,
+    /// at its next record. The compaction fails with ErrorKind::Interrupted
+    /// and leaves the log as it was
+    ///
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        let _paused = self.paused.lock().unwrap();
+        self.wake.notify_all();
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::Interrupted, "compaction cancelled"));
+        }
         Ok(())
     }
+
+    ///
+    /// Sleep for duration, or until cancelled
+    ///
+    fn pause(&self, duration: Duration) -> Result<()> {
+        let paused = self.paused.lock().unwrap();
+        let _ = self
+            .wake
+            .wait_timeout_while(paused, duration, |_| !self.cancelled.load(Ordering::SeqCst))
+            .unwrap();
+        self.check_cancelled()
+    }
 }
 
 ///
-/// Implements iteration over a single log file
+/// Paces compaction I/O to a rate limit which may change while compaction
+/// runs. Bytes are counted in chunks, and at the end of each chunk the
+/// compaction sleeps for however long the chunk should have taken
 ///
-struct FileIterator {
-    // Pointer to the file to be read from
-    log_file: File,
-
-    // Next offset in the file to read a record from
-    iter: std::collections::hash_map::IntoIter<u64, u64>,
+struct Throttle<'a> {
+    control: &'a CompactionControl,
+    chunk_start: Instant,
+    chunk_bytes: u64,
 }
 
-impl FileIterator {
-    fn new(log_file: &LogFile) -> Result<FileIterator> {
-        Ok(FileIterator {
-            log_file: File::try_clone(&log_file.file)?,
-            iter: log_file.index_map.clone().into_iter(),
-        })
+impl Throttle<'_> {
+    ///
+    /// Count bytes of I/O, returning true at the end of a chunk. Fails once
+    /// the compaction is cancelled
+    ///
+    fn consume(&mut self, bytes: u64) -> Result<bool> {
+        self.control.check_cancelled()?;
+        self.chunk_bytes += bytes;
+        if self.chunk_bytes < COMPACTION_CHUNK_SIZE {
+            return Ok(false);
+        }
+
+        let rate_limit = self.control.rate_limit.load(Ordering::SeqCst);
+        if rate_limit > 0 {
+            let target = Duration::from_secs_f64(self.chunk_bytes as f64 / rate_limit as f64);
+            if let Some(remaining) = target.checked_sub(self.chunk_start.elapsed()) {
+                self.control.pause(remaining)?;
+            }
+        }
+
+        self.chunk_start = Instant::now();
+        self.chunk_bytes = 0;
+        Ok(true)
     }
 }
 
-impl Iterator for FileIterator {
-    type Item = Result<(LogRecord, u64)>;
+///
+/// Exclusive advisory lock over a log directory, held for as long as the
+/// value is alive. The pid of the holder is written into the LOCK file so a
+/// second process can report who has the directory open
+///
+pub(crate) struct DirectoryLock {
+    _file: File,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.iter.next() {
-            Some((_, offset)) => match self.log_file.seek(SeekFrom::Start(offset)) {
-                Ok(_) => {
-                    let record: LogRecord = bincode::deserialize_from(&mut self.log_file).unwrap();
-                    Some(Ok((record, offset)))
-                }
-                Err(err) => Some(Err(err)),
-            },
-            None => None,
+impl DirectoryLock {
+    pub(crate) fn acquire(path: &Path) -> Result<DirectoryLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join("LOCK"))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(Error::new(
+                    ErrorKind::ResourceBusy,
+                    format!(
+                        "directory {} in use by pid {}",
+                        path.display(),
+                        if pid.is_empty() { "unknown" } else { pid.trim() }
+                    ),
+                ));
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
         }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_data()?;
+
+        Ok(DirectoryLock { _file: file })
     }
 }
 
@@ -295,27 +681,138 @@ pub(crate) struct Log {
     // Ordered log files, in increasing index number, the last file in the
     // BTreeMap is the current file being appended into. The key is the
     // smallest index associated with the LogFile
-    log_files: Mutex<BTreeMap<u64, LogFile>>,
+    log_files: Arc<Mutex<BTreeMap<u64, LogFile>>>,
 
     // Next index number for the next write to the log
     next_index: AtomicU64,
 
+    durability: Durability,
+
+    // Bytes written to the tail file since it was last synced
+    unsynced_bytes: Arc<AtomicU64>,
+
+    // Records written since the log was opened
+    writes: AtomicU64,
+
+    last_compaction: Mutex<Option<SystemTime>>,
+
+    // Number handed to the next log file created. Wraps around, skipping
+    // numbers still in use
+    next_file_number: AtomicU16,
+
+    // Files sealed since the last compaction started
+    sealed_since_compaction: AtomicU64,
+
+    // Held for the duration of a compaction so only one runs at a time
+    compacting: Mutex<()>,
+
+    // Held for the duration of a write, including its sync, so writes are
+    // serialized without holding log_files and blocking reads meanwhile
+    writing: Mutex<()>,
+
+    compaction_progress: Mutex<CompactionProgress>,
+
+    // Whether sealed files are memory mapped for reads
+    mmap_reads: AtomicBool,
+
+    // Time of the last write which failed for lack of space, while the log
+    // is rejecting writes. Cleared by the next write to succeed
+    disk_full: Mutex<Option<Instant>>,
+
+    // Background thread syncing the tail file under Durability::Periodic.
+    // Dropping the sender stops the thread
+    flusher: Option<(Sender<()>, JoinHandle<()>)>,
+
     logger: Option<Logger>,
 
     path: PathBuf,
+
+    fs: Arc<dyn FileSystem>,
+
+    // Held for the lifetime of the log so no other process can append to
+    // the same files or rewrite the MANIFEST underneath us. None when the
+    // log is opened read only, which takes no lock so it can be opened
+    // alongside a writer
+    lock: Option<Box<dyn Any + Send + Sync>>,
 }
 
 impl Log {
     ///
     /// Open an existing log or create a new one using a specific directory as defined by path
     ///
-    pub(crate) fn open(logger: Option<Logger>, path: PathBuf) -> Result<Self> {
+    /// The log files are scanned in parallel, and replay folds the records
+    /// of each file, in index order, into a summary of that file. The
+    /// summaries are returned in index order alongside the log, so applying
+    /// them in turn replays the whole log
+    ///
+    pub(crate) fn open<T, F>(
+        logger: Option<Logger>,
+        path: PathBuf,
+        durability: Durability,
+        fs: Arc<dyn FileSystem>,
+        replay: F,
+    ) -> Result<(Self, Vec<T>)>
+    where
+        T: Default + Send,
+        F: Fn(&mut T, LogRecord) + Sync,
+    {
+        let lock = fs.lock_dir(&path)?;
+        Self::load(logger, path, durability, fs, Some(lock), replay)
+    }
+
+    ///
+    /// Open an existing log for reading only. Nothing in the directory is
+    /// created, modified or deleted, so the log may be on read-only storage
+    /// or opened while a writer holds the directory. Records written after
+    /// the log is opened are not seen, and a record the writer is part way
+    /// through appending is skipped. Every write fails with
+    /// ErrorKind::ReadOnlyFilesystem
+    ///
+    pub(crate) fn open_read_only<T, F>(
+        logger: Option<Logger>,
+        path: PathBuf,
+        fs: Arc<dyn FileSystem>,
+        replay: F,
+    ) -> Result<(Self, Vec<T>)>
+    where
+        T: Default + Send,
+        F: Fn(&mut T, LogRecord) + Sync,
+    {
+        Self::load(logger, path, Durability::Buffered, fs, None, replay)
+    }
+
+    ///
+    /// Scan and replay the log files, opening the log read only when no
+    /// directory lock is passed in
+    ///
+    fn load<T, F>(
+        logger: Option<Logger>,
+        path: PathBuf,
+        durability: Durability,
+        fs: Arc<dyn FileSystem>,
+        lock: Option<Box<dyn Any + Send + Sync>>,
+        replay: F,
+    ) -> Result<(Self, Vec<T>)>
+    where
+        T: Default + Send,
+        F: Fn(&mut T, LogRecord) + Sync,
+    {
+        let read_only = lock.is_none();
+
         // Mapping from first index in the log file, to the LogFile itself
         let mut log_files: BTreeMap<u64, LogFile> = BTreeMap::new();
 
-        let mut manifest_records = Self::read_manifest(&logger, path.clone())?;
+        let mut manifest_records = Self::read_manifest(&logger, fs.as_ref(), path.clone())?;
 
         let next_index;
+        let mut summaries = Vec::new();
+
+        if manifest_records.is_empty() && read_only {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no log found in {}", path.display()),
+            ));
+        }
 
         // Upon the first load, create an empty manifest and add a single log file to it
         if manifest_records.is_empty() {
@@ -324,21 +821,55 @@ impl Log {
                 info!(logger, "Creating new log file"; "file" => log_file_path.to_str());
             }
 
-            File::create(log_file_path)?;
+            fs.create(&log_file_path)?;
             manifest_records.push(FileManifestRecord {
                 file_number: 0,
                 max_index: u64::MIN,
                 min_index: u64::MIN,
             });
             next_index = 0;
-            Self::write_manifest(&logger, manifest_records.clone(), &path)?;
-
-            let log_file = LogFile::open(&logger, path.clone(), *manifest_records.get(0).unwrap())?;
+            Self::write_manifest(&logger, fs.as_ref(), manifest_records.clone(), &path)?;
+
+            let log_file = LogFile::open(
+                &logger,
+                fs.clone(),
+                path.clone(),
+                *manifest_records.get(0).unwrap(),
+                true,
+                false,
+                &mut |_| {},
+            )?;
             log_files.insert(log_file.manifest_record.min_index, log_file);
         } else {
-            for record in manifest_records {
-                let log_file = LogFile::open(&logger, path.clone(), record)?;
+            let tail_min_index = manifest_records.iter().map(|record| record.min_index).max();
+            let mut opened = manifest_records
+                .into_par_iter()
+                .map(|record| {
+                    let is_tail = Some(record.min_index) == tail_min_index;
+                    let mut summary = T::default();
+                    let log_file = LogFile::open(
+                        &logger,
+                        fs.clone(),
+                        path.clone(),
+                        record,
+                        is_tail,
+                        read_only,
+                        &mut |log_record| replay(&mut summary, log_record),
+                    )?;
+                    Ok((log_file, summary))
+                })
+                .collect::<Result<Vec<(LogFile, T)>>>()?;
+
+            // Each file holds a distinct range of indexes, so ordering the
+            // files by their first index orders every record in the log
+            opened.sort_by_key(|(log_file, _)| log_file.manifest_record.min_index);
+            for (log_file, summary) in opened {
                 log_files.insert(log_file.manifest_record.min_index, log_file);
+                summaries.push(summary);
+            }
+
+            if !read_only {
+                Self::remove_unlisted_files(&logger, fs.as_ref(), &path, &log_files)?;
             }
 
             next_index = log_files
@@ -351,12 +882,118 @@ impl Log {
             info!(logger, "Completed manifest scan"; "max_index" => next_index);
         }
 
-        Ok(Self {
-            log_files: Mutex::new(log_files),
+        let next_file_number = log_files
+            .values()
+            .map(|log_file| log_file.manifest_record.file_number)
+            .max()
+            .map_or(0, |file_number| file_number.wrapping_add(1));
+
+        let log_files = Arc::new(Mutex::new(log_files));
+        let unsynced_bytes = Arc::new(AtomicU64::new(0));
+        let flusher = match durability {
+            Durability::Periodic { interval, .. } => Some(Self::start_flusher(
+                log_files.clone(),
+                unsynced_bytes.clone(),
+                interval,
+            )?),
+            _ => None,
+        };
+
+        let log = Self {
+            log_files,
             next_index: AtomicU64::new(next_index),
+            durability,
+            unsynced_bytes,
+            writes: AtomicU64::new(0),
+            last_compaction: Mutex::new(None),
+            next_file_number: AtomicU16::new(next_file_number),
+            sealed_since_compaction: AtomicU64::new(0),
+            compacting: Mutex::new(()),
+            writing: Mutex::new(()),
+            compaction_progress: Mutex::new(CompactionProgress::default()),
+            mmap_reads: AtomicBool::new(false),
+            disk_full: Mutex::new(None),
+            flusher,
             logger,
             path,
-        })
+            fs,
+            lock,
+        };
+        Ok((log, summaries))
+    }
+
+    ///
+    /// Delete log files which are not listed in the MANIFEST, along with any
+    /// MANIFEST.new left behind. These are outputs of a compaction or a new
+    /// tail file which were never published, or inputs of a compaction which
+    /// were published away but not yet deleted, when the log was last closed
+    ///
+    fn remove_unlisted_files(
+        logger: &Option<Logger>,
+        fs: &dyn FileSystem,
+        path: &Path,
+        log_files: &BTreeMap<u64, LogFile>,
+    ) -> Result<()> {
+        let listed: HashSet<u16> = log_files
+            .values()
+            .map(|log_file| log_file.manifest_record.file_number)
+            .collect();
+
+        for name in fs.read_dir(path)? {
+            let unlisted = match name.strip_suffix(".log").map(str::parse::<u16>) {
+                Some(Ok(file_number)) => !listed.contains(&file_number),
+                _ => name == "MANIFEST.new",
+            };
+
+            if unlisted {
+                if let Some(logger) = logger {
+                    info!(logger, "Removing unlisted file"; "file_name" => &name);
+                }
+                fs.remove_file(&path.join(&name))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Spawn the background thread which syncs the tail file every interval
+    /// whenever writes have been made since the last sync. The thread exits,
+    /// after a final sync, once the returned sender is dropped
+    ///
+    fn start_flusher(
+        log_files: Arc<Mutex<BTreeMap<u64, LogFile>>>,
+        unsynced_bytes: Arc<AtomicU64>,
+        interval: Duration,
+    ) -> Result<(Sender<()>, JoinHandle<()>)> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let handle = std::thread::Builder::new()
+            .name("log-flusher".to_string())
+            .spawn(move || loop {
+                let stopping = matches!(
+                    receiver.recv_timeout(interval),
+                    Err(RecvTimeoutError::Disconnected)
+                );
+
+                // Claim the bytes counted so far before syncing, so any
+                // written while the sync runs count towards the next
+                let claimed = unsynced_bytes.swap(0, Ordering::SeqCst);
+                if claimed > 0 {
+                    // Sync outside the files lock, so reads carry on meanwhile
+                    let tail = log_files
+                        .lock()
+                        .unwrap()
+                        .last_key_value()
+                        .map(|(_, tail_file)| tail_file.file.clone());
+                    if !tail.is_some_and(|file| file.sync_data().is_ok()) {
+                        unsynced_bytes.fetch_add(claimed, Ordering::SeqCst);
+                    }
+                }
+
+                if stopping {
+                    return;
+                }
+            })?;
+        Ok((sender, handle))
     }
 
     ///
@@ -366,14 +1003,65 @@ impl Log {
         if let Some(ref logger) = self.logger {
             info!(logger, "Reading log"; "index" => index);
         }
-        let mut log_files = self.log_files.lock().unwrap();
-        match log_files
-            .upper_bound_mut(std::ops::Bound::Included(&index))
-            .value_mut()
-        {
-            Some(entry) => Ok(entry.read(index)?),
-            None => Err(Error::other("Failed to find file for index")),
+        // Only finding the record needs the files, so reads of different
+        // records, and writes, carry on alongside the read itself
+        let location = match self.log_files.lock().unwrap().range(..=index).next_back() {
+            Some((_, entry)) => entry.locate(index)?,
+            None => return Err(Error::other("Failed to find file for index")),
+        };
+        location.read()
+    }
+
+    pub(crate) fn read_only(&self) -> bool {
+        self.lock.is_none()
+    }
+
+    ///
+    /// Fail with ErrorKind::ReadOnlyFilesystem if the log was opened read
+    /// only, or with ErrorKind::StorageFull if a recent write ran out of
+    /// space. Once DISK_FULL_RETRY_INTERVAL has passed since that write,
+    /// writes are let through again to find out if space has been freed
+    ///
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.read_only() {
+            return Err(Error::new(
+                ErrorKind::ReadOnlyFilesystem,
+                "store read-only: opened in read-only mode",
+            ));
         }
+        if let Some(failed_at) = *self.disk_full.lock().unwrap() {
+            if failed_at.elapsed() < DISK_FULL_RETRY_INTERVAL {
+                return Err(disk_full_error());
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Record a failed write, returning the error to report for it. Running
+    /// out of space puts the log into a degraded, read only state until a
+    /// later write succeeds
+    ///
+    fn write_failed(&self, err: Error) -> Error {
+        if !matches!(err.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded) {
+            return err;
+        }
+
+        let mut disk_full = self.disk_full.lock().unwrap();
+        if disk_full.is_none() {
+            if let Some(ref logger) = self.logger {
+                error!(logger, "Out of space, rejecting writes"; "error" => err.to_string());
+            }
+        }
+        *disk_full = Some(Instant::now());
+        disk_full_error()
+    }
+
+    ///
+    /// True while writes are being rejected because the disk is full
+    ///
+    pub(crate) fn disk_full(&self) -> bool {
+        self.disk_full.lock().unwrap().is_some()
     }
 
     ///
@@ -381,7 +1069,20 @@ impl Log {
     /// which the record was written
     ///
     pub(crate) fn write(&self, operation: LogOperation) -> Result<u64> {
-        if let Some(mut entry) = self.log_files.lock().unwrap().last_entry() {
+        self.check_writable()?;
+        let _writing = self.writing.lock().unwrap();
+
+        // Append under the files lock, but sync through a clone of the
+        // handle once it is released, so reads are not held up by the sync
+        let (tail_key, file_number, file, valid_len, last_index, written) = {
+            let mut log_files = self.log_files.lock().unwrap();
+            let Some(mut entry) = log_files.last_entry() else {
+                if let Some(ref logger) = self.logger {
+                    info!(logger, "Missing tail file");
+                }
+                return Err(ErrorKind::InvalidData.into());
+            };
+            let tail_key = *entry.key();
             let tail_file = entry.get_mut();
 
             let record = LogRecord {
@@ -396,24 +1097,138 @@ impl Log {
             }
 
             let last_index = record.index;
-            tail_file.write(record)?;
+            let valid_len = tail_file.valid_len;
+            let written = tail_file
+                .write(record)
+                .map_err(|err| self.write_failed(err))?;
+            let file_number = tail_file.manifest_record.file_number;
+            (tail_key, file_number, tail_file.file.clone(), valid_len, last_index, written)
+        };
 
-            // Force data to disk for durability prior to returning back to the caller
-            tail_file.file.sync_data()?;
+        // Force data to disk as required by the durability policy prior
+        // to returning back to the caller. If the sync fails the record
+        // is rolled back. Under Durability::Sync it is the only unsynced
+        // data, while under Durability::Periodic the writes already
+        // acknowledged stay behind for the next sync
+        let synced = match self.durability {
+            Durability::Sync => file.sync_data(),
+            Durability::Periodic { bytes, .. } => {
+                if self.unsynced_bytes.fetch_add(written, Ordering::SeqCst) + written >= bytes {
+                    // Claimed as the flusher does. If the sync fails, the
+                    // bytes claimed are given back less this record's own,
+                    // which is rolled back
+                    let claimed = self.unsynced_bytes.swap(0, Ordering::SeqCst);
+                    file.sync_data().inspect_err(|_| {
+                        self.unsynced_bytes
+                            .fetch_add(claimed.saturating_sub(written), Ordering::SeqCst);
+                    })
+                } else {
+                    Ok(())
+                }
+            }
+            Durability::Buffered => Ok(()),
+        };
 
+        let mut log_files = self.log_files.lock().unwrap();
+        if let Err(err) = synced {
+            // Only writes seal the tail, so it is still the file written to
+            if let Some(tail_file) = log_files.get_mut(&tail_key) {
+                tail_file.rollback(valid_len, last_index);
+            }
+            return Err(self.write_failed(err));
+        }
+        self.writes.fetch_add(1, Ordering::SeqCst);
+
+        if self.disk_full.lock().unwrap().take().is_some() {
             if let Some(ref logger) = self.logger {
-                info!(logger, "Wrote record"; "index" => last_index, "file_number" => tail_file.manifest_record.file_number);
+                info!(logger, "Space available again, accepting writes");
             }
+        }
 
-            Ok(last_index)
-        } else {
+        if let Some(ref logger) = self.logger {
+            info!(logger, "Wrote record"; "index" => last_index, "file_number" => file_number);
+        }
+
+        // The record is already durable as required, so a failure to
+        // seal only means writes stay in the current tail file, and
+        // sealing is retried on the next write
+        if file.size()? >= MAX_FILE_SIZE {
+            if let Err(err) = self.seal_tail(&mut log_files) {
+                if let Some(ref logger) = self.logger {
+                    warn!(logger, "Failed to seal tail file"; "error" => err.to_string());
+                }
+            }
+        }
+
+        Ok(last_index)
+    }
+
+    ///
+    /// Index the next record written will be given. Every record written
+    /// so far has a lower index
+    ///
+    pub(crate) fn next_index(&self) -> u64 {
+        self.next_index.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Size and live bytes of every log file, in index order. The records
+    /// still referenced by the key directory are passed in live
+    ///
+    pub(crate) fn file_stats(&self, live: &LiveRecords) -> Result<Vec<LogFileStats>> {
+        let log_files = self.log_files.lock().unwrap();
+        log_files
+            .values()
+            .map(|log_file| {
+                Ok(LogFileStats {
+                    file_number: log_file.manifest_record.file_number,
+                    size: log_file.size()?,
+                    live_bytes: log_file.live_bytes(live)?,
+                })
+            })
+            .collect()
+    }
+
+    ///
+    /// Serve reads of sealed files from memory maps rather than reading
+    /// through their handles, or go back to reading through the handles.
+    /// The tail file is always read through its handle, since it is still
+    /// being appended to. A log opened read only never maps its files, since
+    /// without the directory lock another process may truncate them
+    ///
+    pub(crate) fn set_mmap_reads(&self, enabled: bool) {
+        if enabled && self.read_only() {
             if let Some(ref logger) = self.logger {
-                info!(logger, "Missing tail file");
+                warn!(logger, "Not mapping log files of a log opened read only");
+            }
+            return;
+        }
+
+        let mut log_files = self.log_files.lock().unwrap();
+        self.mmap_reads.store(enabled, Ordering::SeqCst);
+
+        let sealed = log_files.len().saturating_sub(1);
+        for log_file in log_files.values_mut().take(sealed) {
+            if enabled {
+                log_file.map();
+            } else {
+                log_file.unmap();
             }
-            Err(ErrorKind::InvalidData.into())
         }
     }
 
+    pub(crate) fn writes(&self) -> u64 {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn last_compaction(&self) -> Option<SystemTime> {
+        *self.last_compaction.lock().unwrap()
+    }
+
+    pub(crate) fn compaction_progress(&self) -> CompactionProgress {
+        *self.compaction_progress.lock().unwrap()
+    }
+
     pub(crate) fn total_size(&self) -> Result<u64> {
         let log_files = self.log_files.lock().unwrap();
         log_files
@@ -431,16 +1246,22 @@ impl Log {
     /// sorted by max_index. This will read from the MANIFEST file in the
     /// target directory
     ///
-    fn read_manifest(logger: &Option<Logger>, path: PathBuf) -> Result<Vec<FileManifestRecord>> {
+    fn read_manifest(
+        logger: &Option<Logger>,
+        fs: &dyn FileSystem,
+        path: PathBuf,
+    ) -> Result<Vec<FileManifestRecord>> {
         let manifest_file_path = path.join("MANIFEST");
 
         if let Some(logger) = logger {
             info!(logger, "Opening manifest"; "path" => manifest_file_path.to_str());
         }
 
-        match File::open(&manifest_file_path) {
-            Ok(mut file) => {
-                let header: FileManifestHeader = bincode::deserialize_from(&mut file).unwrap();
+        match fs.open_read_only(&manifest_file_path) {
+            Ok(file) => {
+                let mut file = BufReader::new(HandleReader::new(file, 0));
+                let header: FileManifestHeader = bincode::deserialize_from(&mut file)
+                    .map_err(|e| Error::other(e.to_string()))?;
 
                 if let Some(logger) = logger {
                     info!(logger, "Manifest file opened"; "entries" => header.entry_count);
@@ -476,8 +1297,9 @@ impl Log {
     /// to a MANIFEST.new file, and then later does an atomic rename to ensure
     /// a consistent view of the file is persisted
     ///
-    fn write_manifest(
+    pub(crate) fn write_manifest(
         logger: &Option<Logger>,
+        fs: &dyn FileSystem,
         mut records: Vec<FileManifestRecord>,
         path: &PathBuf,
     ) -> Result<()> {
@@ -492,39 +1314,41 @@ impl Log {
             info!(logger, "Writing new MANIFEST"; "file_name" => new_manifest_file_path.to_str());
         }
 
-        let w = File::create(&new_manifest_file_path)?;
+        let w = fs.create(&new_manifest_file_path)?;
 
         let header = FileManifestHeader {
             entry_count: records.len() as u16,
-            magic_number: 0xDEAD_BEEF,
+            magic_number: MANIFEST_MAGIC_NUMBER,
         };
 
         if let Some(ref logger) = logger {
             info!(logger, "Writing header into MANIFEST"; "file_name" => new_manifest_file_path.to_str());
         }
 
-        bincode::serialize_into(&w, &header).unwrap();
+        let mut buf = bincode::serialize(&header).unwrap();
 
         for record in records {
             if let Some(ref logger) = logger {
                 info!(logger, "Writing record into MANIFEST"; "file_name" => new_manifest_file_path.to_str());
             }
 
-            bincode::serialize_into(&w, &record).unwrap();
+            buf.extend(bincode::serialize(&record).unwrap());
         }
+        w.append(&buf)?;
 
         if let Some(ref logger) = logger {
             info!(logger, "Syncing manifest file"; "file_name" => new_manifest_file_path.to_str());
         }
 
-        w.sync_all()?;
+        w.sync_data()?;
 
         if let Some(ref logger) = logger {
             info!(logger, "Finished syncing file"; "file_name" => new_manifest_file_path.to_str());
             info!(logger, "Renaming file"; "source_file" => "MANIFEST.new", "destination_file" => "MANIFEST");
         }
 
-        std::fs::rename(path.join("MANIFEST.new"), path.join("MANIFEST"))?;
+        fs.rename(&path.join("MANIFEST.new"), &path.join("MANIFEST"))?;
+        fs.sync_dir(path)?;
 
         if let Some(ref logger) = logger {
             info!(logger, "Renamed file"; "source_file" => "MANIFEST.new", "destination_file" => "MANIFEST");
@@ -533,74 +1357,259 @@ impl Log {
         Ok(())
     }
 
-    pub(crate) fn iter(&mut self) -> Result<LogIterator> {
-        Ok(LogIterator::new(self)?)
+    ///
+    /// Sync the tail file and start a new, empty tail file after it. The
+    /// new file is created before the MANIFEST listing it is published, so a
+    /// crash in between leaves an unlisted file which is removed on open.
+    /// Writes only move to the new file once the MANIFEST is published
+    ///
+    fn seal_tail(&self, log_files: &mut BTreeMap<u64, LogFile>) -> Result<()> {
+        if let Some((_, tail_file)) = log_files.last_key_value() {
+            tail_file.file.sync_data()?;
+            self.unsynced_bytes.store(0, Ordering::SeqCst);
+        }
+
+        let next_index = self.next_index.load(Ordering::SeqCst);
+        let tail_file = LogFile::create(
+            &self.logger,
+            self.fs.clone(),
+            self.path.clone(),
+            FileManifestRecord {
+                file_number: self.allocate_file_number(log_files),
+                max_index: next_index,
+                min_index: next_index,
+            },
+        )?;
+
+        let published = self.fs.sync_dir(&self.path).and_then(|_| {
+            Self::write_manifest(
+                &self.logger,
+                self.fs.as_ref(),
+                log_files
+                    .values()
+                    .map(|log_file| log_file.manifest_record)
+                    .chain(std::iter::once(tail_file.manifest_record))
+                    .collect(),
+                &self.path,
+            )
+        });
+        if let Err(err) = published {
+            let _ = tail_file.remove();
+            return Err(err);
+        }
+
+        if let Some(ref logger) = self.logger {
+            info!(logger, "Sealed tail file"; "file_number" => tail_file.manifest_record.file_number, "min_index" => next_index);
+        }
+
+        log_files.insert(next_index, tail_file);
+        self.sealed_since_compaction.fetch_add(1, Ordering::SeqCst);
+
+        if self.mmap_reads.load(Ordering::SeqCst) {
+            if let Some((_, sealed_file)) = log_files.range_mut(..next_index).next_back() {
+                sealed_file.map();
+            }
+        }
+        Ok(())
     }
 
     ///
-    /// Issue a compaction against the log to eliminate old records. The
-    /// predicate passed should return true for records which should be
-    /// retained, and false for records which should be dropped from the log.
+    /// Pick a number for a new log file which is not used by any file in
+    /// log_files. Numbers increase monotonically until they wrap, so files
+    /// created by a compaction in progress are not handed out again
     ///
-    pub(crate) fn compact_log<F: Fn(&LogRecord) -> bool>(&self, predicate: F) -> Result<()> {
-        if let Some(ref logger) = self.logger {
-            info!(logger, "Starting compaction");
+    fn allocate_file_number(&self, log_files: &BTreeMap<u64, LogFile>) -> u16 {
+        loop {
+            let file_number = self.next_file_number.fetch_add(1, Ordering::SeqCst);
+            if !log_files
+                .values()
+                .any(|log_file| log_file.manifest_record.file_number == file_number)
+            {
+                return file_number;
+            }
         }
+    }
 
-        let mut log_files = self.log_files.lock().unwrap();
+    ///
+    /// True once enough files have been sealed since the last compaction for
+    /// another one to be worth considering. Resets the count, so concurrent
+    /// callers do not all see true for the same batch of files
+    ///
+    pub(crate) fn compaction_due(&self) -> bool {
+        self.sealed_since_compaction.load(Ordering::SeqCst) >= COMPACTION_TRIGGER_FILES
+            && self.sealed_since_compaction.swap(0, Ordering::SeqCst) >= COMPACTION_TRIGGER_FILES
+    }
 
-        for log_file in log_files.values_mut() {
-            log_file.compact(&predicate)?;
+    ///
+    /// Merge every sealed file into new files holding only the records in
+    /// live, a snapshot of those referenced by the key directory. The inputs
+    /// are chosen after the snapshot is taken, so they may include files
+    /// sealed since, whose records lie above its watermark and are kept.
+    /// Tombstones are dropped, since every older record they could shadow is
+    /// also an input. Writes carry on into the tail file meanwhile.
+    ///
+    /// Records keep their index, so the key directory remains valid and only
+    /// the index -> file mapping changes. The outputs are synced, then a new
+    /// MANIFEST listing them in place of the inputs is atomically renamed
+    /// into place, and only then are the inputs deleted. A crash before the
+    /// rename leaves the old MANIFEST and the inputs intact, and a crash after
+    /// it leaves the outputs published. Either way the unlisted files are
+    /// removed on the next open.
+    ///
+    /// Reads and writes are paced to the control's rate limit, or run flat
+    /// out while it is 0. It is re-read between chunks, so it can be changed
+    /// while the compaction runs. Cancelling the control stops the
+    /// compaction before it publishes anything
+    ///
+    pub(crate) fn compact_log(&self, live: &LiveRecords, control: &CompactionControl) -> Result<()> {
+        self.check_writable()?;
+        let _compacting = self.compacting.lock().unwrap();
+        control.check_cancelled()?;
+        self.sealed_since_compaction.store(0, Ordering::SeqCst);
+
+        // Every file but the tail is sealed and will never be written again
+        let inputs: Vec<LogFile> = {
+            let log_files = self.log_files.lock().unwrap();
+            let sealed = log_files.len().saturating_sub(1);
+            log_files.values().take(sealed).cloned().collect()
+        };
+
+        if inputs.is_empty() {
+            return Ok(());
         }
 
         if let Some(ref logger) = self.logger {
-            info!(logger, "Finished compaction");
+            info!(logger, "Starting compaction"; "input_files" => inputs.len());
         }
 
-        // Once compaction has completed, write out the updated manifest with any updates
-        Self::write_manifest(
-            &self.logger,
-            log_files
+        *self.compaction_progress.lock().unwrap() = CompactionProgress {
+            running: true,
+            files_total: inputs.len() as u64,
+            ..CompactionProgress::default()
+        };
+
+        let mut outputs = Vec::new();
+        let written = self.write_compaction_outputs(&inputs, live, control, &mut outputs);
+        self.compaction_progress.lock().unwrap().running = false;
+        if let Err(err) = written {
+            for output in &outputs {
+                let _ = output.remove();
+            }
+            return Err(err);
+        }
+        self.fs.sync_dir(&self.path)?;
+
+        let output_count = outputs.len();
+        {
+            let mut log_files = self.log_files.lock().unwrap();
+
+            // Publish the MANIFEST before touching the in-memory mapping, so
+            // a failure here leaves the log exactly as it was
+            let mut records: Vec<FileManifestRecord> = log_files
                 .iter()
+                .filter(|(min_index, _)| {
+                    !inputs
+                        .iter()
+                        .any(|input| input.manifest_record.min_index == **min_index)
+                })
                 .map(|(_, log_file)| log_file.manifest_record)
-                .collect(),
-            &self.path,
-        )
+                .collect();
+            records.extend(outputs.iter().map(|output| output.manifest_record));
+            Self::write_manifest(&self.logger, self.fs.as_ref(), records, &self.path)?;
+
+            for input in &inputs {
+                log_files.remove(&input.manifest_record.min_index);
+            }
+            for mut output in outputs {
+                if self.mmap_reads.load(Ordering::SeqCst) {
+                    output.map();
+                }
+                log_files.insert(output.manifest_record.min_index, output);
+            }
+        }
+
+        for input in &inputs {
+            input.remove()?;
+        }
+
+        if let Some(ref logger) = self.logger {
+            info!(logger, "Finished compaction"; "input_files" => inputs.len(), "output_files" => output_count);
+        }
+
+        *self.last_compaction.lock().unwrap() = Some(SystemTime::now());
+        Ok(())
     }
 
     ///
-    /// Close the final file, flushing the manifest
+    /// Stream the live records of inputs, in index order, into newly created
+    /// files which are pushed onto outputs as they are started. Each output
+    /// is synced once full
     ///
-    fn seal_last_file(&mut self) -> Result<()> {
-        let mut log_files = self.log_files.lock().unwrap();
+    fn write_compaction_outputs(
+        &self,
+        inputs: &[LogFile],
+        live: &LiveRecords,
+        control: &CompactionControl,
+        outputs: &mut Vec<LogFile>,
+    ) -> Result<()> {
+        let mut throttle = Throttle {
+            control,
+            chunk_start: Instant::now(),
+            chunk_bytes: 0,
+        };
+        let mut progress = CompactionProgress {
+            running: true,
+            files_total: inputs.len() as u64,
+            ..CompactionProgress::default()
+        };
 
-        if let Some(mut entry) = log_files.last_entry() {
-            let log_file = entry.get_mut();
-            log_file.manifest_record.max_index =
-                self.next_index.load(std::sync::atomic::Ordering::SeqCst) - 1;
-
-            let mut last_record = log_file.manifest_record;
-            last_record.file_number += 1;
-            last_record.min_index = last_record.max_index + 1;
-            last_record.max_index = u64::MAX;
-            let first_index = last_record.min_index;
-
-            log_files.insert(
-                first_index,
-                LogFile::create(&self.logger, self.path.clone(), last_record)?,
-            );
-
-            // Flush the manifest so the log files are picked up on a reload
-            // after this point, including the updated max_index for the
-            // previous last file, and the newly added tail file
-            Self::write_manifest(
-                &self.logger,
-                log_files
-                    .iter()
-                    .map(|(_, log_file)| log_file.manifest_record)
-                    .collect(),
-                &self.path,
-            )?;
+        for input in inputs {
+            let mut scanner = RecordScanner::from_handle(input.file.clone())?;
+
+            while let Some(entry) = scanner.next() {
+                let (offset, record) = entry?;
+                let mut io_bytes = scanner.valid_len() - offset;
+                progress.bytes_read += io_bytes;
+
+                if live.contains(record.index) {
+                    let output_full = match outputs.last() {
+                        Some(output) => output.size()? >= MAX_FILE_SIZE,
+                        None => true,
+                    };
+                    if output_full {
+                        if let Some(output) = outputs.last() {
+                            output.file.sync_data()?;
+                        }
+                        let file_number =
+                            self.allocate_file_number(&self.log_files.lock().unwrap());
+                        outputs.push(LogFile::create(
+                            &self.logger,
+                            self.fs.clone(),
+                            self.path.clone(),
+                            FileManifestRecord {
+                                file_number,
+                                max_index: record.index,
+                                min_index: record.index,
+                            },
+                        )?);
+                    }
+
+                    let written = outputs.last_mut().unwrap().write(record)?;
+                    progress.bytes_written += written;
+                    io_bytes += written;
+                }
+
+                if throttle.consume(io_bytes)? {
+                    *self.compaction_progress.lock().unwrap() = progress;
+                }
+            }
+
+            progress.files_done += 1;
+            *self.compaction_progress.lock().unwrap() = progress;
+        }
+
+        if let Some(output) = outputs.last() {
+            output.file.sync_data()?;
         }
         Ok(())
     }
@@ -608,8 +1617,22 @@ impl Log {
 
 impl Drop for Log {
     fn drop(&mut self) {
+        if self.read_only() {
+            return;
+        }
+
+        if let Some((sender, handle)) = self.flusher.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+
         let log_files = self.log_files.lock().unwrap();
 
+        // Whatever the durability policy, a clean shutdown loses nothing
+        if let Some((_, tail_file)) = log_files.last_key_value() {
+            let _ = tail_file.file.sync_data();
+        }
+
         // Write manifest out as best effort, recovery process on startup can
         // properly scan a final file which was not sealed with a final
         // version number on shutdown.
@@ -617,60 +1640,6 @@ impl Drop for Log {
             .iter()
             .map(|(_, log_file)| log_file.manifest_record)
             .collect();
-        let _ = Self::write_manifest(&self.logger, records, &self.path);
-    }
-}
-
-///
-/// Iterator over all records in the log, starting at the first record.
-/// Provides sequential access to all records in the log. Version numbers
-/// will be strictly increasing for each log record returned, though gaps
-/// are likely to occur due to compaction on the log
-///
-pub(crate) struct LogIterator {
-    file_iterator: std::collections::btree_map::IntoIter<u64, LogFile>,
-    record_iterator: FileIterator,
-}
-
-impl LogIterator {
-    fn new(log: &mut Log) -> Result<LogIterator> {
-        let mut file_map = log
-            .log_files
-            .lock()
-            .map_err(|e| Error::other(e.to_string()))?
-            .clone();
-        let record_iterator = FileIterator::new(file_map.first_entry().unwrap().get())?;
-
-        Ok(LogIterator {
-            record_iterator,
-            file_iterator: file_map.into_iter(),
-        })
-    }
-}
-
-impl Iterator for LogIterator {
-    type Item = Result<LogRecord>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // Move to next file (or stop iteration)
-        match self.record_iterator.next() {
-            Some(value) => Some(value.map(|e| e.0)),
-            None => {
-                // Move to next file in the log
-                // TODO address a bug here with missing values on log file transition
-                match self.file_iterator.next() {
-                    Some((_, mut log_file)) => match FileIterator::new(&mut log_file) {
-                        Ok(record_iterator) => {
-                            self.record_iterator = record_iterator;
-                            self.record_iterator
-                                .next()
-                                .map(|v| v.map(|(log_record, _)| log_record))
-                        }
-                        Err(err) => Some(Err(err)),
-                    },
-                    None => None,
-                }
-            }
-        }
+        let _ = Self::write_manifest(&self.logger, self.fs.as_ref(), records, &self.path);
     }
 }
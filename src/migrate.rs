@@ -0,0 +1,206 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::engines::KvsEngine;
+
+///
+/// Number of keys copied between each checkpoint and progress report
+///
+const CHECKPOINT_INTERVAL: u64 = 256;
+
+///
+/// Snapshot of how far a migration has got, handed to the progress callback
+///
+pub struct MigrateProgress {
+    // Keys of the source's listing dealt with so far, including those
+    // skipped because an earlier interrupted run already copied them
+    pub done: u64,
+    pub total: u64,
+}
+
+pub struct MigrateReport {
+    // Keys written to the destination by this run. Keys removed from the
+    // source after it was listed are not written, so are not counted
+    pub copied: u64,
+
+    // Keys skipped because the checkpoint showed they were already copied
+    pub skipped: u64,
+    pub verified: u64,
+}
+
+///
+/// Where a migration keeps its checkpoint, and the migration it belongs to.
+/// A checkpoint left by a migration from another source, or into another
+/// engine, is ignored rather than resumed from
+///
+pub struct MigrateCheckpoint {
+    pub path: PathBuf,
+    pub source: PathBuf,
+    pub destination_engine: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointRecord {
+    source: PathBuf,
+    destination_engine: String,
+    last_key: String,
+}
+
+impl MigrateCheckpoint {
+    ///
+    /// Last key copied by an earlier run of this migration, if any. A
+    /// checkpoint which cannot be read as one of this migration's is treated
+    /// as missing, so every key is copied again
+    ///
+    fn read(&self) -> Result<Option<String>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(bincode::deserialize_from::<_, CheckpointRecord>(file)
+            .ok()
+            .filter(|record| record.source == self.source && record.destination_engine == self.destination_engine)
+            .map(|record| record.last_key))
+    }
+
+    ///
+    /// Persist the last key copied. Written to a temporary file and renamed
+    /// into place so an interrupted write never leaves a torn checkpoint
+    /// behind
+    ///
+    fn write(&self, last_key: &str) -> Result<()> {
+        let record = CheckpointRecord {
+            source: self.source.clone(),
+            destination_engine: self.destination_engine.clone(),
+            last_key: last_key.to_owned(),
+        };
+        let tmp = self.path.with_extension("tmp");
+        let file = File::create(&tmp)?;
+        bincode::serialize_into(&file, &record).map_err(|e| Error::other(e.to_string()))?;
+        file.sync_all()?;
+        std::fs::rename(tmp, &self.path)
+    }
+
+    fn remove(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+///
+/// Copy every live key from source into destination, then verify the
+/// destination holds exactly the same data. Keys are copied in ascending
+/// order and the last copied key is periodically saved to the checkpoint
+/// file, so a migration which is interrupted (or aborted by progress
+/// returning an error) resumes where it left off when run again. The
+/// checkpoint is removed once verification finishes, whether or not it
+/// succeeds, so a run which fails verification is followed by one which
+/// copies every key again. It should be kept outside the destination's
+/// directory, which is expected to hold only the store's own files
+///
+pub fn migrate<S, D, F>(
+    source: &S,
+    destination: &D,
+    checkpoint: &MigrateCheckpoint,
+    mut progress: F,
+) -> Result<MigrateReport>
+where
+    S: KvsEngine,
+    D: KvsEngine,
+    F: FnMut(&MigrateProgress) -> Result<()>,
+{
+    let keys = source.keys()?;
+    let resume_after = checkpoint.read()?;
+
+    let mut report = MigrateReport {
+        copied: 0,
+        skipped: 0,
+        verified: 0,
+    };
+    let mut processed: u64 = 0;
+
+    for key in &keys {
+        if resume_after.as_ref().is_some_and(|last_key| key <= last_key) {
+            report.skipped += 1;
+            continue;
+        }
+
+        // A key removed from the source since the listing was taken is
+        // simply not copied
+        if let Some(value) = source.get(key.clone())? {
+            destination.set(key.clone(), value)?;
+            report.copied += 1;
+        }
+        processed += 1;
+
+        if processed.is_multiple_of(CHECKPOINT_INTERVAL) {
+            checkpoint.write(key)?;
+            progress(&MigrateProgress {
+                done: processed + report.skipped,
+                total: keys.len() as u64,
+            })?;
+        }
+    }
+
+    if let Some(last_key) = keys.last() {
+        checkpoint.write(last_key)?;
+    }
+    progress(&MigrateProgress {
+        done: processed + report.skipped,
+        total: keys.len() as u64,
+    })?;
+
+    // Resuming from the checkpoint again could never repair a destination
+    // which fails verification, so it is removed either way
+    let verified = verify(source, destination, &keys);
+    checkpoint.remove()?;
+    report.verified = verified.map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!(
+                "{}, checkpoint {} removed so the next run copies every key",
+                err,
+                checkpoint.path.display()
+            ),
+        )
+    })?;
+    Ok(report)
+}
+
+///
+/// Check that destination holds exactly the live keys of source, with the
+/// same values. Returns the number of keys verified
+///
+fn verify<S: KvsEngine, D: KvsEngine>(source: &S, destination: &D, keys: &[String]) -> Result<u64> {
+    let mut verified = 0;
+    for key in keys {
+        let value = source.get(key.clone())?;
+        if value != destination.get(key.clone())? {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("verification failed, value for key {:?} differs", key),
+            ));
+        }
+        if value.is_some() {
+            verified += 1;
+        }
+    }
+
+    let destination_keys = destination.keys()?.len() as u64;
+    if destination_keys != verified {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "verification failed, destination holds {} keys but source holds {}",
+                destination_keys, verified
+            ),
+        ));
+    }
+    Ok(verified)
+}
@@ -1,6 +1,278 @@
 use std::fmt::Display;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::ops::Bound;
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::engines::EngineStats;
+use crate::limits::{limit_exceeded, LimitExceeded};
+
+///
+/// Bytes a client sends first on a framed connection. Read as the start of a
+/// bare Request, as sent by clients which predate framing, it would be an
+/// unknown variant, so the server can tell the two kinds of client apart
+///
+pub(crate) const PROTOCOL_MAGIC: [u8; 4] = *b"KVSP";
+
+///
+/// Newest protocol version spoken by this build. Version 2 adds a
+/// correlation ID to the header of every frame sent after the handshake
+///
+pub(crate) const PROTOCOL_VERSION: u16 = 2;
+
+///
+/// Oldest protocol version this build still speaks
+///
+pub(crate) const MIN_PROTOCOL_VERSION: u16 = 1;
+
+///
+/// Optional protocol features, negotiated as a bit set during the handshake.
+/// A request needing a feature which was not agreed is answered with an
+/// error frame
+///
+pub(crate) mod features {
+    ///
+    /// Stats and SetCompactionRate requests
+    ///
+    pub(crate) const ADMIN: u64 = 1 << 0;
+
+    ///
+    /// MultiGet, MultiSet and MultiRm requests
+    ///
+    pub(crate) const MULTI_KEY: u64 = 1 << 1;
+
+    ///
+    /// Scan requests
+    ///
+    pub(crate) const SCAN: u64 = 1 << 2;
+
+    ///
+    /// Every feature implemented by this build
+    ///
+    pub(crate) const SUPPORTED: u64 = ADMIN | MULTI_KEY | SCAN;
+}
+
+///
+/// Type of a frame, sent in its header ahead of the payload
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    // ClientHello from the client, or ServerHello in reply
+    Hello = 0,
+
+    Request = 1,
+
+    Response = 2,
+
+    // Exception sent in place of a response, or of a ServerHello when the
+    // handshake fails
+    Error = 3,
+}
+
+impl FrameKind {
+    fn from_u8(kind: u8) -> Option<FrameKind> {
+        match kind {
+            0 => Some(FrameKind::Hello),
+            1 => Some(FrameKind::Request),
+            2 => Some(FrameKind::Response),
+            3 => Some(FrameKind::Error),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Length delimited message on a framed connection. On the wire a frame is
+/// the payload length as a little endian u32, the kind as a single byte,
+/// from protocol version 2 the correlation ID as a little endian u64, then
+/// the bincode encoded payload. The length lets a frame which cannot be
+/// decoded be skipped without losing track of where the next one starts
+///
+pub(crate) struct Frame {
+    // None for a kind this build does not know
+    pub(crate) kind: Option<FrameKind>,
+
+    // Chosen by the client for each request and echoed back on the frame
+    // answering it. Always 0 on connections without correlation IDs
+    pub(crate) id: u64,
+
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Frame {
+    pub(crate) fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        bincode::deserialize(&self.payload).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+///
+/// Header of a frame whose payload was too large to read
+///
+pub(crate) struct OversizedFrame {
+    pub(crate) id: u64,
+    pub(crate) len: u64,
+}
+
+///
+/// Header layout used on a connection, which depends on the protocol version
+/// agreed in the handshake
+///
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Framing {
+    // Whether frame headers carry a correlation ID
+    pub(crate) ids: bool,
+}
+
+impl Framing {
+    ///
+    /// Layout of the hello frames, which are exchanged before a version has
+    /// been agreed
+    ///
+    pub(crate) const HANDSHAKE: Framing = Framing { ids: false };
+
+    pub(crate) fn for_version(version: u16) -> Framing {
+        Framing { ids: version >= 2 }
+    }
+
+    fn header_len(self) -> usize {
+        if self.ids {
+            13
+        } else {
+            5
+        }
+    }
+
+    ///
+    /// Payload length, kind and correlation ID held in a frame header
+    ///
+    fn parse_header(self, header: &[u8]) -> (u64, Option<FrameKind>, u64) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+        let id = match self.ids {
+            true => u64::from_le_bytes(header[5..13].try_into().unwrap()),
+            false => 0,
+        };
+        (len, FrameKind::from_u8(header[4]), id)
+    }
+
+    ///
+    /// Encode a whole frame, header and payload. The id is dropped on
+    /// connections without correlation IDs
+    ///
+    pub(crate) fn encode<T: Serialize>(self, kind: FrameKind, id: u64, payload: &T) -> Result<Vec<u8>> {
+        let payload = bincode::serialize(payload).map_err(|e| Error::other(e.to_string()))?;
+        let len = u32::try_from(payload.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "frame too large to send"))?;
+        let mut frame = Vec::with_capacity(self.header_len() + payload.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(kind as u8);
+        if self.ids {
+            frame.extend_from_slice(&id.to_le_bytes());
+        }
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    ///
+    /// Write a frame without flushing, so several can be sent together
+    ///
+    pub(crate) fn write<W: Write, T: Serialize>(
+        self,
+        writer: &mut W,
+        kind: FrameKind,
+        id: u64,
+        payload: &T,
+    ) -> Result<()> {
+        writer.write_all(&self.encode(kind, id, payload)?)
+    }
+
+    ///
+    /// Read the next frame. A payload longer than max_len is skipped over
+    /// without being read into memory, and reported as an OversizedFrame
+    ///
+    pub(crate) fn read<R: Read>(
+        self,
+        reader: &mut R,
+        max_len: u64,
+    ) -> Result<std::result::Result<Frame, OversizedFrame>> {
+        let mut header = [0u8; 13];
+        reader.read_exact(&mut header[..self.header_len()])?;
+        let (len, kind, id) = self.parse_header(&header);
+
+        if len > max_len {
+            std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+            return Ok(Err(OversizedFrame { id, len }));
+        }
+
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(Ok(Frame { kind, id, payload }))
+    }
+
+    ///
+    /// Same as read, for a connection served by the async server
+    ///
+    pub(crate) async fn read_async<R: AsyncRead + Unpin>(
+        self,
+        reader: &mut R,
+        max_len: u64,
+    ) -> Result<std::result::Result<Frame, OversizedFrame>> {
+        let mut header = [0u8; 13];
+        reader.read_exact(&mut header[..self.header_len()]).await?;
+        let (len, kind, id) = self.parse_header(&header);
+
+        if len > max_len {
+            tokio::io::copy(&mut reader.take(len), &mut tokio::io::sink()).await?;
+            return Ok(Err(OversizedFrame { id, len }));
+        }
+
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload).await?;
+        Ok(Ok(Frame { kind, id, payload }))
+    }
+}
+
+///
+/// First frame sent by a client, offering the range of protocol versions
+/// it speaks and the features it would like to use
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ClientHello {
+    pub(crate) min_version: u16,
+    pub(crate) max_version: u16,
+    pub(crate) features: u64,
+}
+
+///
+/// Server's reply to a ClientHello, with the protocol version and the
+/// features used for the rest of the connection
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ServerHello {
+    pub(crate) version: u16,
+    pub(crate) features: u64,
+}
+
+impl ClientHello {
+    ///
+    /// Agree on the newest version both sides speak and the features both
+    /// sides support
+    ///
+    pub(crate) fn negotiate(&self) -> std::result::Result<ServerHello, Exception> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(Exception::unsupported(format!(
+                "no common protocol version: client speaks {}-{}, server speaks {}-{}",
+                self.min_version, self.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+        Ok(ServerHello {
+            version,
+            features: self.features & features::SUPPORTED,
+        })
+    }
+}
 
 ///
 /// Message sent to server for each request
@@ -10,6 +282,12 @@ pub(crate) enum Request {
     Set(SetRequest),
     Get(GetRequest),
     Rm(RmRequest),
+    Stats(StatsRequest),
+    SetCompactionRate(SetCompactionRateRequest),
+    MultiGet(MultiGetRequest),
+    MultiSet(MultiSetRequest),
+    MultiRm(MultiRmRequest),
+    Scan(ScanRequest),
 }
 
 impl From<GetRequest> for Request {
@@ -30,6 +308,42 @@ impl From<RmRequest> for Request {
     }
 }
 
+impl From<StatsRequest> for Request {
+    fn from(value: StatsRequest) -> Self {
+        Request::Stats(value)
+    }
+}
+
+impl From<SetCompactionRateRequest> for Request {
+    fn from(value: SetCompactionRateRequest) -> Self {
+        Request::SetCompactionRate(value)
+    }
+}
+
+impl From<MultiGetRequest> for Request {
+    fn from(value: MultiGetRequest) -> Self {
+        Request::MultiGet(value)
+    }
+}
+
+impl From<MultiSetRequest> for Request {
+    fn from(value: MultiSetRequest) -> Self {
+        Request::MultiSet(value)
+    }
+}
+
+impl From<MultiRmRequest> for Request {
+    fn from(value: MultiRmRequest) -> Self {
+        Request::MultiRm(value)
+    }
+}
+
+impl From<ScanRequest> for Request {
+    fn from(value: ScanRequest) -> Self {
+        Request::Scan(value)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct SetRequest {
     pub(crate) key: String,
@@ -46,21 +360,215 @@ pub(crate) struct RmRequest {
     pub(crate) key: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StatsRequest {}
+
+///
+/// Admin request changing the compaction I/O rate limit of the server's
+/// engine. None lifts the limit
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SetCompactionRateRequest {
+    pub(crate) bytes_per_second: Option<u64>,
+}
+
+///
+/// Get several keys at once. Each key is answered separately, in the order
+/// of keys
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MultiGetRequest {
+    pub(crate) keys: Vec<String>,
+}
+
+///
+/// Set several keys at once, in the order of pairs. Each pair succeeds or
+/// fails separately
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MultiSetRequest {
+    pub(crate) pairs: Vec<(String, String)>,
+}
+
+///
+/// Remove several keys at once, in the order of keys. Each key succeeds or
+/// fails separately
+///
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct MultiRmRequest {
+    pub(crate) keys: Vec<String>,
+}
+
+///
+/// Fetch one page of the keys starting with prefix and falling within
+/// [start, end), in ascending order, along with their values. The first
+/// page is requested without a cursor, and each later page with the cursor
+/// returned by the page before it
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ScanRequest {
+    pub(crate) prefix: Option<String>,
+    pub(crate) start: Option<String>,
+    pub(crate) end: Option<String>,
+
+    // Most entries the page may hold. The server may return fewer
+    pub(crate) limit: u32,
+
+    // Opaque to the client, which only passes it back
+    pub(crate) cursor: Option<Vec<u8>>,
+}
+
+impl ScanRequest {
+    ///
+    /// Range of keys still to be scanned, combining the prefix, the start
+    /// and end keys and the position reached by the cursor
+    ///
+    pub(crate) fn range(&self) -> Result<(Bound<String>, Bound<String>)> {
+        let resume = match &self.cursor {
+            Some(cursor) => Some(
+                String::from_utf8(cursor.clone())
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid scan cursor"))?,
+            ),
+            None => None,
+        };
+
+        let lower = [
+            self.prefix.clone().map(Bound::Included),
+            self.start.clone().map(Bound::Included),
+            resume.map(Bound::Excluded),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(Bound::Unbounded, tighter_lower);
+
+        let upper = match (self.end.clone(), self.prefix.as_deref().map(prefix_end)) {
+            (Some(end), Some(Bound::Excluded(prefix_end))) => Bound::Excluded(end.min(prefix_end)),
+            (Some(end), _) => Bound::Excluded(end),
+            (None, Some(prefix_end)) => prefix_end,
+            (None, None) => Bound::Unbounded,
+        };
+        Ok((lower, upper))
+    }
+}
+
+///
+/// Whichever of two lower bounds admits fewer keys
+///
+fn tighter_lower(a: Bound<String>, b: Bound<String>) -> Bound<String> {
+    let b_tighter = match (&a, &b) {
+        (_, Bound::Unbounded) => false,
+        (Bound::Unbounded, _) => true,
+        (Bound::Included(a_key) | Bound::Excluded(a_key), Bound::Included(b_key) | Bound::Excluded(b_key)) => {
+            b_key > a_key || (b_key == a_key && matches!(b, Bound::Excluded(_)))
+        }
+    };
+    if b_tighter {
+        b
+    } else {
+        a
+    }
+}
+
+///
+/// Smallest key greater than every key starting with prefix, found by
+/// incrementing its last character. Keys order by their UTF-8 bytes, which
+/// is the same as ordering them by character
+///
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Bound::Excluded(chars.into_iter().collect());
+        }
+    }
+    Bound::Unbounded
+}
+
+impl Request {
+    ///
+    /// Features which must have been negotiated to send this request
+    ///
+    pub(crate) fn required_features(&self) -> u64 {
+        match self {
+            Request::Set(_) | Request::Get(_) | Request::Rm(_) => 0,
+            Request::Stats(_) | Request::SetCompactionRate(_) => features::ADMIN,
+            Request::MultiGet(_) | Request::MultiSet(_) | Request::MultiRm(_) => {
+                features::MULTI_KEY
+            }
+            Request::Scan(_) => features::SCAN,
+        }
+    }
+}
+
 impl Display for Request {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Request::Set(set) => f.write_fmt(format_args!("{:?}", set)),
             Request::Get(get) => f.write_fmt(format_args!("{:?}", get)),
             Request::Rm(rm) => f.write_fmt(format_args!("{:?}", rm)),
+            Request::Stats(stats) => f.write_fmt(format_args!("{:?}", stats)),
+            Request::SetCompactionRate(rate) => f.write_fmt(format_args!("{:?}", rate)),
+            Request::MultiGet(get) => f.write_fmt(format_args!("MultiGetRequest({} keys)", get.keys.len())),
+            Request::MultiSet(set) => f.write_fmt(format_args!("MultiSetRequest({} keys)", set.pairs.len())),
+            Request::MultiRm(rm) => f.write_fmt(format_args!("MultiRmRequest({} keys)", rm.keys.len())),
+            Request::Scan(scan) => f.write_fmt(format_args!("{:?}", scan)),
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExceptionKind {
+    Other,
+
+    // The request broke one of the server's configured Limits
+    LimitExceeded,
+
+    // The request type, frame type or protocol version is not supported by
+    // the server, or needs a feature which was not negotiated
+    Unsupported,
+}
+
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Exception {
+    pub(crate) kind: ExceptionKind,
     pub(crate) what: String,
 }
 
+impl Exception {
+    pub(crate) fn unsupported(what: String) -> Exception {
+        Exception {
+            kind: ExceptionKind::Unsupported,
+            what,
+        }
+    }
+}
+
+impl From<Error> for Exception {
+    fn from(err: Error) -> Self {
+        match err.get_ref().and_then(|inner| inner.downcast_ref::<LimitExceeded>()) {
+            Some(limit) => Exception {
+                kind: ExceptionKind::LimitExceeded,
+                what: limit.what.clone(),
+            },
+            None => Exception {
+                kind: ExceptionKind::Other,
+                what: err.to_string(),
+            },
+        }
+    }
+}
+
+impl From<Exception> for Error {
+    fn from(exception: Exception) -> Self {
+        match exception.kind {
+            ExceptionKind::LimitExceeded => limit_exceeded(exception.what),
+            ExceptionKind::Unsupported => Error::new(ErrorKind::Unsupported, exception.what),
+            ExceptionKind::Other => Error::other(exception.what),
+        }
+    }
+}
+
 ///
 /// Response received back from server. Could be an error
 ///
@@ -118,3 +626,182 @@ impl Display for RmResponse {
         }
     }
 }
+
+///
+/// Response received back from server. Could be an error
+///
+#[derive(Serialize, Deserialize)]
+pub(crate) enum StatsResponse {
+    Ok(EngineStats),
+    Error(Exception),
+}
+
+impl Display for StatsResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatsResponse::Ok(_) => f.write_fmt(format_args!("StatsResponse::Ok")),
+            StatsResponse::Error(err) => {
+                f.write_fmt(format_args!("StatsResponse::Error({})", err.what))
+            }
+        }
+    }
+}
+
+///
+/// Response received back from server. Could be an error
+///
+#[derive(Serialize, Deserialize)]
+pub(crate) enum SetCompactionRateResponse {
+    Ok(()),
+    Error(Exception),
+}
+
+impl Display for SetCompactionRateResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SetCompactionRateResponse::Ok(_) => {
+                f.write_fmt(format_args!("SetCompactionRateResponse::Ok"))
+            }
+            SetCompactionRateResponse::Error(err) => {
+                f.write_fmt(format_args!("SetCompactionRateResponse::Error({})", err.what))
+            }
+        }
+    }
+}
+
+///
+/// Response received back from server, holding the response for each key
+/// in the order requested. Could be an error
+///
+#[derive(Serialize, Deserialize)]
+pub(crate) enum MultiGetResponse {
+    Ok(Vec<GetResponse>),
+    Error(Exception),
+}
+
+impl Display for MultiGetResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultiGetResponse::Ok(resps) => {
+                f.write_fmt(format_args!("MultiGetResponse::Ok({} keys)", resps.len()))
+            }
+            MultiGetResponse::Error(err) => {
+                f.write_fmt(format_args!("MultiGetResponse::Error({})", err.what))
+            }
+        }
+    }
+}
+
+///
+/// Response received back from server, holding the response for each key
+/// in the order requested. Could be an error
+///
+#[derive(Serialize, Deserialize)]
+pub(crate) enum MultiSetResponse {
+    Ok(Vec<SetResponse>),
+    Error(Exception),
+}
+
+impl Display for MultiSetResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultiSetResponse::Ok(resps) => {
+                f.write_fmt(format_args!("MultiSetResponse::Ok({} keys)", resps.len()))
+            }
+            MultiSetResponse::Error(err) => {
+                f.write_fmt(format_args!("MultiSetResponse::Error({})", err.what))
+            }
+        }
+    }
+}
+
+///
+/// Response received back from server, holding the response for each key
+/// in the order requested. Could be an error
+///
+#[derive(Serialize, Deserialize)]
+pub(crate) enum MultiRmResponse {
+    Ok(Vec<RmResponse>),
+    Error(Exception),
+}
+
+impl Display for MultiRmResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultiRmResponse::Ok(resps) => {
+                f.write_fmt(format_args!("MultiRmResponse::Ok({} keys)", resps.len()))
+            }
+            MultiRmResponse::Error(err) => {
+                f.write_fmt(format_args!("MultiRmResponse::Error({})", err.what))
+            }
+        }
+    }
+}
+
+///
+/// Page of entries returned for a ScanRequest
+///
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ScanPage {
+    pub(crate) entries: Vec<(String, String)>,
+
+    // Passed back to fetch the next page, or None once the scan is complete
+    pub(crate) cursor: Option<Vec<u8>>,
+}
+
+///
+/// Response received back from server. Could be an error
+///
+#[derive(Serialize, Deserialize)]
+pub(crate) enum ScanResponse {
+    Ok(ScanPage),
+    Error(Exception),
+}
+
+impl Display for ScanResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanResponse::Ok(page) => {
+                f.write_fmt(format_args!("ScanResponse::Ok({} entries)", page.entries.len()))
+            }
+            ScanResponse::Error(err) => {
+                f.write_fmt(format_args!("ScanResponse::Error({})", err.what))
+            }
+        }
+    }
+}
+
+///
+/// Response to any request, as sent back by the server. Serializes as the
+/// response it wraps, which is what the client decodes for the request it
+/// sent
+///
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum Response {
+    Get(GetResponse),
+    Set(SetResponse),
+    Rm(RmResponse),
+    Stats(StatsResponse),
+    SetCompactionRate(SetCompactionRateResponse),
+    MultiGet(MultiGetResponse),
+    MultiSet(MultiSetResponse),
+    MultiRm(MultiRmResponse),
+    Scan(ScanResponse),
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Get(resp) => resp.fmt(f),
+            Response::Set(resp) => resp.fmt(f),
+            Response::Rm(resp) => resp.fmt(f),
+            Response::Stats(resp) => resp.fmt(f),
+            Response::SetCompactionRate(resp) => resp.fmt(f),
+            Response::MultiGet(resp) => resp.fmt(f),
+            Response::MultiSet(resp) => resp.fmt(f),
+            Response::MultiRm(resp) => resp.fmt(f),
+            Response::Scan(resp) => resp.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::future::Future;
+use std::io::{BufReader, BufWriter, Cursor, Error, ErrorKind, Read, Result};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use slog::{error, info};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::{is_timeout, unframed_over_tls, KvsServer, Reply, REPLY_QUEUE};
+use crate::engines::KvsEngine;
+use crate::limits::ConnectionLimits;
+use crate::net::{Exception, Framing, PROTOCOL_MAGIC};
+use crate::thread_pool::ThreadPool;
+
+impl<Engine: KvsEngine + Sync + Send, Pool: ThreadPool + Send + Sync + 'static> KvsServer<Engine, Pool> {
+    ///
+    /// Accept connections on a tokio runtime, serving each from a task
+    /// rather than a thread of its own
+    ///
+    pub(super) fn run_async(self: Arc<Self>, worker_threads: usize) -> Result<()> {
+        if worker_threads == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "async mode needs at least 1 worker thread"));
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .enable_io()
+            .enable_time()
+            .build()?;
+
+        runtime.block_on(async {
+            let listener = TcpListener::bind(&self.addr).await?;
+            self.shutdown.listening(listener.local_addr()?);
+
+            info!(self.logger, "Starting server"; "addr" => &self.addr, "mode" => "async", "worker_threads" => worker_threads);
+
+            while !self.shutdown.is_shutdown() {
+                let (connection, _) = listener.accept().await?;
+                if self.shutdown.is_shutdown() {
+                    break;
+                }
+
+                // Shutdown closes connections through a std handle on the
+                // same socket
+                let rejection = self.admit();
+                let rejected = rejection.is_some();
+                if rejected && !self.start_rejection() {
+                    continue;
+                }
+                let connection = connection.into_std()?;
+                let registration = self.shutdown.register(&connection)?;
+                let connection = TcpStream::from_std(connection)?;
+
+                let server = self.clone();
+                tokio::spawn(async move {
+                    let engine = server.engine.clone();
+                    if let Err(err) = server.clone().process_connection_async(engine, connection, rejection).await {
+                        error!(server.logger, "Error while processing connection"; "error" => err.to_string());
+                    }
+                    if rejected {
+                        server.rejecting.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    drop(server);
+                    drop(registration);
+                });
+            }
+            Ok::<(), Error>(())
+        })?;
+
+        // Connections are served by the runtime's threads while this one
+        // waits for them
+        self.drain_connections();
+        runtime.shutdown_timeout(self.shutdown_timeout);
+        Ok(())
+    }
+
+    async fn process_connection_async(
+        self: Arc<Self>,
+        engine: Engine,
+        mut connection: TcpStream,
+        rejection: Option<Exception>,
+    ) -> Result<()> {
+        let peer_addr = connection.peer_addr()?.to_string();
+
+        info!(self.logger, "Received connection"; "remote_addr" => &peer_addr);
+
+        let limits = self.limits_for(&rejection);
+        if let Some(tls) = &self.tls {
+            let connection = with_timeout(limits.read_timeout, tls.acceptor().accept(connection)).await?;
+            let (reader, writer) = tokio::io::split(connection);
+            let mut reader = tokio::io::BufReader::new(reader);
+            return match self.read_magic_async(&peer_addr, &mut reader, &limits).await? {
+                Some(PROTOCOL_MAGIC) => {
+                    let writer = tokio::io::BufWriter::new(writer);
+                    self.process_framed_async(engine, &peer_addr, reader, writer, rejection).await
+                }
+                Some(_) => Err(unframed_over_tls()),
+                None => Ok(()),
+            };
+        }
+
+        let Some(magic) = self.read_magic_async(&peer_addr, &mut connection, &limits).await? else {
+            return Ok(());
+        };
+        if magic == PROTOCOL_MAGIC {
+            let (reader, writer) = connection.into_split();
+            let (reader, writer) = (tokio::io::BufReader::new(reader), tokio::io::BufWriter::new(writer));
+            return self.process_framed_async(engine, &peer_addr, reader, writer, rejection).await;
+        }
+
+        // Clients which predate framing are rare, and their unframed
+        // requests can only be decoded from a blocking reader, so they are
+        // given a thread of their own
+        let connection = connection.into_std()?;
+        connection.set_nonblocking(false)?;
+        connection.set_write_timeout(limits.write_timeout)?;
+        tokio::task::spawn_blocking(move || {
+            let reader = Read::chain(Cursor::new(magic), BufReader::new(connection.try_clone()?));
+            let writer = BufWriter::new(connection.try_clone()?);
+            self.process_legacy(&engine, &peer_addr, &connection, reader, writer, rejection)
+        })
+        .await
+        .map_err(Error::other)?
+    }
+
+    ///
+    /// Read the magic number or start of the first legacy request, waiting
+    /// no longer than the idle timeout in limits for it. None if the client
+    /// left the connection idle for too long
+    ///
+    async fn read_magic_async(
+        &self,
+        peer_addr: &str,
+        reader: &mut (impl AsyncRead + Unpin),
+        limits: &ConnectionLimits,
+    ) -> Result<Option<[u8; 4]>> {
+        let mut magic = [0u8; 4];
+        match with_timeout(limits.idle_timeout, reader.read_exact(&mut magic)).await {
+            Ok(_) => Ok(Some(magic)),
+            Err(err) if is_timeout(&err) => {
+                info!(self.logger, "Closing idle connection"; "remote_addr" => peer_addr);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    ///
+    /// Serve a client speaking the framed protocol, as process_framed does
+    /// but from tasks. Each request is executed on the blocking pool before
+    /// the next is read, keeping requests on a connection in order
+    ///
+    async fn process_framed_async(
+        self: Arc<Self>,
+        engine: Engine,
+        peer_addr: &str,
+        mut reader: impl AsyncBufRead + Unpin,
+        mut writer: impl AsyncWrite + Unpin + Send + 'static,
+        rejection: Option<Exception>,
+    ) -> Result<()> {
+        let limits = self.limits_for(&rejection);
+        let hello = Framing::HANDSHAKE.read_async(&mut reader, self.limits.max_frame_size);
+        let hello = with_timeout(limits.read_timeout, hello).await?;
+        let negotiated = match rejection {
+            Some(exception) => Err(exception),
+            None => self.handshake(peer_addr, hello)?,
+        };
+        let handshake = self.encode_handshake(&negotiated)?;
+        with_timeout(limits.write_timeout, async {
+            writer.write_all(&handshake).await?;
+            writer.flush().await
+        })
+        .await?;
+        let Ok(negotiated) = negotiated else {
+            return Ok(());
+        };
+
+        let framing = Framing::for_version(negotiated.version);
+        let (replies, outbox) = mpsc::channel(REPLY_QUEUE);
+        let sender = tokio::spawn(self.clone().send_replies_async(peer_addr.to_owned(), framing, writer, outbox));
+        let received = self
+            .receive_requests_async(engine, peer_addr, framing, negotiated.features, reader, replies)
+            .await;
+        let sent = sender.await.map_err(Error::other)?;
+        received.and(sent)
+    }
+
+    async fn receive_requests_async(
+        self: Arc<Self>,
+        engine: Engine,
+        peer_addr: &str,
+        framing: Framing,
+        features: u64,
+        mut reader: impl AsyncBufRead + Unpin,
+        replies: Sender<(u64, Reply)>,
+    ) -> Result<()> {
+        let limits = self.connection_limits;
+        for served in 1.. {
+            // Wait for the start of the next request, then give the rest of
+            // it the read timeout, as await_request does
+            match with_timeout(limits.idle_timeout, async { Ok(reader.fill_buf().await?.is_empty()) }).await {
+                Ok(false) => {}
+                Ok(true) => return Ok(()),
+                Err(err) if is_timeout(&err) => {
+                    info!(self.logger, "Closing idle connection"; "remote_addr" => peer_addr);
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+            let frame = framing.read_async(&mut reader, self.limits.max_frame_size);
+            let frame = match with_timeout(limits.read_timeout, frame).await {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let (id, request) = self.parse_request(peer_addr, features, frame);
+            if let Some(exception) = self.request_limit(served) {
+                let _ = replies.send((id, Err(exception))).await;
+                return Ok(());
+            }
+            let reply = match request {
+                Ok(request) => {
+                    let (server, engine) = (self.clone(), engine.clone());
+                    let response = tokio::task::spawn_blocking(move || server.execute(&engine, request))
+                        .await
+                        .map_err(Error::other)?;
+                    Ok(response)
+                }
+                Err(exception) => Err(exception),
+            };
+
+            // The sender only stops early if writing to the client failed,
+            // and reports that error itself
+            if replies.send((id, reply)).await.is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_replies_async(
+        self: Arc<Self>,
+        peer_addr: String,
+        framing: Framing,
+        mut writer: impl AsyncWrite + Unpin,
+        mut outbox: Receiver<(u64, Reply)>,
+    ) -> Result<()> {
+        let timeout = self.connection_limits.write_timeout;
+        while let Some(first) = outbox.recv().await {
+            let mut next = Some(first);
+            while let Some((id, reply)) = next {
+                let frame = self.encode_reply(&peer_addr, framing, id, reply)?;
+                with_timeout(timeout, writer.write_all(&frame)).await?;
+                next = outbox.try_recv().ok();
+            }
+            with_timeout(timeout, writer.flush()).await?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Await io, failing with TimedOut if it takes longer than timeout
+///
+async fn with_timeout<T>(timeout: Option<Duration>, io: impl Future<Output = Result<T>>) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, io)
+            .await
+            .map_err(|_| Error::from(ErrorKind::TimedOut))?,
+        None => io.await,
+    }
+}
@@ -0,0 +1,786 @@
+use bincode::Options;
+use slog::{error, info, Logger};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::engines::KvsEngine;
+use crate::limits::{limit_exceeded, ConnectionLimits, Limits};
+use crate::thread_pool::naive_thread_pool::NaiveThreadPool;
+use crate::thread_pool::ThreadPool;
+use crate::tls::ServerTls;
+use crate::net::{
+    ClientHello, Exception, Frame, FrameKind, Framing, GetResponse, MultiGetResponse, MultiRmResponse,
+    MultiSetResponse, Request, Response, RmResponse, ScanPage, ScanRequest, ScanResponse,
+    OversizedFrame, ServerHello, SetCompactionRateResponse, SetResponse, StatsResponse,
+    PROTOCOL_MAGIC,
+};
+
+mod async_server;
+mod shutdown;
+
+pub use self::shutdown::ShutdownHandle;
+
+///
+/// How long to wait for more of an oversized request before giving up on it
+///
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+///
+/// How long a shutdown waits for connections to finish the requests they
+/// have already sent
+///
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// Connections over the connection limit which may be turned away with an
+/// error at once. Any more are closed without a reply, so a flood of them
+/// cannot take up a thread each
+///
+const MAX_REJECTIONS: usize = 64;
+
+///
+/// Most entries returned in one page of a scan, whatever the client asks for
+///
+const MAX_SCAN_PAGE: usize = 1000;
+
+///
+/// Key and value bytes after which a page of a scan is cut short, so a page
+/// of large values stays well within a frame
+///
+const SCAN_PAGE_BYTES: usize = 4 * 1024 * 1024;
+
+///
+/// Replies which may wait to be written to a client before reading its
+/// requests pauses, so a client which pipelines requests without reading
+/// the responses cannot make the server buffer them without bound
+///
+const REPLY_QUEUE: usize = 64;
+
+///
+/// Response to a request, or the error sent back in its place
+///
+type Reply = std::result::Result<Response, Exception>;
+
+///
+/// How a KvsServer serves its connections
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerMode {
+    ///
+    /// Serve each connection from a job on the server's thread pool. A
+    /// connection holds its pool thread until it closes, so the pool size
+    /// bounds how many connections are served at once
+    ///
+    Threaded,
+
+    ///
+    /// Multiplex every connection on a tokio runtime with worker_threads
+    /// threads, running engine calls on the runtime's blocking pool so they
+    /// never stall other connections
+    ///
+    Async { worker_threads: usize },
+}
+
+pub struct KvsServer<Engine: KvsEngine, Pool: ThreadPool = NaiveThreadPool> {
+    addr: String,
+    logger: Logger,
+
+    ///
+    /// Handle each connection clones its own engine from. Engines
+    /// synchronize internally, so connections run requests concurrently
+    ///
+    engine: Engine,
+    limits: Limits,
+    connection_limits: ConnectionLimits,
+    mode: ServerMode,
+    pool: Pool,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    tls: Option<ServerTls>,
+
+    // Connections currently being turned away with an error
+    rejecting: AtomicUsize,
+}
+
+impl<Engine: KvsEngine + Sync + Send> KvsServer<Engine> {
+    ///
+    /// Create a server which runs each connection on a thread of its own,
+    /// until given a pool with with_pool
+    ///
+    pub fn new(addr: String, logger: Logger, engine: Engine) -> KvsServer<Engine> {
+        KvsServer {
+            addr,
+            logger,
+            engine,
+            limits: Limits::default(),
+            connection_limits: ConnectionLimits::default(),
+            mode: ServerMode::Threaded,
+            pool: NaiveThreadPool,
+            shutdown: ShutdownHandle::new(),
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+            tls: None,
+            rejecting: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<Engine: KvsEngine + Sync + Send, Pool: ThreadPool + Send + Sync + 'static> KvsServer<Engine, Pool> {
+    ///
+    /// Reject requests, keys and values larger than limits with a
+    /// LimitExceeded error response
+    ///
+    pub fn with_limits(mut self, limits: Limits) -> KvsServer<Engine, Pool> {
+        self.limits = limits;
+        self
+    }
+
+    ///
+    /// Time out, cap and reap connections as set by limits
+    ///
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> KvsServer<Engine, Pool> {
+        self.connection_limits = limits;
+        self
+    }
+
+    ///
+    /// Serve every connection over TLS, refusing clients which do not
+    /// complete a handshake
+    ///
+    pub fn with_tls(mut self, tls: ServerTls) -> KvsServer<Engine, Pool> {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: ServerMode) -> KvsServer<Engine, Pool> {
+        self.mode = mode;
+        self
+    }
+
+    ///
+    /// Serve connections from jobs on pool in ServerMode::Threaded
+    ///
+    pub fn with_pool<P: ThreadPool>(self, pool: P) -> KvsServer<Engine, P> {
+        KvsServer {
+            addr: self.addr,
+            logger: self.logger,
+            engine: self.engine,
+            limits: self.limits,
+            connection_limits: self.connection_limits,
+            mode: self.mode,
+            pool,
+            shutdown: self.shutdown,
+            shutdown_timeout: self.shutdown_timeout,
+            tls: self.tls,
+            rejecting: self.rejecting,
+        }
+    }
+
+    ///
+    /// Wait up to timeout for connections to finish their requests once
+    /// shut down, before closing them outright
+    ///
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> KvsServer<Engine, Pool> {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    ///
+    /// Handle which stops this server, from any thread, once it is running
+    ///
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    ///
+    /// Main event processing loop for all operations on the server. Accepts
+    /// inbound connections and serves them in parallel, as chosen by the
+    /// server's mode, until stopped by a ShutdownHandle
+    ///
+    pub fn run(self) -> Result<()> {
+        let server = Arc::new(self);
+        let logger = server.logger.clone();
+        let result = match server.mode {
+            ServerMode::Threaded => server.clone().run_threaded(),
+            ServerMode::Async { worker_threads } => server.clone().run_async(worker_threads),
+        };
+
+        // Once every connection has finished this is the last handle on
+        // the server, and dropping it flushes the engine
+        if result.is_ok() && Arc::into_inner(server).is_none() {
+            error!(logger, "Connections outlived shutdown, engine not flushed");
+        }
+        result
+    }
+
+    ///
+    /// Hand each connection to the thread pool to process
+    ///
+    fn run_threaded(self: Arc<Self>) -> Result<()> {
+        let listener = TcpListener::bind(&self.addr)?;
+        self.shutdown.listening(listener.local_addr()?);
+
+        info!(self.logger, "Starting server"; "addr" => &self.addr, "mode" => "threaded");
+
+        while !self.shutdown.is_shutdown() {
+            let (connection, _) = listener.accept()?;
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let rejection = self.admit();
+            let rejected = rejection.is_some();
+            if rejected && !self.start_rejection() {
+                continue;
+            }
+            let registration = self.shutdown.register(&connection)?;
+            let server = self.clone();
+            let engine = self.engine.clone();
+            let job = move || {
+                // A job queued behind busy connections may only start once
+                // shutdown has closed its connection, which has sent nothing
+                // yet, so it is dropped without being served
+                if !server.shutdown.is_shutdown() {
+                    match server.process_connection(&engine, connection, rejection) {
+                        Ok(()) => {},
+                        Err(err) => error!(server.logger, "Error while processing connection"; "error" => err.to_string())
+                    }
+                }
+
+                if rejected {
+                    server.rejecting.fetch_sub(1, Ordering::SeqCst);
+                }
+
+                // Release the engine before the connection is seen to end
+                drop(engine);
+                drop(server);
+                drop(registration);
+            };
+
+            // Turned away from a thread of its own, as the pool may be
+            // taken up by the very connections which filled the server
+            if rejected {
+                std::thread::spawn(job);
+            } else {
+                self.pool.spawn(job);
+            }
+        }
+        drop(listener);
+        self.drain_connections();
+        Ok(())
+    }
+
+    ///
+    /// Error to turn a new connection away with, if it would take the server
+    /// over its maximum connection count. A connection turned away is still
+    /// read from for up to DRAIN_TIMEOUT until its first request, so the
+    /// error reaches the client
+    ///
+    fn admit(&self) -> Option<Exception> {
+        let max = self.connection_limits.max_connections?;
+        (self.shutdown.open_connections() >= max).then(|| {
+            Exception::from(limit_exceeded(format!(
+                "server already has the maximum of {} connections open",
+                max
+            )))
+        })
+    }
+
+    ///
+    /// Count a connection as being turned away, unless MAX_REJECTIONS
+    /// already are, in which case it should be closed without a reply.
+    /// The count is released once the connection is done with
+    ///
+    fn start_rejection(&self) -> bool {
+        if self.rejecting.fetch_add(1, Ordering::SeqCst) < MAX_REJECTIONS {
+            return true;
+        }
+        self.rejecting.fetch_sub(1, Ordering::SeqCst);
+        info!(self.logger, "Closing connection, too many already being turned away");
+        false
+    }
+
+    ///
+    /// Bounds on a connection given rejection. A connection being turned
+    /// away gets no longer than DRAIN_TIMEOUT to send its first request
+    /// and take the error, rather than the usual timeouts
+    ///
+    fn limits_for(&self, rejection: &Option<Exception>) -> ConnectionLimits {
+        match rejection {
+            Some(_) => ConnectionLimits {
+                read_timeout: Some(DRAIN_TIMEOUT),
+                write_timeout: Some(DRAIN_TIMEOUT),
+                idle_timeout: Some(DRAIN_TIMEOUT),
+                ..self.connection_limits
+            },
+            None => self.connection_limits,
+        }
+    }
+
+    ///
+    /// Error to answer a connection's served'th request with, if it takes
+    /// the connection over its request limit
+    ///
+    fn request_limit(&self, served: u64) -> Option<Exception> {
+        let max = self.connection_limits.max_requests_per_connection?;
+        (served > max).then(|| {
+            Exception::from(limit_exceeded(format!(
+                "connection exceeded the maximum of {} requests",
+                max
+            )))
+        })
+    }
+
+    ///
+    /// Let connections finish once the listener has closed, closing any
+    /// which outlast the shutdown timeout
+    ///
+    fn drain_connections(&self) {
+        info!(self.logger, "Shutting down"; "addr" => &self.addr);
+        let remaining = self.shutdown.drain(self.shutdown_timeout);
+        if remaining > 0 {
+            error!(self.logger, "Connections still open after shutdown timeout"; "connections" => remaining);
+        }
+    }
+
+    fn process_connection(&self, engine: &Engine, connection: TcpStream, rejection: Option<Exception>) -> Result<()> {
+        let peer_addr = connection.peer_addr()?.to_string();
+
+        info!(self.logger, "Received connection"; "remote_addr" => &peer_addr);
+
+        let limits = self.limits_for(&rejection);
+        connection.set_write_timeout(limits.write_timeout)?;
+        connection.set_read_timeout(limits.read_timeout)?;
+        let (reader, writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match &self.tls {
+            Some(tls) => {
+                let (reader, writer) = tls.accept(&connection)?;
+                (Box::new(reader), Box::new(writer))
+            }
+            None => (Box::new(connection.try_clone()?), Box::new(connection.try_clone()?)),
+        };
+        let mut reader = BufReader::new(reader);
+        let writer = BufWriter::new(writer);
+
+        if !self.await_request(&peer_addr, &connection, &mut reader, &limits)? {
+            return Ok(());
+        }
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic == PROTOCOL_MAGIC {
+            self.process_framed(engine, &peer_addr, &connection, reader, writer, rejection)
+        } else if self.tls.is_some() {
+            Err(unframed_over_tls())
+        } else {
+            // Clients which predate framing send bare requests, the start
+            // of which has already been read
+            let reader = Cursor::new(magic).chain(reader);
+            self.process_legacy(engine, &peer_addr, &connection, reader, writer, rejection)
+        }
+    }
+
+    ///
+    /// Wait up to the idle timeout in limits for the client to start its
+    /// next request, then allow the read timeout for the rest of it. False
+    /// if the client closed the connection or left it idle for too long
+    ///
+    fn await_request(
+        &self,
+        peer_addr: &str,
+        connection: &TcpStream,
+        reader: &mut impl BufRead,
+        limits: &ConnectionLimits,
+    ) -> Result<bool> {
+        connection.set_read_timeout(limits.idle_timeout)?;
+        match reader.fill_buf() {
+            Ok([]) => return Ok(false),
+            Ok(_) => {}
+            Err(err) if is_timeout(&err) => {
+                info!(self.logger, "Closing idle connection"; "remote_addr" => peer_addr);
+                return Ok(false);
+            }
+            Err(err) => return Err(err),
+        }
+        connection.set_read_timeout(limits.read_timeout)?;
+        Ok(true)
+    }
+
+    ///
+    /// Serve a client speaking the framed protocol, starting with the
+    /// handshake which follows the magic number.
+    ///
+    /// Requests are read and executed in the order they arrive, while their
+    /// responses are written from a separate thread. A client can therefore
+    /// keep many requests in flight without the server stalling on
+    /// responses the client has not read yet. A connection given a
+    /// rejection is refused with it in place of the handshake
+    ///
+    fn process_framed(
+        &self,
+        engine: &Engine,
+        peer_addr: &str,
+        connection: &TcpStream,
+        mut reader: impl BufRead,
+        mut writer: impl Write + Send,
+        rejection: Option<Exception>,
+    ) -> Result<()> {
+        let hello = Framing::HANDSHAKE.read(&mut reader, self.limits.max_frame_size)?;
+        let negotiated = match rejection {
+            Some(exception) => Err(exception),
+            None => self.handshake(peer_addr, hello)?,
+        };
+        writer.write_all(&self.encode_handshake(&negotiated)?)?;
+        writer.flush()?;
+        let Ok(negotiated) = negotiated else {
+            return Ok(());
+        };
+
+        let framing = Framing::for_version(negotiated.version);
+        let (replies, outbox) = mpsc::sync_channel(REPLY_QUEUE);
+        std::thread::scope(|s| {
+            let sender = s.spawn(move || self.send_replies(peer_addr, framing, writer, outbox));
+            let received = self.receive_requests(engine, peer_addr, connection, &negotiated, reader, replies);
+            let sent = sender.join().unwrap();
+            received.and(sent)
+        })
+    }
+
+    ///
+    /// Agree on a protocol version and features from the client's hello,
+    /// or give the error to refuse the connection with
+    ///
+    fn handshake(
+        &self,
+        peer_addr: &str,
+        hello: std::result::Result<Frame, OversizedFrame>,
+    ) -> Result<std::result::Result<ServerHello, Exception>> {
+        let hello = match hello {
+            Ok(frame) if frame.kind == Some(FrameKind::Hello) => frame.decode::<ClientHello>()?,
+            _ => return Ok(Err(Exception::unsupported("expected a hello frame".to_string()))),
+        };
+
+        Ok(match hello.negotiate() {
+            Ok(negotiated) => {
+                info!(self.logger, "Negotiated protocol"; "remote_addr" => peer_addr, "version" => negotiated.version, "features" => negotiated.features);
+                Ok(negotiated)
+            }
+            Err(exception) => {
+                info!(self.logger, "Rejected handshake"; "remote_addr" => peer_addr, "error" => &exception.what);
+                Err(exception)
+            }
+        })
+    }
+
+    fn encode_handshake(&self, negotiated: &std::result::Result<ServerHello, Exception>) -> Result<Vec<u8>> {
+        match negotiated {
+            Ok(hello) => Framing::HANDSHAKE.encode(FrameKind::Hello, 0, hello),
+            Err(exception) => Framing::HANDSHAKE.encode(FrameKind::Error, 0, exception),
+        }
+    }
+
+    ///
+    /// Read and execute requests until the client closes the connection,
+    /// passing the reply to each on to send_replies
+    ///
+    fn receive_requests(
+        &self,
+        engine: &Engine,
+        peer_addr: &str,
+        connection: &TcpStream,
+        negotiated: &ServerHello,
+        mut reader: impl BufRead,
+        replies: SyncSender<(u64, Reply)>,
+    ) -> Result<()> {
+        let framing = Framing::for_version(negotiated.version);
+        for served in 1.. {
+            info!(self.logger, "Waiting for request");
+
+            if !self.await_request(peer_addr, connection, &mut reader, &self.connection_limits)? {
+                return Ok(());
+            }
+            let frame = match framing.read(&mut reader, self.limits.max_frame_size) {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let (id, request) = self.parse_request(peer_addr, negotiated.features, frame);
+            if let Some(exception) = self.request_limit(served) {
+                let _ = replies.send((id, Err(exception)));
+                return Ok(());
+            }
+            let reply = request.map(|request| self.execute(engine, request));
+
+            // The sender only stops early if writing to the client failed,
+            // and reports that error itself
+            if replies.send((id, reply)).is_err() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Turn a frame read from the client into the request it carries, or
+    /// the error to answer it with. The frame has been read in full either
+    /// way, so the connection carries on after an error
+    ///
+    fn parse_request(
+        &self,
+        peer_addr: &str,
+        features: u64,
+        frame: std::result::Result<Frame, OversizedFrame>,
+    ) -> (u64, std::result::Result<Request, Exception>) {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(oversized) => {
+                let exception = Exception::from(limit_exceeded(format!(
+                    "request of {} bytes exceeds the maximum frame size of {} bytes",
+                    oversized.len, self.limits.max_frame_size
+                )));
+                return (oversized.id, Err(exception));
+            }
+        };
+
+        let request = match frame.kind {
+            Some(FrameKind::Request) => frame.decode::<Request>().map_err(|_| {
+                Exception::unsupported("unknown or malformed request".to_string())
+            }),
+            _ => Err(Exception::unsupported("expected a request frame".to_string())),
+        };
+        let request = request.and_then(|request| match request.required_features() & !features {
+            0 => Ok(request),
+            missing => Err(Exception::unsupported(format!(
+                "{} needs protocol features {:#x} which were not negotiated",
+                request, missing
+            ))),
+        });
+
+        match &request {
+            Ok(request) => {
+                info!(self.logger, "Received request"; "remote_addr" => peer_addr, "id" => frame.id, "request" => format!("{}", request))
+            }
+            Err(exception) => {
+                info!(self.logger, "Rejected request"; "remote_addr" => peer_addr, "id" => frame.id, "error" => &exception.what)
+            }
+        }
+        (frame.id, request)
+    }
+
+    ///
+    /// Write each reply as a response or error frame tagged with the ID of
+    /// its request. Replies which are already waiting are written together
+    /// before flushing, so a burst of pipelined requests is answered in as
+    /// few writes as possible
+    ///
+    fn send_replies(
+        &self,
+        peer_addr: &str,
+        framing: Framing,
+        mut writer: impl Write,
+        outbox: Receiver<(u64, Reply)>,
+    ) -> Result<()> {
+        while let Ok(first) = outbox.recv() {
+            let mut next = Some(first);
+            while let Some((id, reply)) = next {
+                writer.write_all(&self.encode_reply(peer_addr, framing, id, reply)?)?;
+                next = outbox.try_recv().ok();
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn encode_reply(&self, peer_addr: &str, framing: Framing, id: u64, reply: Reply) -> Result<Vec<u8>> {
+        match reply {
+            Ok(response) => {
+                let frame = framing.encode(FrameKind::Response, id, &response)?;
+                info!(self.logger, "Sent response"; "remote_addr" => peer_addr, "id" => id, "response" => format!("{}", response));
+                Ok(frame)
+            }
+            Err(exception) => framing.encode(FrameKind::Error, id, &exception),
+        }
+    }
+
+    ///
+    /// Serve a client which sends bare bincode requests with no framing. A
+    /// connection given a rejection is sent it in answer to its first
+    /// request, then closed
+    ///
+    fn process_legacy(
+        &self,
+        engine: &Engine,
+        peer_addr: &str,
+        connection: &TcpStream,
+        mut reader: impl BufRead,
+        mut writer: impl Write,
+        rejection: Option<Exception>,
+    ) -> Result<()> {
+        macro_rules! send_response {
+            ($resp:expr) => {{
+                let resp = $resp;
+                bincode::serialize_into(&mut writer, &resp).map_err(|e| Error::other(e))?;
+                writer.flush()?;
+                info!(self.logger, "Sent response"; "remote_addr" => peer_addr, "response" => format!("{}", resp));
+            }};
+        }
+
+        // Same encoding as bincode::deserialize_from, but bounded so an
+        // oversized request fails before its contents are allocated
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(self.limits.max_frame_size);
+
+        let limits = self.limits_for(&rejection);
+        let mut rejection = rejection;
+        for served in 1.. {
+            info!(self.logger, "Waiting for request");
+
+            if !self.await_request(peer_addr, connection, &mut reader, &limits)? {
+                return Ok(());
+            }
+            let request: Request = match options.deserialize_from(&mut reader) {
+                Ok(request) => request,
+                Err(err) if matches!(*err, bincode::ErrorKind::SizeLimit) => {
+                    // The request type is unknown at this point, but every
+                    // response encodes its Error variant identically. The
+                    // rest of the oversized request cannot be skipped
+                    // without a length prefix, so it is drained until the
+                    // client stops sending and the connection is closed.
+                    // Draining lets a client blocked on writing the request
+                    // go on to read the error
+                    send_response!(SetResponse::Error(Exception::from(limit_exceeded(format!(
+                        "request exceeds the maximum frame size of {} bytes",
+                        self.limits.max_frame_size
+                    )))));
+                    connection.set_read_timeout(Some(DRAIN_TIMEOUT))?;
+                    let _ = std::io::copy(&mut reader, &mut std::io::sink());
+                    return Ok(());
+                }
+                Err(err) => return Err(Error::other(err.to_string())),
+            };
+
+            info!(self.logger, "Received request"; "remote_addr" => peer_addr, "request" => format!("{}", request));
+
+            // Every response encodes its Error variant identically
+            if let Some(exception) = rejection.take().or_else(|| self.request_limit(served)) {
+                send_response!(SetResponse::Error(exception));
+                return Ok(());
+            }
+            send_response!(self.execute(engine, request));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Run a request against the engine, checking keys and values against
+    /// the limits first. Multi-key requests run every key in one pass, but
+    /// other connections' requests may interleave with theirs
+    ///
+    fn execute(&self, engine: &Engine, request: Request) -> Response {
+        match request {
+            Request::Set(cmd) => Response::Set(self.set(engine, cmd.key, cmd.value)),
+            Request::Get(cmd) => Response::Get(self.get(engine, cmd.key)),
+            Request::Rm(cmd) => Response::Rm(self.remove(engine, cmd.key)),
+            Request::Stats(_) => Response::Stats(match engine.stats() {
+                Ok(value) => StatsResponse::Ok(value),
+                Err(err) => StatsResponse::Error(Exception::from(err)),
+            }),
+            Request::SetCompactionRate(cmd) => Response::SetCompactionRate(
+                match engine.set_compaction_rate(cmd.bytes_per_second) {
+                    Ok(value) => SetCompactionRateResponse::Ok(value),
+                    Err(err) => SetCompactionRateResponse::Error(Exception::from(err)),
+                },
+            ),
+            Request::MultiGet(cmd) => Response::MultiGet(MultiGetResponse::Ok(
+                cmd.keys.into_iter().map(|key| self.get(engine, key)).collect(),
+            )),
+            Request::MultiSet(cmd) => Response::MultiSet(MultiSetResponse::Ok(
+                cmd.pairs
+                    .into_iter()
+                    .map(|(key, value)| self.set(engine, key, value))
+                    .collect(),
+            )),
+            Request::MultiRm(cmd) => Response::MultiRm(MultiRmResponse::Ok(
+                cmd.keys.into_iter().map(|key| self.remove(engine, key)).collect(),
+            )),
+            Request::Scan(cmd) => Response::Scan(match self.scan(engine, cmd) {
+                Ok(page) => ScanResponse::Ok(page),
+                Err(err) => ScanResponse::Error(Exception::from(err)),
+            }),
+        }
+    }
+
+    ///
+    /// Read the next page of a scan from the engine, with a cursor resuming
+    /// after its last key if the range may hold more
+    ///
+    fn scan(&self, engine: &Engine, cmd: ScanRequest) -> Result<ScanPage> {
+        for key in [&cmd.prefix, &cmd.start, &cmd.end].into_iter().flatten() {
+            self.limits.check_key(key)?;
+        }
+        let limit = (cmd.limit as usize).clamp(1, MAX_SCAN_PAGE);
+        let mut entries = engine.scan(cmd.range()?, limit)?;
+        let mut more = entries.len() == limit;
+
+        let mut bytes = 0;
+        if let Some(over) = entries.iter().position(|(key, value)| {
+            bytes += key.len() + value.len();
+            bytes > SCAN_PAGE_BYTES
+        }) {
+            // Every page makes progress, however large its first value
+            entries.truncate(over.max(1));
+            more = true;
+        }
+
+        let cursor = match more {
+            true => entries.last().map(|(key, _)| key.clone().into_bytes()),
+            false => None,
+        };
+        Ok(ScanPage { entries, cursor })
+    }
+
+    fn set(&self, engine: &Engine, key: String, value: String) -> SetResponse {
+        let checked = self
+            .limits
+            .check_key(&key)
+            .and_then(|_| self.limits.check_value(&value));
+        match checked.and_then(|_| engine.set(key, value)) {
+            Ok(value) => SetResponse::Ok(value),
+            Err(err) => SetResponse::Error(Exception::from(err)),
+        }
+    }
+
+    fn get(&self, engine: &Engine, key: String) -> GetResponse {
+        let checked = self.limits.check_key(&key);
+        match checked.and_then(|_| engine.get(key)) {
+            Ok(value) => GetResponse::Ok(value),
+            Err(err) => GetResponse::Error(Exception::from(err)),
+        }
+    }
+
+    fn remove(&self, engine: &Engine, key: String) -> RmResponse {
+        let checked = self.limits.check_key(&key);
+        match checked.and_then(|_| engine.remove(key)) {
+            Ok(value) => RmResponse::Ok(value),
+            Err(err) => RmResponse::Error(Exception::from(err)),
+        }
+    }
+}
+
+///
+/// Whether err is a socket timeout expiring, which blocking sockets report
+/// as WouldBlock on some platforms and TimedOut on others
+///
+fn is_timeout(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+///
+/// Clients which predate framing also predate TLS, so anything else sent
+/// over TLS is not a client at all
+///
+fn unframed_over_tls() -> Error {
+    Error::new(ErrorKind::InvalidData, "unframed requests are not served over TLS")
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+///
+/// How long shutdown waits to connect to the server's own listener, which
+/// only serves to wake it
+///
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+///
+/// Stops the KvsServer it was taken from. The server stops accepting
+/// connections, lets requests already read from its clients finish and
+/// returns from run once its engine is flushed
+///
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+    pub(super) fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            state: Arc::new(ShutdownState {
+                requested: AtomicBool::new(false),
+                listener: Mutex::new(None),
+                connections: Mutex::new(HashMap::new()),
+                closed: Condvar::new(),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    ///
+    /// Ask the server to shut down, returning without waiting for it to.
+    /// Safe to call from a signal handler thread, and more than once
+    ///
+    pub fn shutdown(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+
+        // The listener is blocked in accept, so give it a connection to
+        // return, after which it sees shutdown was requested
+        if let Some(addr) = *self.state.listener.lock().unwrap() {
+            let _ = TcpStream::connect_timeout(&wake_addr(addr), WAKE_TIMEOUT);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Record the address the server is listening on, so shutdown can wake
+    /// it. Shutdown may have been requested before the server was bound, so
+    /// the caller checks is_shutdown afterwards
+    ///
+    pub(super) fn listening(&self, addr: SocketAddr) {
+        *self.state.listener.lock().unwrap() = Some(addr);
+    }
+
+    ///
+    /// Track connection until the returned Connection is dropped, so a
+    /// shutdown can close it and wait for it
+    ///
+    pub(super) fn register(&self, connection: &TcpStream) -> std::io::Result<Connection> {
+        let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+        self.state.connections.lock().unwrap().insert(id, connection.try_clone()?);
+        Ok(Connection {
+            id,
+            state: self.state.clone(),
+        })
+    }
+
+    ///
+    /// Connections registered and not yet dropped
+    ///
+    pub(super) fn open_connections(&self) -> usize {
+        self.state.connections.lock().unwrap().len()
+    }
+
+    ///
+    /// Close every connection to further requests, then wait up to timeout
+    /// for them to finish the requests they have already read. Connections
+    /// still open after that are closed outright and given as long again.
+    /// Returns how many connections were left open
+    ///
+    pub(super) fn drain(&self, timeout: Duration) -> usize {
+        self.close_all(Shutdown::Read);
+        if self.wait_for_connections(timeout) == 0 {
+            return 0;
+        }
+        self.close_all(Shutdown::Both);
+        self.wait_for_connections(timeout)
+    }
+
+    fn close_all(&self, how: Shutdown) {
+        for connection in self.state.connections.lock().unwrap().values() {
+            let _ = connection.shutdown(how);
+        }
+    }
+
+    fn wait_for_connections(&self, timeout: Duration) -> usize {
+        let connections = self.state.connections.lock().unwrap();
+        let (connections, _) = self
+            .state
+            .closed
+            .wait_timeout_while(connections, timeout, |connections| !connections.is_empty())
+            .unwrap();
+        connections.len()
+    }
+}
+
+struct ShutdownState {
+    requested: AtomicBool,
+    listener: Mutex<Option<SocketAddr>>,
+    connections: Mutex<HashMap<u64, TcpStream>>,
+    closed: Condvar,
+    next_id: AtomicU64,
+}
+
+///
+/// Registration of an open connection, which ends when dropped
+///
+pub(super) struct Connection {
+    id: u64,
+    state: Arc<ShutdownState>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.state.connections.lock().unwrap().remove(&self.id);
+        self.state.closed.notify_all();
+    }
+}
+
+///
+/// Address to connect to in order to reach a listener bound to addr, which
+/// may be the unspecified address
+///
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => (Ipv4Addr::LOCALHOST, v4.port()).into(),
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => (Ipv6Addr::LOCALHOST, v6.port()).into(),
+        addr => addr,
+    }
+}
@@ -1,64 +1,68 @@
 use std::io::Result;
 use std::panic::AssertUnwindSafe;
-use std::sync::Mutex;
-use std::{sync::Arc, thread::JoinHandle};
+use std::thread::JoinHandle;
 
 use super::ThreadPool;
 
-use crossbeam::queue::SegQueue;
+use crossbeam::channel::{self, Receiver, Sender};
 
 enum Message {
     Run(Box<dyn FnOnce() + Send + 'static>),
     Shutdown,
 }
 pub struct SharedQueueThreadPool {
-    queue: Arc<SegQueue<Message>>,
+    queue: Sender<Message>,
     threads: Vec<JoinHandle<()>>,
 }
 
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
-        let _ = (0..self.threads.len()).map(|_| self.queue.push(Message::Shutdown));
-        while !self.threads.is_empty() {
-            self.threads.pop().unwrap().join().unwrap();
+        for _ in 0..self.threads.len() {
+            let _ = self.queue.send(Message::Shutdown);
+        }
+
+        // The pool may be dropped by one of its own jobs, which cannot wait
+        // for the thread it is running on
+        let current = std::thread::current().id();
+        for thread in self.threads.drain(..) {
+            if thread.thread().id() != current {
+                let _ = thread.join();
+            }
         }
     }
 }
 
 impl SharedQueueThreadPool {
-    fn thread_main(queue: Arc<SegQueue<Message>>) {
-        loop {
-            match queue.pop() {
-                Some(Message::Run(f)) => {
-                    let _ = std::panic::catch_unwind(AssertUnwindSafe(|| f()));
-                }
-                _ => {
-                    return;
-                }
-            }
+    ///
+    /// Run jobs from the queue, waiting for more whenever it is empty, until
+    /// told to shut down
+    ///
+    fn thread_main(queue: Receiver<Message>) {
+        while let Ok(Message::Run(f)) = queue.recv() {
+            let _ = std::panic::catch_unwind(AssertUnwindSafe(f));
         }
     }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(thread_count: u16) -> Result<Self> {
-        let queue = Arc::new(SegQueue::new());
+        let (sender, receiver) = channel::unbounded();
         let mut threads = Vec::new();
 
         for _ in 0..thread_count {
-            let q = queue.clone();
+            let q = receiver.clone();
             threads.push(std::thread::spawn(move || {
                 SharedQueueThreadPool::thread_main(q)
             }));
         }
 
-        Ok(SharedQueueThreadPool { queue, threads })
+        Ok(SharedQueueThreadPool { queue: sender, threads })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.queue.push(Message::Run(Box::new(job)));
+        let _ = self.queue.send(Message::Run(Box::new(job)));
     }
 }
@@ -0,0 +1,288 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+
+///
+/// Most ciphertext read from the socket at once. Plaintext is only read
+/// off the socket once the last of it has been taken, so this also bounds
+/// how much plaintext the session buffers
+///
+const READ_CHUNK: usize = 8 * 1024;
+
+///
+/// Certificate and key a server proves itself with, and optionally the CAs
+/// its clients must present certificates from
+///
+#[derive(Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    ///
+    /// Present the certificate chain in cert_path, whose private key is in
+    /// key_path. With client_ca_path, also require every client to present
+    /// a certificate issued by one of the CAs in it
+    ///
+    pub fn from_pem_files(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> Result<ServerTls> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_config)?;
+        let builder = match client_ca_path {
+            Some(client_ca_path) => {
+                let roots = Arc::new(read_roots(client_ca_path)?);
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider)
+                    .build()
+                    .map_err(invalid_config)?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(read_certs(cert_path)?, read_key(key_path)?)
+            .map_err(invalid_config)?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+
+    ///
+    /// Complete the server side of a handshake on socket
+    ///
+    pub(crate) fn accept(&self, socket: &TcpStream) -> Result<(TlsReader, TlsWriter)> {
+        let session = ServerConnection::new(self.config.clone()).map_err(invalid_data)?;
+        handshake(Connection::from(session), socket)
+    }
+
+    pub(crate) fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        tokio_rustls::TlsAcceptor::from(self.config.clone())
+    }
+}
+
+///
+/// CAs a client trusts to issue server certificates, and optionally the
+/// certificate and key it proves itself with
+///
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+}
+
+impl ClientTls {
+    ///
+    /// Trust servers with a certificate issued by one of the CAs in
+    /// ca_path. With identity, a certificate chain and private key path,
+    /// present that certificate to servers which ask for one
+    ///
+    pub fn from_pem_files(ca_path: &Path, identity: Option<(&Path, &Path)>) -> Result<ClientTls> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_config)?
+            .with_root_certificates(read_roots(ca_path)?);
+        let config = match identity {
+            Some((cert_path, key_path)) => builder
+                .with_client_auth_cert(read_certs(cert_path)?, read_key(key_path)?)
+                .map_err(invalid_config)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(ClientTls {
+            config: Arc::new(config),
+        })
+    }
+
+    ///
+    /// Complete the client side of a handshake on socket, checking the
+    /// server's certificate is for the host in addr
+    ///
+    pub(crate) fn connect(&self, addr: &str, socket: &TcpStream) -> Result<(TlsReader, TlsWriter)> {
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host.to_owned()).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+        let session = ClientConnection::new(self.config.clone(), name).map_err(invalid_data)?;
+        handshake(Connection::from(session), socket)
+    }
+}
+
+///
+/// TLS session over a socket, shared by the halves it is split into
+///
+struct Session {
+    tls: Mutex<Connection>,
+
+    // Held while ciphertext is taken from the session and written, so it
+    // reaches the socket in the order it was produced. Never waited on
+    // while holding tls
+    sending: Mutex<TcpStream>,
+}
+
+impl Session {
+    fn tls(&self) -> MutexGuard<'_, Connection> {
+        self.tls.lock().unwrap()
+    }
+
+    ///
+    /// Write out whatever ciphertext the session has waiting
+    ///
+    fn send_pending(&self) -> Result<()> {
+        let mut socket = self.sending.lock().unwrap();
+        let ciphertext = take_ciphertext(&mut self.tls())?;
+        socket.write_all(&ciphertext)
+    }
+}
+
+///
+/// Reading half of a TLS session, which never blocks the writing half
+/// while waiting on the socket
+///
+pub(crate) struct TlsReader {
+    socket: TcpStream,
+    session: Arc<Session>,
+    incoming: Box<[u8]>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.session.tls().reader().read(buf) {
+                Ok(len) => return Ok(len),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+
+            // A read of 0 still goes to the session, which then reports
+            // whether the peer closed the session cleanly
+            let len = self.socket.read(&mut self.incoming)?;
+            let mut incoming = &self.incoming[..len];
+            let mut tls = self.session.tls();
+            loop {
+                tls.read_tls(&mut incoming)?;
+                tls.process_new_packets().map_err(invalid_data)?;
+                if incoming.is_empty() {
+                    break;
+                }
+            }
+
+            // Answers to the peer, such as alerts or key updates
+            if tls.wants_write() {
+                drop(tls);
+                self.session.send_pending()?;
+            }
+        }
+    }
+}
+
+///
+/// Writing half of a TLS session. Each write is encrypted and sent before
+/// returning, so flushing has nothing left to do
+///
+pub(crate) struct TlsWriter {
+    session: Arc<Session>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut socket = self.session.sending.lock().unwrap();
+        let (len, ciphertext) = {
+            let mut tls = self.session.tls();
+            let len = tls.writer().write(buf)?;
+            (len, take_ciphertext(&mut tls)?)
+        };
+        socket.write_all(&ciphertext)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TlsWriter {
+    fn drop(&mut self) {
+        self.session.tls().send_close_notify();
+        let _ = self.session.send_pending();
+    }
+}
+
+///
+/// Drive session through its handshake on socket, then split it so one
+/// thread can read while another writes
+///
+fn handshake(mut session: Connection, socket: &TcpStream) -> Result<(TlsReader, TlsWriter)> {
+    let mut io = socket;
+    while session.is_handshaking() {
+        if session.complete_io(&mut io)? == (0, 0) {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed during TLS handshake"));
+        }
+    }
+    while session.wants_write() {
+        session.write_tls(&mut io)?;
+    }
+
+    let session = Arc::new(Session {
+        tls: Mutex::new(session),
+        sending: Mutex::new(socket.try_clone()?),
+    });
+    let reader = TlsReader {
+        socket: socket.try_clone()?,
+        session: session.clone(),
+        incoming: vec![0; READ_CHUNK].into_boxed_slice(),
+    };
+    Ok((reader, TlsWriter { session }))
+}
+
+fn take_ciphertext(tls: &mut Connection) -> Result<Vec<u8>> {
+    let mut ciphertext = Vec::new();
+    while tls.wants_write() {
+        tls.write_tls(&mut ciphertext)?;
+    }
+    Ok(ciphertext)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| invalid_pem(path, err))?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: no certificates found", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|err| invalid_pem(path, err))
+}
+
+fn read_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert).map_err(invalid_config)?;
+    }
+    Ok(roots)
+}
+
+fn invalid_pem(path: &Path, err: rustls::pki_types::pem::Error) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{}: {}", path.display(), err))
+}
+
+fn invalid_config(err: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::InvalidInput, err)
+}
+
+fn invalid_data(err: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,103 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+
+use kvs::check::check;
+use kvs::{engines::KvStore, engines::KvsEngine};
+use tempfile::TempDir;
+
+// A cleanly closed store should pass and report live vs dead records
+#[test]
+fn check_clean_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert!(report.is_ok(), "{}", report);
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(report.files[0].records, 4);
+    assert_eq!(report.files[0].live_records, 1);
+    assert_eq!(report.files[0].dead_records, 3);
+
+    Ok(())
+}
+
+// A torn record at the end of a log file should be found and truncated away
+#[test]
+fn check_repairs_corrupt_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("0.log");
+    let valid_len = log_path.metadata()?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&log_path)?
+        .write_all(&[0xff; 7])?;
+
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert!(!report.is_ok());
+    assert_eq!(report.files[0].valid_len, valid_len);
+    assert_eq!(log_path.metadata()?.len(), valid_len + 7);
+
+    let report = check(None, temp_dir.path().to_path_buf(), true)?;
+    assert_eq!(report.repairs.len(), 1);
+    assert_eq!(log_path.metadata()?.len(), valid_len);
+
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert!(report.is_ok(), "{}", report);
+
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Files which are not listed in the MANIFEST, or listed but absent, are problems
+#[test]
+fn check_detects_missing_and_extra_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    File::create(temp_dir.path().join("7.log"))?;
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert_eq!(report.problems.len(), 1);
+    assert!(report.problems[0].contains("7.log"));
+
+    std::fs::remove_file(temp_dir.path().join("7.log"))?;
+    std::fs::remove_file(temp_dir.path().join("0.log"))?;
+    let report = check(None, temp_dir.path().to_path_buf(), true)?;
+    assert!(!report.is_ok());
+    assert!(report.problems[0].contains("0.log"));
+    assert_eq!(report.repairs.len(), 1);
+
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert!(report.is_ok(), "{}", report);
+    assert!(report.files.is_empty());
+
+    Ok(())
+}
+
+// A damaged MANIFEST header should be reported rather than failing the check
+#[test]
+fn check_bad_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert!(!report.is_ok());
+
+    File::create(temp_dir.path().join("MANIFEST"))?.write_all(&[0u8; 10])?;
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert!(!report.is_ok());
+    assert!(report.problems[0].contains("magic number"));
+
+    Ok(())
+}
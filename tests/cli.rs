@@ -118,6 +118,32 @@ fn client_cli_invalid_rm() {
         .failure();
 }
 
+#[test]
+fn client_cli_invalid_multi_key() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value1", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("pairs of KEY VALUE"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mrm", "key1", "key2", "--addr", "invalid-addr"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
@@ -151,6 +177,42 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server --async-threads 0` should be rejected rather than panic
+#[test]
+fn server_cli_invalid_async_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["127.0.0.1:4009", "--mode", "async", "--async-threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+}
+
+// `kvs-server --threads` should be at least 1, and only given with a pool
+// it sizes
+#[test]
+fn server_cli_invalid_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["127.0.0.1:4010", "--pool", "shared-queue", "--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+
+    for args in [["--pool", "naive"], ["--mode", "async"]] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["127.0.0.1:4010", "--threads", "4"])
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("--threads"));
+    }
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -334,4 +396,50 @@ fn cli_access_server_kvs_engine() {
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
-}
\ No newline at end of file
+}
+
+// `kvs-server` should shut down cleanly on SIGTERM, keeping what was written
+#[cfg(unix)]
+#[test]
+fn cli_server_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("value1"));
+}
@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use kvs::durability::Durability;
+use kvs::engines::{KvStore, KvsEngine};
+use kvs::fs::{FaultyFileSystem, FileSystem};
+
+fn path() -> PathBuf {
+    PathBuf::from("/db")
+}
+
+fn open(fs: &FaultyFileSystem, durability: Durability) -> Result<KvStore> {
+    KvStore::open_with_fs(None, path(), durability, Arc::new(fs.clone()))
+}
+
+fn value(key_id: u64, iter: u64) -> String {
+    format!("{}-{}-{}", key_id, iter, "v".repeat(1024))
+}
+
+///
+/// Write iterations rounds of every key, enough for the log to seal files
+/// once more than a MiB has been written. Returns the last value written to
+/// each key
+///
+fn populate(store: &KvStore, keys: u64, iterations: u64) -> Result<HashMap<String, String>> {
+    let mut expected = HashMap::new();
+    for iter in 0..iterations {
+        for key_id in 0..keys {
+            store.set(format!("key{}", key_id), value(key_id, iter))?;
+            expected.insert(format!("key{}", key_id), value(key_id, iter));
+        }
+    }
+    Ok(expected)
+}
+
+///
+/// Write 200 keys once and then overwrite 50 of them repeatedly, leaving two
+/// sealed files which hold the only copy of the other 150 keys
+///
+fn populate_hot_and_cold(store: &KvStore) -> Result<HashMap<String, String>> {
+    let mut expected = populate(store, 200, 1)?;
+    expected.extend(populate(store, 50, 48)?);
+    Ok(expected)
+}
+
+fn verify(store: &KvStore, expected: &HashMap<String, String>) -> Result<()> {
+    for (key, value) in expected {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value), "key {}", key);
+    }
+    assert_eq!(store.keys()?.len(), expected.len());
+    Ok(())
+}
+
+fn log_files(fs: &FaultyFileSystem) -> Result<Vec<String>> {
+    let mut names: Vec<String> = fs
+        .read_dir(&path())?
+        .into_iter()
+        .filter(|name| name.ends_with(".log") || name == "MANIFEST.new")
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+// Every write acknowledged under Durability::Sync should survive a crash
+#[test]
+fn crash_keeps_synced_writes() -> Result<()> {
+    let fs = FaultyFileSystem::new();
+    let store = open(&fs, Durability::Sync)?;
+    let expected = populate(&store, 100, 2)?;
+
+    fs.crash();
+    drop(store);
+    fs.restart();
+
+    verify(&open(&fs, Durability::Sync)?, &expected)
+}
+
+// A write torn part way through by a crash should be truncated away on open,
+// leaving the records before it intact
+#[test]
+fn crash_tears_unsynced_write() -> Result<()> {
+    let fs = FaultyFileSystem::new();
+    let store = open(&fs, Durability::Sync)?;
+    let expected = populate(&store, 10, 1)?;
+    drop(store);
+
+    let store = open(&fs, Durability::Buffered)?;
+    store.set("torn".to_owned(), "v".repeat(4096))?;
+    fs.tear_writes(100);
+    fs.crash();
+    drop(store);
+    fs.restart();
+
+    let store = open(&fs, Durability::Sync)?;
+    verify(&store, &expected)?;
+
+    // The log remains writable after the torn record is removed
+    store.set("key0".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert_eq!(open(&fs, Durability::Sync)?.get("key0".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A crash at any point while creating a new store should leave a directory
+// which opens
+#[test]
+fn crash_during_first_open() -> Result<()> {
+    for operations in 0.. {
+        let fs = FaultyFileSystem::new();
+        fs.crash_after(operations);
+        let opened = open(&fs, Durability::Sync).and_then(|store| {
+            store.set("key1".to_owned(), "value1".to_owned())?;
+            Ok(store)
+        });
+        let completed = !fs.has_crashed();
+        fs.crash();
+        drop(opened);
+        fs.restart();
+
+        let store = open(&fs, Durability::Sync)?;
+        if completed {
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            return Ok(());
+        }
+    }
+    unreachable!()
+}
+
+// A crash at any point while the tail file is sealed and the MANIFEST
+// rewritten should lose no acknowledged write
+#[test]
+fn crash_during_seal() -> Result<()> {
+    for operations in 0.. {
+        let fs = FaultyFileSystem::new();
+        let store = open(&fs, Durability::Sync)?;
+        let mut expected = populate(&store, 100, 9)?;
+        assert_eq!(log_files(&fs)?, vec!["0.log"]);
+
+        // Keep writing across the 1 MiB boundary which seals 0.log
+        fs.crash_after(operations);
+        for key_id in 0..200 {
+            match store.set(format!("key{}", key_id), value(key_id, 9)) {
+                Ok(()) => {
+                    expected.insert(format!("key{}", key_id), value(key_id, 9));
+                }
+                Err(_) => break,
+            }
+        }
+        let completed = !fs.has_crashed();
+        fs.crash();
+        drop(store);
+        fs.restart();
+
+        let store = open(&fs, Durability::Sync)?;
+        verify(&store, &expected)?;
+        if completed {
+            assert_eq!(log_files(&fs)?, vec!["0.log", "1.log"]);
+            return Ok(());
+        }
+    }
+    unreachable!()
+}
+
+// A crash at any point during compaction should leave a store holding
+// exactly the data it held before, with no files left over
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    for operations in 0.. {
+        let fs = FaultyFileSystem::new();
+        let store = open(&fs, Durability::Sync)?;
+        let expected = populate_hot_and_cold(&store)?;
+        assert_eq!(log_files(&fs)?.len(), 3);
+
+        fs.crash_after(operations);
+        let compacted = store.compact();
+        let completed = !fs.has_crashed();
+        assert_eq!(compacted.is_ok(), completed);
+        fs.crash();
+        drop(store);
+        fs.restart();
+
+        let store = open(&fs, Durability::Sync)?;
+        verify(&store, &expected)?;
+        let files = log_files(&fs)?;
+        if completed {
+            assert_eq!(files, vec!["2.log", "3.log"]);
+            return Ok(());
+        }
+        assert!(
+            files == vec!["0.log", "1.log", "2.log"] || files == vec!["2.log", "3.log"],
+            "{:?} after {} operations",
+            files,
+            operations
+        );
+    }
+    unreachable!()
+}
+
+// A compaction whose MANIFEST cannot be renamed into place should fail
+// without losing data, and succeed once renames work again
+#[test]
+fn compaction_rename_failure() -> Result<()> {
+    let fs = FaultyFileSystem::new();
+    let store = open(&fs, Durability::Sync)?;
+    let expected = populate_hot_and_cold(&store)?;
+
+    fs.fail_renames(true);
+    assert!(store.compact().is_err());
+    verify(&store, &expected)?;
+
+    fs.fail_renames(false);
+    store.compact()?;
+    verify(&store, &expected)?;
+    drop(store);
+
+    // The outputs of the failed compaction are never published, and are
+    // removed on open
+    verify(&open(&fs, Durability::Sync)?, &expected)?;
+    assert_eq!(log_files(&fs)?, vec!["2.log", "4.log"]);
+    Ok(())
+}
+
+// Running out of space should fail the write with StorageFull, and the
+// partial record it leaves behind should be removed on the next open
+#[test]
+fn storage_full() -> Result<()> {
+    let fs = FaultyFileSystem::new();
+    let store = open(&fs, Durability::Sync)?;
+    fs.set_capacity(Some(64 * 1024));
+
+    let mut expected = HashMap::new();
+    let err = loop {
+        let key_id = expected.len() as u64;
+        match store.set(format!("key{}", key_id), value(key_id, 0)) {
+            Ok(()) => {
+                expected.insert(format!("key{}", key_id), value(key_id, 0));
+            }
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), ErrorKind::StorageFull);
+    verify(&store, &expected)?;
+    drop(store);
+
+    verify(&open(&fs, Durability::Sync)?, &expected)
+}
+
+// After running out of space the store should reject writes with a disk full
+// error while still serving reads, and accept writes again once space is
+// freed. The record torn by running out of space must be rolled back, or the
+// writes after recovery would be lost behind it on the next open
+#[test]
+fn disk_full_degrades_to_read_only() -> Result<()> {
+    let fs = FaultyFileSystem::new();
+    let store = open(&fs, Durability::Sync)?;
+    fs.set_capacity(Some(64 * 1024));
+
+    let mut expected = HashMap::new();
+    let err = loop {
+        let key_id = expected.len() as u64;
+        match store.set(format!("key{}", key_id), value(key_id, 0)) {
+            Ok(()) => {
+                expected.insert(format!("key{}", key_id), value(key_id, 0));
+            }
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err.kind(), ErrorKind::StorageFull);
+    assert_eq!(err.to_string(), "store read-only: disk full");
+    assert!(store.stats()?.disk_full);
+    verify(&store, &expected)?;
+
+    // Writes are rejected up front until the retry interval has passed, even
+    // once space is available
+    fs.set_capacity(None);
+    let err = store.remove("key0".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "store read-only: disk full");
+
+    std::thread::sleep(Duration::from_millis(1100));
+    store.set("key0".to_owned(), "recovered".to_owned())?;
+    expected.insert("key0".to_owned(), "recovered".to_owned());
+    assert!(!store.stats()?.disk_full);
+    verify(&store, &expected)?;
+    drop(store);
+
+    verify(&open(&fs, Durability::Sync)?, &expected)
+}
+
+// A write waiting on its sync should hold up neither reads of records
+// already written nor the write's own result once the sync completes
+#[test]
+fn reads_during_blocked_sync() -> Result<()> {
+    let fs = FaultyFileSystem::new();
+    let store = open(&fs, Durability::Sync)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fs.block_syncs(true);
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || store.set("key2".to_owned(), "value2".to_owned()))
+    };
+    while fs.blocked_syncs() == 0 {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    {
+        let store = store.clone();
+        std::thread::spawn(move || sender.send(store.get("key1".to_owned())));
+    }
+    let read = receiver.recv_timeout(Duration::from_secs(5));
+
+    fs.block_syncs(false);
+    writer.join().unwrap()?;
+    assert_eq!(read.expect("read waited on a sync")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
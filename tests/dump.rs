@@ -0,0 +1,69 @@
+use std::io::Result;
+
+use kvs::dump::{dump, DumpOptions, OperationKind};
+use kvs::{engines::KvStore, engines::KvsEngine};
+use tempfile::TempDir;
+
+fn dump_to_string(temp_dir: &TempDir, options: &DumpOptions) -> Result<String> {
+    let mut out = Vec::new();
+    dump(temp_dir.path().to_path_buf(), options, &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+// Every record should be listed, with long values truncated unless asked otherwise
+#[test]
+fn dump_all_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "a".repeat(100))?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let output = dump_to_string(&temp_dir, &DumpOptions::default())?;
+    assert!(output.contains("0.log min_index=0 max_index=1"));
+    assert!(output.contains(&format!("index=0 offset=0 set key=\"key1\" value=\"{}...\"", "a".repeat(32))));
+    assert!(output.contains("index=1"));
+    assert!(output.contains("rm key=\"key1\""));
+
+    let output = dump_to_string(
+        &temp_dir,
+        &DumpOptions {
+            full_values: true,
+            ..Default::default()
+        },
+    )?;
+    assert!(output.contains(&"a".repeat(100)));
+
+    Ok(())
+}
+
+// Filters should narrow the records printed, and JSON output should be one object per line
+#[test]
+fn dump_filtered_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i % 3), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let output = dump_to_string(
+        &temp_dir,
+        &DumpOptions {
+            key: Some("key0".to_owned()),
+            min_index: Some(3),
+            operation: Some(OperationKind::Set),
+            json: true,
+            ..Default::default()
+        },
+    )?;
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].contains("\"type\":\"file\""));
+    assert!(lines[1].contains("\"index\":3") && lines[1].contains("\"value\":\"value3\""));
+    assert!(lines[2].contains("\"index\":6"));
+    assert!(lines[3].contains("\"index\":9"));
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::io::Result;
+use std::ops::Bound;
+
+use kvs::engines::{detect_engine, record_engine, resolve_engine, KvStore, KvsEngine, SledKvStore, ENGINE_MARKER};
+use tempfile::TempDir;
+
+// The first open records the engine and later opens default to it
+#[test]
+fn engine_marker_written_on_first_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("log");
+
+    assert_eq!(detect_engine(&path)?, None);
+    assert_eq!(resolve_engine(&path, Some("sled"))?, "sled");
+    assert_eq!(detect_engine(&path)?, None);
+    drop(SledKvStore::open(&path)?);
+    record_engine(&path, "sled")?;
+    assert_eq!(detect_engine(&path)?, Some("sled".to_owned()));
+    assert!(!path.join(format!("{}.tmp", ENGINE_MARKER)).exists());
+    assert_eq!(resolve_engine(&path, None)?, "sled");
+    assert!(resolve_engine(&path, Some("kvs")).is_err());
+
+    Ok(())
+}
+
+// Directories written before the marker existed are recognised by their files
+#[test]
+fn engine_detected_without_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_path = temp_dir.path().join("kvs");
+    std::fs::create_dir(&kvs_path)?;
+    drop(KvStore::open(None, kvs_path.clone())?);
+    assert_eq!(resolve_engine(&kvs_path, None)?, "kvs");
+
+    let sled_path = temp_dir.path().join("sled");
+    drop(SledKvStore::open(&sled_path)?);
+    assert!(resolve_engine(&sled_path, Some("kvs")).is_err());
+    assert_eq!(resolve_engine(&sled_path, None)?, "sled");
+
+    assert_eq!(resolve_engine(&temp_dir.path().join("new"), None)?, "kvs");
+    assert!(resolve_engine(&temp_dir.path().join("other"), Some("rocks")).is_err());
+
+    Ok(())
+}
+
+// An empty marker, as a crash while it was first written could once leave,
+// is treated as missing and replaced when the engine is next recorded
+#[test]
+fn empty_engine_marker_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("new");
+    std::fs::create_dir(&path)?;
+    std::fs::write(path.join(ENGINE_MARKER), "")?;
+    assert_eq!(detect_engine(&path)?, None);
+    assert_eq!(resolve_engine(&path, None)?, "kvs");
+
+    let kvs_path = temp_dir.path().join("kvs");
+    std::fs::create_dir(&kvs_path)?;
+    drop(KvStore::open(None, kvs_path.clone())?);
+    std::fs::write(kvs_path.join(ENGINE_MARKER), "")?;
+    assert_eq!(resolve_engine(&kvs_path, None)?, "kvs");
+    record_engine(&kvs_path, "kvs")?;
+    assert_eq!(std::fs::read_to_string(kvs_path.join(ENGINE_MARKER))?.trim(), "kvs");
+
+    Ok(())
+}
+
+fn check_scan(engine: &impl KvsEngine) -> Result<()> {
+    for i in (0..50).rev() {
+        engine.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    engine.remove("key10".to_owned())?;
+
+    let all = engine.scan((Bound::Unbounded, Bound::Unbounded), 100)?;
+    assert_eq!(all.len(), 49);
+    assert_eq!(all[0], ("key00".to_owned(), "value0".to_owned()));
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    // A removed key does not cut a page short
+    let range = (Bound::Excluded("key05".to_owned()), Bound::Included("key20".to_owned()));
+    let keys: Vec<String> = engine.scan(range, 10)?.into_iter().map(|(key, _)| key).collect();
+    let expected: Vec<String> = [6, 7, 8, 9, 11, 12, 13, 14, 15, 16]
+        .iter()
+        .map(|i| format!("key{:02}", i))
+        .collect();
+    assert_eq!(keys, expected);
+
+    assert!(engine.scan((Bound::Included("zzz".to_owned()), Bound::Unbounded), 10)?.is_empty());
+
+    // Ranges which hold no keys at all
+    let range = (Bound::Included("key20".to_owned()), Bound::Excluded("key05".to_owned()));
+    assert!(engine.scan(range, 10)?.is_empty());
+    let range = (Bound::Excluded("key05".to_owned()), Bound::Excluded("key05".to_owned()));
+    assert!(engine.scan(range, 10)?.is_empty());
+    Ok(())
+}
+
+// Both engines scan keys in ascending order within the range given
+#[test]
+fn engine_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_path = temp_dir.path().join("kvs");
+    std::fs::create_dir(&kvs_path)?;
+    check_scan(&KvStore::open(None, kvs_path)?)?;
+    check_scan(&SledKvStore::open(temp_dir.path().join("sled"))?)
+}
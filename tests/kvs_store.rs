@@ -1,6 +1,10 @@
 use std::io::Result;
+use std::time::Duration;
 
-use kvs::{engines::KvStore, engines::KvsEngine};
+use kvs::check::check;
+use kvs::durability::Durability;
+use kvs::limits::{is_limit_exceeded, Limits};
+use kvs::{engines::KvStore, engines::KvsEngine, engines::SledKvStore};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -126,3 +130,455 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A second open of the same directory should fail while the first is alive
+#[test]
+fn open_locked_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+
+    match KvStore::open(None, temp_dir.path().to_path_buf()) {
+        Ok(_) => panic!("opened a directory which is already in use"),
+        Err(err) => assert!(err
+            .to_string()
+            .contains(&format!("in use by pid {}", std::process::id()))),
+    }
+
+    drop(store);
+    KvStore::open(None, temp_dir.path().to_path_buf())?;
+    Ok(())
+}
+
+// A read-only store should open alongside a writer, serve the data written
+// before it was opened, reject writes and leave the directory untouched
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(None, temp_dir.path().to_path_buf()).is_err());
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), 0);
+
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let manifest = std::fs::read(temp_dir.path().join("MANIFEST"))?;
+
+    let reader = KvStore::open_read_only(None, temp_dir.path().to_path_buf())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    for err in [
+        reader.set("key3".to_owned(), "value3".to_owned()).unwrap_err(),
+        reader.remove("key1".to_owned()).unwrap_err(),
+        reader.compact().unwrap_err(),
+    ] {
+        assert_eq!(err.kind(), std::io::ErrorKind::ReadOnlyFilesystem);
+    }
+
+    // Writes made after the reader opened are not seen by it
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(reader);
+    assert_eq!(std::fs::read(temp_dir.path().join("MANIFEST"))?, manifest);
+
+    drop(store);
+    let reader = KvStore::open_read_only(None, temp_dir.path().to_path_buf())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Every durability policy should persist data across a clean reopen
+#[test]
+fn reopen_with_each_durability() -> Result<()> {
+    let durabilities = [
+        Durability::Sync,
+        Durability::Periodic {
+            interval: Duration::from_millis(10),
+            bytes: 64,
+        },
+        Durability::Buffered,
+    ];
+
+    for durability in durabilities {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), durability)?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        drop(store);
+
+        let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+        drop(store);
+
+        let sled_dir = temp_dir.path().join("sled");
+        let store = SledKvStore::open_with_durability(&sled_dir, durability)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        let store = SledKvStore::open(&sled_dir)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Stats should account for live and overwritten records
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.writes_since_open, 3);
+    assert_eq!(stats.files.len(), 1);
+    assert_eq!(stats.live_bytes, 2 * stats.dead_bytes);
+    assert!((stats.files[0].garbage_ratio - 1.0 / 3.0).abs() < 1e-9);
+    assert!(stats.last_compaction.is_none());
+
+    Ok(())
+}
+
+// Engines should enforce limits even when called directly
+#[test]
+fn engine_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limits = Limits {
+        max_key_size: 8,
+        max_value_size: 8,
+        ..Limits::default()
+    };
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?.with_limits(limits);
+    assert!(is_limit_exceeded(&store.set("key".repeat(3), "value".to_owned()).unwrap_err()));
+    assert!(is_limit_exceeded(&store.set("key".to_owned(), "value".repeat(2)).unwrap_err()));
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let store = SledKvStore::open(temp_dir.path().join("sled"))?.with_limits(limits);
+    assert!(is_limit_exceeded(&store.remove("key".repeat(3)).unwrap_err()));
+    Ok(())
+}
+
+fn log_file_count(path: &std::path::Path) -> usize {
+    std::fs::read_dir(path)
+        .expect("unable to list log directory")
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .is_ok_and(|entry| entry.file_name().to_string_lossy().ends_with(".log"))
+        })
+        .count()
+}
+
+// Compaction should merge the sealed files into fewer files holding only
+// live records, and the result should be intact after a reopen
+#[test]
+fn merge_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), Durability::Buffered)?;
+
+    let padding = "v".repeat(1024);
+    for iter in 0..6 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, padding))?;
+        }
+    }
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    store.compact()?;
+    assert_eq!(log_file_count(temp_dir.path()), 2);
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 900);
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.files[0].garbage_ratio < 0.01);
+
+    for key_id in 0..1000 {
+        let expected = (key_id >= 100).then(|| format!("5{}", padding));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    drop(store);
+    let report = check(None, temp_dir.path().to_path_buf(), false)?;
+    assert!(report.is_ok(), "{}", report);
+
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    for key_id in 0..1000 {
+        let expected = (key_id >= 100).then(|| format!("5{}", padding));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// Writes made while a compaction runs, including those landing in files
+// sealed after it started, should all survive it
+#[test]
+fn compaction_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), Durability::Buffered)?;
+
+    let padding = "v".repeat(1024);
+    let writing = std::sync::atomic::AtomicUsize::new(4);
+    std::thread::scope(|scope| {
+        for writer in 0..4 {
+            let (store, padding, writing) = (store.clone(), &padding, &writing);
+            scope.spawn(move || {
+                for iter in 0..3 {
+                    for key_id in 0..1000 {
+                        let key = format!("key{}-{}", writer, key_id);
+                        store.set(key, format!("{}{}", iter, padding)).unwrap();
+                    }
+                }
+                writing.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            });
+        }
+        while writing.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+            store.compact().unwrap();
+        }
+    });
+
+    for writer in 0..4 {
+        for key_id in 0..1000 {
+            let key = format!("key{}-{}", writer, key_id);
+            assert_eq!(store.get(key)?, Some(format!("2{}", padding)));
+        }
+    }
+    Ok(())
+}
+
+// Reads racing overwrites and compactions should always find the value of
+// the key, even once the record they looked up has been compacted away
+#[test]
+fn reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), Durability::Buffered)?;
+
+    let padding = "v".repeat(1024);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("0{}", padding))?;
+    }
+
+    let writing = std::sync::atomic::AtomicBool::new(true);
+    std::thread::scope(|scope| {
+        let (writer, padding, writing) = (store.clone(), &padding, &writing);
+        scope.spawn(move || {
+            for iter in 1..6 {
+                for key_id in 0..1000 {
+                    writer.set(format!("key{}", key_id), format!("{}{}", iter, padding)).unwrap();
+                }
+                writer.compact().unwrap();
+            }
+            writing.store(false, std::sync::atomic::Ordering::SeqCst);
+        });
+        for _ in 0..4 {
+            let reader = store.clone();
+            scope.spawn(move || {
+                while writing.load(std::sync::atomic::Ordering::SeqCst) {
+                    for key_id in (0..1000).step_by(7) {
+                        assert!(reader.get(format!("key{}", key_id)).unwrap().is_some());
+                    }
+                }
+            });
+        }
+    });
+
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("5{}", padding)));
+    }
+    Ok(())
+}
+
+// Writes should carry on and survive while a background compaction runs,
+// and dropping the store should cancel a slow compaction rather than wait
+// for it to finish
+#[test]
+fn background_compaction_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), Durability::Buffered)?;
+    store.set_compaction_rate(Some(64 * 1024))?;
+
+    let padding = "v".repeat(1024);
+    std::thread::scope(|scope| {
+        for writer in 0..4 {
+            let (store, padding) = (store.clone(), &padding);
+            scope.spawn(move || {
+                for iter in 0..4 {
+                    for key_id in 0..1000 {
+                        let key = format!("key{}-{}", writer, key_id);
+                        store.set(key, format!("{}{}", iter, padding)).unwrap();
+                    }
+                }
+            });
+        }
+    });
+    assert!(store.stats()?.compaction.running);
+
+    for writer in 0..4 {
+        for key_id in 0..1000 {
+            let key = format!("key{}-{}", writer, key_id);
+            assert_eq!(store.get(key)?, Some(format!("3{}", padding)));
+        }
+    }
+
+    let start = std::time::Instant::now();
+    drop(store);
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    for writer in 0..4 {
+        for key_id in 0..1000 {
+            let key = format!("key{}-{}", writer, key_id);
+            assert_eq!(store.get(key)?, Some(format!("3{}", padding)));
+        }
+    }
+    Ok(())
+}
+
+// Files are replayed in parallel on open, and the result should match the
+// writes applied in order, including keys removed and set again in later
+// files
+#[test]
+fn reopen_replays_files_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), Durability::Buffered)?;
+
+    let padding = "v".repeat(1024);
+    let mut expected = std::collections::HashMap::new();
+    for iter in 0..8 {
+        for key_id in 0..500 {
+            let key = format!("key{}", key_id);
+            if (key_id + iter) % 3 == 0 {
+                store.remove(key.clone()).ok();
+                expected.remove(&key);
+            } else {
+                store.set(key.clone(), format!("{}{}", iter, padding))?;
+                expected.insert(key, format!("{}{}", iter, padding));
+            }
+        }
+    }
+    assert!(log_file_count(temp_dir.path()) > 1);
+    drop(store);
+
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    for key_id in 0..500 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned(), "{}", key);
+    }
+    assert_eq!(store.stats()?.key_count, expected.len() as u64);
+    Ok(())
+}
+
+// Reads served from memory maps of the sealed files should see the same
+// values as regular reads, across sealing, compaction and reopening
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), Durability::Buffered)?
+            .with_mmap_reads(true);
+
+    let padding = "v".repeat(1024);
+    for iter in 0..3 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, padding))?;
+        }
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}{}", iter, padding))
+            );
+        }
+    }
+    assert!(log_file_count(temp_dir.path()) > 2);
+
+    store.compact()?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("2{}", padding)));
+    }
+
+    let store = store.with_mmap_reads(false);
+    assert_eq!(store.get("key0".to_owned())?, Some(format!("2{}", padding)));
+    drop(store);
+
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?.with_mmap_reads(true);
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("2{}", padding)));
+    }
+
+    // Read only stores do not hold the directory lock, so keep reading
+    // through the file
+    let reader = KvStore::open_read_only(None, temp_dir.path().to_path_buf())?.with_mmap_reads(true);
+    for key_id in 0..1000 {
+        assert_eq!(reader.get(format!("key{}", key_id))?, Some(format!("2{}", padding)));
+    }
+    Ok(())
+}
+
+// Files left behind by a compaction or seal interrupted by a crash are not
+// listed in the MANIFEST, and should be removed on the next open
+#[test]
+fn open_removes_unlisted_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    std::fs::copy(temp_dir.path().join("0.log"), temp_dir.path().join("7.log"))?;
+    std::fs::write(temp_dir.path().join("MANIFEST.new"), b"torn")?;
+
+    let store = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("7.log").exists());
+    assert!(!temp_dir.path().join("MANIFEST.new").exists());
+    Ok(())
+}
+
+// A rate limited compaction should take at least as long as its I/O allows,
+// and report its progress in stats once done
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        KvStore::open_with_durability(None, temp_dir.path().to_path_buf(), Durability::Buffered)?;
+
+    let padding = "v".repeat(1024);
+    for iter in 0..3 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, padding))?;
+        }
+    }
+
+    let rate_limit = 8 * 1024 * 1024;
+    store.set_compaction_rate(Some(rate_limit))?;
+    let start = std::time::Instant::now();
+    store.compact()?;
+    let elapsed = start.elapsed();
+
+    let progress = store.stats()?.compaction;
+    assert!(!progress.running);
+    assert_eq!(progress.rate_limit, Some(rate_limit));
+    assert!(progress.files_total >= 2);
+    assert_eq!(progress.files_done, progress.files_total);
+    assert!(progress.bytes_read > progress.bytes_written);
+    assert!(progress.bytes_written > 0);
+
+    // Allow for the final partial chunk, which is never slept for
+    let throttled_bytes = progress.bytes_read + progress.bytes_written - 64 * 1024;
+    assert!(elapsed >= Duration::from_secs_f64(throttled_bytes as f64 / rate_limit as f64));
+
+    assert!(store.set_compaction_rate(Some(0)).is_err());
+    let sled_store = SledKvStore::open(temp_dir.path().join("sled"))?;
+    assert!(sled_store.set_compaction_rate(None).is_err());
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::io::{Error, ErrorKind, Result};
+
+use kvs::engines::{KvStore, KvsEngine, SledKvStore};
+use kvs::migrate::{migrate, MigrateCheckpoint};
+use tempfile::TempDir;
+
+fn checkpoint(temp_dir: &TempDir, source: &str, destination_engine: &str) -> MigrateCheckpoint {
+    MigrateCheckpoint {
+        path: temp_dir.path().join("MIGRATE"),
+        source: temp_dir.path().join(source),
+        destination_engine: destination_engine.to_owned(),
+    }
+}
+
+// Every live key should be copied, and removed keys left behind
+#[test]
+fn migrate_kvs_to_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source_dir = temp_dir.path().join("kvs");
+    std::fs::create_dir(&source_dir)?;
+
+    let source = KvStore::open(None, source_dir)?;
+    for i in 0..100 {
+        source.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    source.set("key1".to_owned(), "updated".to_owned())?;
+    source.remove("key2".to_owned())?;
+
+    let destination = SledKvStore::open(temp_dir.path().join("sled"))?;
+    let checkpoint = checkpoint(&temp_dir, "kvs", "sled");
+    let report = migrate(&source, &destination, &checkpoint, |_| Ok(()))?;
+
+    assert_eq!(report.copied, 99);
+    assert_eq!(report.verified, 99);
+    assert_eq!(destination.get("key1".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(destination.get("key2".to_owned())?, None);
+    assert_eq!(destination.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert!(!checkpoint.path.exists());
+
+    Ok(())
+}
+
+// An interrupted migration should pick up after the last checkpointed key
+#[test]
+fn migrate_resumes_after_interruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = SledKvStore::open(temp_dir.path().join("sled"))?;
+    for i in 0..1000 {
+        source.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let destination_dir = temp_dir.path().join("kvs");
+    std::fs::create_dir(&destination_dir)?;
+    let destination = KvStore::open(None, destination_dir)?;
+    let checkpoint = checkpoint(&temp_dir, "sled", "kvs");
+
+    let result = migrate(&source, &destination, &checkpoint, |_| {
+        Err(Error::other("interrupted"))
+    });
+    assert!(result.is_err());
+    assert!(checkpoint.path.exists());
+
+    let report = migrate(&source, &destination, &checkpoint, |_| Ok(()))?;
+    assert_eq!(report.skipped, 256);
+    assert_eq!(report.copied, 744);
+    assert_eq!(report.verified, 1000);
+    assert!(!checkpoint.path.exists());
+
+    Ok(())
+}
+
+// Keys removed from the source while the migration runs should be left out
+// of the destination and not counted as copied
+#[test]
+fn migrate_skips_keys_removed_during_copy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = SledKvStore::open(temp_dir.path().join("sled"))?;
+    for i in 0..1000 {
+        source.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let destination_dir = temp_dir.path().join("kvs");
+    std::fs::create_dir(&destination_dir)?;
+    let destination = KvStore::open(None, destination_dir)?;
+    let checkpoint = checkpoint(&temp_dir, "sled", "kvs");
+
+    let report = migrate(&source, &destination, &checkpoint, |progress| {
+        if progress.done == 256 {
+            source.remove("key0999".to_owned())?;
+        }
+        Ok(())
+    })?;
+    assert_eq!(report.copied, 999);
+    assert_eq!(report.verified, 999);
+    assert_eq!(destination.get("key0999".to_owned())?, None);
+    assert_eq!(destination.keys()?.len(), 999);
+
+    Ok(())
+}
+
+// A destination changed after the checkpoint was written should fail
+// verification and remove the checkpoint, so the next run copies every key
+// again and recovers
+#[test]
+fn migrate_recovers_after_failed_verification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = SledKvStore::open(temp_dir.path().join("sled"))?;
+    for i in 0..1000 {
+        source.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let destination_dir = temp_dir.path().join("kvs");
+    std::fs::create_dir(&destination_dir)?;
+    let destination = KvStore::open(None, destination_dir)?;
+    let checkpoint = checkpoint(&temp_dir, "sled", "kvs");
+
+    let mut interrupted = false;
+    let result = migrate(&source, &destination, &checkpoint, |progress| {
+        if progress.done == progress.total && !interrupted {
+            interrupted = true;
+            return Err(Error::other("interrupted"));
+        }
+        Ok(())
+    });
+    assert!(result.is_err());
+    assert!(checkpoint.path.exists());
+
+    destination.set("key0001".to_owned(), "changed".to_owned())?;
+    let err = migrate(&source, &destination, &checkpoint, |_| Ok(())).err().expect("verification passed");
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("checkpoint"), "{}", err);
+    assert!(!checkpoint.path.exists());
+
+    let report = migrate(&source, &destination, &checkpoint, |_| Ok(()))?;
+    assert_eq!(report.skipped, 0);
+    assert_eq!(report.copied, 1000);
+    assert_eq!(report.verified, 1000);
+    assert_eq!(destination.get("key0001".to_owned())?, Some("value1".to_owned()));
+    assert!(!checkpoint.path.exists());
+
+    Ok(())
+}
+
+// A checkpoint left by a migration from another source should not be
+// resumed from
+#[test]
+fn migrate_ignores_checkpoint_of_another_migration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = SledKvStore::open(temp_dir.path().join("sled"))?;
+    for i in 0..1000 {
+        source.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+
+    let destination_dir = temp_dir.path().join("kvs");
+    std::fs::create_dir(&destination_dir)?;
+    let destination = KvStore::open(None, destination_dir)?;
+    let other = checkpoint(&temp_dir, "other", "kvs");
+    let result = migrate(&source, &destination, &other, |_| Err(Error::other("interrupted")));
+    assert!(result.is_err());
+    assert!(other.path.exists());
+
+    let report = migrate(&source, &destination, &checkpoint(&temp_dir, "sled", "kvs"), |_| Ok(()))?;
+    assert_eq!(report.skipped, 0);
+    assert_eq!(report.copied, 1000);
+    assert_eq!(report.verified, 1000);
+
+    Ok(())
+}
@@ -0,0 +1,776 @@
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvs::client::{Command, KvsClient, Reply, ScanOptions};
+use kvs::engines::{KvStore, KvsEngine, SledKvStore};
+use kvs::limits::{is_limit_exceeded, ConnectionLimits, Limits};
+use kvs::server::{KvsServer, ServerMode};
+use kvs::thread_pool::rayon_thread_pool::RayonThreadPool;
+use kvs::thread_pool::shared_queue_thread_pool::SharedQueueThreadPool;
+use kvs::thread_pool::ThreadPool;
+use serde::{Deserialize, Serialize};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+// Pool threads for servers under concurrent load, one for each client
+const CONCURRENT_THREADS: u16 = 16;
+
+fn logger() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+fn start_server(addr: &str, temp_dir: &TempDir) -> Result<()> {
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let server = KvsServer::new(addr.to_owned(), logger(), engine);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+    Ok(())
+}
+
+// Stats should be served over the network
+#[test]
+fn server_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server("127.0.0.1:4100", &temp_dir)?;
+
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4100".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+
+    let stats = client.stats()?;
+    assert_eq!(stats.key_count, 1);
+    assert_eq!(stats.writes_since_open, 2);
+    assert!(stats.dead_bytes > 0);
+
+    Ok(())
+}
+
+// Oversized keys, values and requests should come back as limit errors
+#[test]
+fn server_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limits = Limits {
+        max_key_size: 16,
+        max_value_size: 128,
+        max_frame_size: 1024,
+    };
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let server = KvsServer::new("127.0.0.1:4101".to_owned(), logger(), engine).with_limits(limits);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4101".to_owned())?;
+    let err = client.set("key1".to_owned(), "v".repeat(200)).unwrap_err();
+    assert!(is_limit_exceeded(&err), "{}", err);
+    let err = client.get("k".repeat(17)).unwrap_err();
+    assert!(is_limit_exceeded(&err), "{}", err);
+
+    // The connection remains usable after a rejected key or value
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let err = client.set("key2".to_owned(), "v".repeat(1024 * 1024)).unwrap_err();
+    assert!(is_limit_exceeded(&err), "{}", err);
+
+    // Oversized requests are skipped using the frame length, so the
+    // connection remains usable after those too
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The compaction rate limit should be adjustable through the admin request
+// and reported back in stats
+#[test]
+fn server_compaction_rate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server("127.0.0.1:4102", &temp_dir)?;
+
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4102".to_owned())?;
+    assert_eq!(client.stats()?.compaction.rate_limit, None);
+
+    client.set_compaction_rate(Some(1024 * 1024))?;
+    assert_eq!(client.stats()?.compaction.rate_limit, Some(1024 * 1024));
+
+    assert!(client.set_compaction_rate(Some(0)).is_err());
+
+    client.set_compaction_rate(None)?;
+    assert_eq!(client.stats()?.compaction.rate_limit, None);
+
+    Ok(())
+}
+
+// Mirrors of the wire types, for talking to the server without KvsClient.
+// Variants must stay in the same order as in the net module
+#[derive(Serialize)]
+enum RawRequest {
+    Set { key: String, value: String },
+    Get { key: String },
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+enum RawResponse<T> {
+    Ok(T),
+    Error(RawException),
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+struct RawException {
+    kind: u32,
+    what: String,
+}
+
+#[derive(Serialize)]
+struct RawClientHello {
+    min_version: u16,
+    max_version: u16,
+    features: u64,
+}
+
+const FRAME_HELLO: u8 = 0;
+const FRAME_REQUEST: u8 = 1;
+const FRAME_RESPONSE: u8 = 2;
+const FRAME_ERROR: u8 = 3;
+
+fn write_raw_frame(stream: &mut TcpStream, kind: u8, payload: &[u8]) -> Result<()> {
+    stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+    stream.write_all(&[kind])?;
+    stream.write_all(payload)
+}
+
+fn read_raw_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header)?;
+    let mut payload = vec![0; u32::from_le_bytes(header[..4].try_into().unwrap()) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((header[4], payload))
+}
+
+fn raw_handshake(addr: &str, min_version: u16, max_version: u16) -> Result<(TcpStream, u8, Vec<u8>)> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP")?;
+    let hello = RawClientHello {
+        min_version,
+        max_version,
+        features: 0,
+    };
+    write_raw_frame(&mut stream, FRAME_HELLO, &bincode::serialize(&hello).unwrap())?;
+    let (kind, payload) = read_raw_frame(&mut stream)?;
+    Ok((stream, kind, payload))
+}
+
+// Clients which predate framing send bare requests and read bare responses,
+// and should still be served alongside framed clients
+#[test]
+fn server_legacy_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server("127.0.0.1:4103", &temp_dir)?;
+
+    let mut stream = TcpStream::connect("127.0.0.1:4103")?;
+    let set = RawRequest::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    bincode::serialize_into(&mut stream, &set).unwrap();
+    let response: RawResponse<()> = bincode::deserialize_from(&mut stream).unwrap();
+    assert_eq!(response, RawResponse::Ok(()));
+
+    let get = RawRequest::Get {
+        key: "key1".to_owned(),
+    };
+    bincode::serialize_into(&mut stream, &get).unwrap();
+    let response: RawResponse<Option<String>> = bincode::deserialize_from(&mut stream).unwrap();
+    assert_eq!(response, RawResponse::Ok(Some("value1".to_owned())));
+
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4103".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A client speaking no protocol version the server supports should be
+// refused with an error frame
+#[test]
+fn server_rejects_unsupported_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server("127.0.0.1:4104", &temp_dir)?;
+
+    let (_, kind, payload) = raw_handshake("127.0.0.1:4104", 90, 99)?;
+    assert_eq!(kind, FRAME_ERROR);
+    let exception: RawException = bincode::deserialize(&payload).unwrap();
+    assert!(exception.what.contains("no common protocol version"), "{}", exception.what);
+    Ok(())
+}
+
+// Request types the server does not know, and requests needing features
+// which were not negotiated, should be answered with an error frame while
+// the connection stays usable
+#[test]
+fn server_rejects_unknown_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server("127.0.0.1:4105", &temp_dir)?;
+
+    let (mut stream, kind, _) = raw_handshake("127.0.0.1:4105", 1, 1)?;
+    assert_eq!(kind, FRAME_HELLO);
+
+    // A request variant from some future version of the protocol
+    write_raw_frame(&mut stream, FRAME_REQUEST, &[99, 0, 0, 0, 1, 2, 3])?;
+    let (kind, _) = read_raw_frame(&mut stream)?;
+    assert_eq!(kind, FRAME_ERROR);
+
+    // Stats needs the admin feature, which this handshake did not ask for
+    write_raw_frame(&mut stream, FRAME_REQUEST, &3u32.to_le_bytes())?;
+    let (kind, _) = read_raw_frame(&mut stream)?;
+    assert_eq!(kind, FRAME_ERROR);
+
+    let get = RawRequest::Get {
+        key: "key1".to_owned(),
+    };
+    write_raw_frame(&mut stream, FRAME_REQUEST, &bincode::serialize(&get).unwrap())?;
+    let (kind, payload) = read_raw_frame(&mut stream)?;
+    assert_eq!(kind, FRAME_RESPONSE);
+    let response: RawResponse<Option<String>> = bincode::deserialize(&payload).unwrap();
+    assert_eq!(response, RawResponse::Ok(None));
+
+    // KvsClient asks for every feature, so may send Stats
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4105".to_owned())?;
+    assert_eq!(client.stats()?.key_count, 0);
+    Ok(())
+}
+
+// Pipelined commands should each get their own result, in the order they
+// were sent, with a failing command leaving the rest of the batch unaffected
+#[test]
+fn server_pipelining() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let limits = Limits {
+        max_key_size: 16,
+        ..Limits::default()
+    };
+    let server = KvsServer::new("127.0.0.1:4106".to_owned(), logger(), engine).with_limits(limits);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4106".to_owned())?;
+
+    // Enough values that neither side can buffer the whole batch
+    let value = "v".repeat(1024);
+    let sets = (0..2000)
+        .map(|i| Command::Set(format!("key{}", i), value.clone()))
+        .collect();
+    let replies = client.pipeline(sets)?;
+    assert_eq!(replies.len(), 2000);
+    assert!(replies.iter().all(|reply| matches!(reply, Ok(Reply::Set))));
+
+    let replies = client.pipeline(vec![
+        Command::Get("key1".to_owned()),
+        Command::Rm("key1".to_owned()),
+        Command::Get("key1".to_owned()),
+        Command::Get("k".repeat(17)),
+        Command::Set("key1".to_owned(), "again".to_owned()),
+        Command::Get("key1".to_owned()),
+        Command::Get("key1999".to_owned()),
+    ])?;
+    assert_eq!(replies[0].as_ref().unwrap(), &Reply::Get(Some(value.clone())));
+    assert_eq!(replies[1].as_ref().unwrap(), &Reply::Rm);
+    assert_eq!(replies[2].as_ref().unwrap(), &Reply::Get(None));
+    assert!(is_limit_exceeded(replies[3].as_ref().unwrap_err()));
+    assert_eq!(replies[4].as_ref().unwrap(), &Reply::Set);
+    assert_eq!(replies[5].as_ref().unwrap(), &Reply::Get(Some("again".to_owned())));
+    assert_eq!(replies[6].as_ref().unwrap(), &Reply::Get(Some(value)));
+
+    // The connection is still usable for single requests afterwards
+    assert_eq!(client.get("key2".to_owned())?.map(|v| v.len()), Some(1024));
+    Ok(())
+}
+
+// From protocol version 2 every frame carries a correlation ID, which the
+// server echoes back on the frame answering it
+#[test]
+fn server_echoes_correlation_ids() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server("127.0.0.1:4107", &temp_dir)?;
+
+    let (mut stream, kind, _) = raw_handshake("127.0.0.1:4107", 2, 2)?;
+    assert_eq!(kind, FRAME_HELLO);
+
+    let set = RawRequest::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    let get = RawRequest::Get {
+        key: "key1".to_owned(),
+    };
+    let write_frame = |stream: &mut TcpStream, kind: u8, id: u64, payload: &[u8]| -> Result<()> {
+        stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+        stream.write_all(&[kind])?;
+        stream.write_all(&id.to_le_bytes())?;
+        stream.write_all(payload)
+    };
+    write_frame(&mut stream, FRAME_REQUEST, 42, &bincode::serialize(&set).unwrap())?;
+    write_frame(&mut stream, FRAME_REQUEST, 7, &[99, 0, 0, 0])?;
+    write_frame(&mut stream, FRAME_REQUEST, 1 << 40, &bincode::serialize(&get).unwrap())?;
+
+    let mut read_frame = || -> Result<(u8, u64, Vec<u8>)> {
+        let mut header = [0u8; 13];
+        stream.read_exact(&mut header)?;
+        let mut payload = vec![0; u32::from_le_bytes(header[..4].try_into().unwrap()) as usize];
+        stream.read_exact(&mut payload)?;
+        Ok((header[4], u64::from_le_bytes(header[5..].try_into().unwrap()), payload))
+    };
+    let (kind, id, _) = read_frame()?;
+    assert_eq!((kind, id), (FRAME_RESPONSE, 42));
+    let (kind, id, _) = read_frame()?;
+    assert_eq!((kind, id), (FRAME_ERROR, 7));
+    let (kind, id, payload) = read_frame()?;
+    assert_eq!((kind, id), (FRAME_RESPONSE, 1 << 40));
+    let response: RawResponse<Option<String>> = bincode::deserialize(&payload).unwrap();
+    assert_eq!(response, RawResponse::Ok(Some("value1".to_owned())));
+    Ok(())
+}
+
+// Multi-key requests should return a result for each key, in order, with a
+// failing key leaving the others unaffected
+#[test]
+fn server_multi_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let limits = Limits {
+        max_key_size: 16,
+        ..Limits::default()
+    };
+    let server = KvsServer::new("127.0.0.1:4108".to_owned(), logger(), engine).with_limits(limits);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4108".to_owned())?;
+
+    let results = client.multi_set(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("k".repeat(17), "value".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key3".to_owned(), "value3".to_owned()),
+    ])?;
+    assert_eq!(results.len(), 4);
+    assert!(results[0].is_ok());
+    assert!(is_limit_exceeded(results[1].as_ref().unwrap_err()));
+    assert!(results[2].is_ok() && results[3].is_ok());
+
+    let results = client.multi_rm(vec!["key3".to_owned(), "k".repeat(17)])?;
+    assert!(results[0].is_ok());
+    assert!(is_limit_exceeded(results[1].as_ref().unwrap_err()));
+
+    let keys = vec!["key2", "key1", "missing", "key3"];
+    let results = client.multi_get(keys.into_iter().map(str::to_owned).collect())?;
+    let values: Vec<Option<String>> = results.into_iter().collect::<Result<_>>()?;
+    assert_eq!(
+        values,
+        vec![Some("value2".to_owned()), Some("value1".to_owned()), None, None]
+    );
+
+    assert!(client.multi_get(vec![])?.is_empty());
+    Ok(())
+}
+
+// Scans should visit every selected key exactly once and in order, however
+// the keys fall across pages
+#[test]
+fn server_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server("127.0.0.1:4109", &temp_dir)?;
+
+    let mut client = KvsClient::new(logger(), "127.0.0.1:4109".to_owned())?;
+    let pairs: Vec<(String, String)> = (0..250)
+        .map(|i| (format!("{}{:03}", ["a", "b"][i % 2], i), format!("value{}", i)))
+        .collect();
+    client.multi_set(pairs.clone())?;
+    client.set("b\u{10FFFF}".to_owned(), "last".to_owned())?;
+    client.set("c".to_owned(), "after".to_owned())?;
+
+    let keys = |client: &mut KvsClient, options: ScanOptions| -> Result<Vec<String>> {
+        client.scan(options).map(|entry| entry.map(|(key, _)| key)).collect()
+    };
+
+    let entries: Vec<(String, String)> = client.scan(ScanOptions::new().with_page_size(7)).collect::<Result<_>>()?;
+    assert_eq!(entries.len(), 252);
+    assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(entries[0], ("a000".to_owned(), "value0".to_owned()));
+
+    let prefixed = keys(&mut client, ScanOptions::new().with_prefix("b".to_owned()).with_page_size(10))?;
+    assert_eq!(prefixed.len(), 126);
+    assert!(prefixed.iter().all(|key| key.starts_with('b')));
+    assert_eq!(prefixed.last().unwrap(), "b\u{10FFFF}");
+
+    let ranged = keys(
+        &mut client,
+        ScanOptions::new()
+            .with_prefix("a".to_owned())
+            .with_start("a100".to_owned())
+            .with_end("a110".to_owned()),
+    )?;
+    assert_eq!(ranged, vec!["a100", "a102", "a104", "a106", "a108"]);
+
+    assert!(keys(&mut client, ScanOptions::new().with_prefix("x".to_owned()))?.is_empty());
+
+    // Pages of large values are cut short to keep responses bounded
+    let big = "v".repeat(3 * 1024 * 1024);
+    client.multi_set((0..3).map(|i| (format!("big{}", i), big.clone())).collect())?;
+    let values: Vec<usize> = client
+        .scan(ScanOptions::new().with_prefix("big".to_owned()))
+        .map(|entry| entry.map(|(_, value)| value.len()))
+        .collect::<Result<_>>()?;
+    assert_eq!(values, vec![big.len(); 3]);
+    Ok(())
+}
+
+// The async server should serve many connections from a single worker
+// thread, including pipelined requests, oversized requests and clients
+// which predate framing
+#[test]
+fn server_async() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let limits = Limits {
+        max_frame_size: 64 * 1024,
+        ..Limits::default()
+    };
+    let server = KvsServer::new("127.0.0.1:4110".to_owned(), logger(), engine)
+        .with_limits(limits)
+        .with_mode(ServerMode::Async { worker_threads: 1 });
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut clients = (0..200)
+        .map(|_| KvsClient::new(logger(), "127.0.0.1:4110".to_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, client) in clients.iter_mut().enumerate() {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for (i, client) in clients.iter_mut().enumerate().rev() {
+        assert_eq!(client.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    let client = &mut clients[0];
+    let replies = client.pipeline(
+        (0..2000)
+            .map(|i| Command::Set(format!("key{}", i), "v".repeat(1024)))
+            .collect(),
+    )?;
+    assert!(replies.iter().all(|reply| matches!(reply, Ok(Reply::Set))));
+
+    let err = client.set("big".to_owned(), "v".repeat(128 * 1024)).unwrap_err();
+    assert!(is_limit_exceeded(&err), "{}", err);
+    assert_eq!(client.get("key1999".to_owned())?.map(|v| v.len()), Some(1024));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4110")?;
+    let get = RawRequest::Get {
+        key: "key3".to_owned(),
+    };
+    bincode::serialize_into(&mut stream, &get).unwrap();
+    let response: RawResponse<Option<String>> = bincode::deserialize_from(&mut stream).unwrap();
+    assert_eq!(response, RawResponse::Ok(Some("v".repeat(1024))));
+    Ok(())
+}
+
+fn check_pool<P: ThreadPool + Send + Sync + 'static>(addr: &str) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let server = KvsServer::new(addr.to_owned(), logger(), engine).with_pool(P::new(2)?);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    // As many connections at once as the pool has threads
+    let mut first = KvsClient::new(logger(), addr.to_owned())?;
+    let mut second = KvsClient::new(logger(), addr.to_owned())?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(second.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(first);
+    drop(second);
+
+    // Threads are returned to the pool as connections close
+    for i in 0..10 {
+        let mut client = KvsClient::new(logger(), addr.to_owned())?;
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let mut client = KvsClient::new(logger(), addr.to_owned())?;
+    assert_eq!(client.multi_get((0..10).map(|i| format!("key{}", i)).collect())?.len(), 10);
+    Ok(())
+}
+
+// The threaded server should run its connections on any ThreadPool
+#[test]
+fn server_thread_pools() -> Result<()> {
+    check_pool::<SharedQueueThreadPool>("127.0.0.1:4111")?;
+    check_pool::<RayonThreadPool>("127.0.0.1:4112")
+}
+
+// Run many clients against the server at once, each checking it reads its
+// own writes while all of them also write to a few shared keys, then check
+// the final state from a fresh connection
+fn check_concurrent_clients(addr: &str) -> Result<()> {
+    const CLIENTS: usize = 16;
+    const KEYS: usize = 100;
+    const SHARED: usize = 8;
+
+    let workers = (0..CLIENTS)
+        .map(|t| {
+            let addr = addr.to_owned();
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::new(logger(), addr)?;
+                for i in 0..KEYS {
+                    let key = format!("client{}-key{}", t, i);
+                    client.set(key.clone(), format!("value{}-{}", t, i))?;
+                    assert_eq!(client.get(key.clone())?, Some(format!("value{}-{}", t, i)));
+                    if i % 3 == 0 {
+                        client.rm(key.clone())?;
+                        assert_eq!(client.get(key)?, None);
+                    }
+                    client.set(format!("shared{}", i % SHARED), format!("client{}-{}", t, i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::new(logger(), addr.to_owned())?;
+    for t in 0..CLIENTS {
+        for i in 0..KEYS {
+            let expected = (i % 3 != 0).then(|| format!("value{}-{}", t, i));
+            assert_eq!(client.get(format!("client{}-key{}", t, i))?, expected);
+        }
+    }
+
+    // Each shared key holds the last write any client made to it
+    for s in 0..SHARED {
+        let value = client.get(format!("shared{}", s))?.expect("shared key was written");
+        let (t, i) = value.strip_prefix("client").and_then(|v| v.split_once('-')).unwrap();
+        let (t, i): (usize, usize) = (t.parse().unwrap(), i.parse().unwrap());
+        assert!(t < CLIENTS && i < KEYS && i % SHARED == s, "{}", value);
+    }
+
+    let removed = KEYS.div_ceil(3);
+    assert_eq!(client.stats()?.key_count as usize, CLIENTS * (KEYS - removed) + SHARED);
+    Ok(())
+}
+
+// Connections should run requests concurrently without losing or mixing up
+// writes, whichever engine and mode serve them
+#[test]
+fn server_concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let server = KvsServer::new("127.0.0.1:4113".to_owned(), logger(), engine)
+        .with_pool(SharedQueueThreadPool::new(CONCURRENT_THREADS)?);
+    thread::spawn(move || server.run());
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvStore::open(sled_dir.path())?;
+    let server = KvsServer::new("127.0.0.1:4114".to_owned(), logger(), engine)
+        .with_pool(RayonThreadPool::new(CONCURRENT_THREADS)?);
+    thread::spawn(move || server.run());
+
+    let async_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, async_dir.path().to_path_buf())?;
+    let server = KvsServer::new("127.0.0.1:4115".to_owned(), logger(), engine)
+        .with_mode(ServerMode::Async { worker_threads: 4 });
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    check_concurrent_clients("127.0.0.1:4113")?;
+    check_concurrent_clients("127.0.0.1:4114")?;
+    check_concurrent_clients("127.0.0.1:4115")
+}
+
+fn check_shutdown(addr: &str, mode: ServerMode) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let server = KvsServer::new(addr.to_owned(), logger(), engine).with_mode(mode);
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut idle = KvsClient::new(logger(), addr.to_owned())?;
+    let mut client = KvsClient::new(logger(), addr.to_owned())?;
+    let replies = client.pipeline(
+        (0..100)
+            .map(|i| Command::Set(format!("key{}", i), format!("value{}", i)))
+            .collect(),
+    )?;
+    assert!(replies.iter().all(|reply| matches!(reply, Ok(Reply::Set))));
+
+    // Open connections, busy or idle, are closed rather than waited on
+    shutdown.shutdown();
+    running.join().unwrap()?;
+    assert!(shutdown.is_shutdown());
+    assert!(idle.get("key1".to_owned()).is_err());
+    assert!(KvsClient::new(logger(), addr.to_owned()).is_err());
+
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    for i in 0..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A shutdown handle should stop a running server, which returns once its
+// connections have closed and its engine is flushed
+#[test]
+fn server_shutdown() -> Result<()> {
+    check_shutdown("127.0.0.1:4116", ServerMode::Threaded)?;
+    check_shutdown("127.0.0.1:4117", ServerMode::Async { worker_threads: 2 })?;
+
+    // Shutting down before the server runs stops it as soon as it starts
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let server = KvsServer::new("127.0.0.1:4118".to_owned(), logger(), engine);
+    server.shutdown_handle().shutdown();
+    server.run()
+}
+
+fn check_connection_limits(addr: &str, mode: ServerMode) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let limits = ConnectionLimits {
+        read_timeout: Some(Duration::from_millis(300)),
+        write_timeout: Some(Duration::from_millis(300)),
+        idle_timeout: Some(Duration::from_millis(500)),
+        max_connections: Some(2),
+        max_requests_per_connection: Some(5),
+    };
+    let server = KvsServer::new(addr.to_owned(), logger(), engine)
+        .with_connection_limits(limits)
+        .with_mode(mode);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    // Connections beyond the maximum are turned away, framed or not
+    let mut first = KvsClient::new(logger(), addr.to_owned())?;
+    let mut second = KvsClient::new(logger(), addr.to_owned())?;
+    let err = KvsClient::new(logger(), addr.to_owned()).err().expect("third connection was accepted");
+    assert!(is_limit_exceeded(&err), "{}", err);
+    let mut stream = TcpStream::connect(addr)?;
+    let get = RawRequest::Get {
+        key: "key1".to_owned(),
+    };
+    bincode::serialize_into(&mut stream, &get).unwrap();
+    let response: RawResponse<Option<String>> = bincode::deserialize_from(&mut stream).unwrap();
+    assert!(matches!(response, RawResponse::Error(RawException { kind: 1, .. })), "{:?}", response);
+
+    // A connection is closed after answering the request over its limit
+    for i in 0..5 {
+        first.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let err = first.get("key1".to_owned()).unwrap_err();
+    assert!(is_limit_exceeded(&err), "{}", err);
+    assert!(first.get("key1".to_owned()).is_err());
+    thread::sleep(Duration::from_millis(100));
+    let mut third = KvsClient::new(logger(), addr.to_owned())?;
+    assert_eq!(third.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    // Idle connections, and those stalled partway through a request, are
+    // reaped
+    drop(third);
+    thread::sleep(Duration::from_millis(100));
+    let mut stalled = TcpStream::connect(addr)?;
+    stalled.write_all(b"KVSP\x10\x00")?;
+    thread::sleep(Duration::from_millis(1000));
+    assert!(matches!(stalled.read(&mut [0u8; 16]), Ok(0) | Err(_)));
+    assert!(second.get("key1".to_owned()).is_err());
+    let mut fourth = KvsClient::new(logger(), addr.to_owned())?;
+    assert_eq!(fourth.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Connections should be capped, limited in the requests they make and
+// closed once idle or stalled for too long
+#[test]
+fn server_connection_limits() -> Result<()> {
+    check_connection_limits("127.0.0.1:4119", ServerMode::Threaded)?;
+    check_connection_limits("127.0.0.1:4120", ServerMode::Async { worker_threads: 2 })
+}
+
+fn check_connection_flood(addr: &str, mode: ServerMode) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let limits = ConnectionLimits {
+        max_connections: Some(1),
+        ..ConnectionLimits::default()
+    };
+    let server = KvsServer::new(addr.to_owned(), logger(), engine)
+        .with_connection_limits(limits)
+        .with_mode(mode);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    // Connections over the limit which never send a request are closed
+    // well before the idle timeout, whether or not they were sent an error
+    let mut client = KvsClient::new(logger(), addr.to_owned())?;
+    let flood = (0..200)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    let start = Instant::now();
+    for mut stream in flood {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        match stream.read(&mut [0u8; 16]) {
+            Ok(0) => {}
+            Ok(_) => panic!("connection sent a reply without a request"),
+            Err(err) => assert!(
+                !matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+                "connection left open"
+            ),
+        }
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A flood of connections over the limit should be closed promptly, rather
+// than each waiting out the idle timeout on a thread or task of its own
+#[test]
+fn server_connection_flood() -> Result<()> {
+    check_connection_flood("127.0.0.1:4126", ServerMode::Threaded)?;
+    check_connection_flood("127.0.0.1:4127", ServerMode::Async { worker_threads: 2 })
+}
+
+// With a pool no larger than the connection limit, connections turned away
+// should still hear why, and connections waiting for a pool thread should
+// not hold up shutdown
+#[test]
+fn server_connection_limits_fixed_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let limits = ConnectionLimits {
+        max_connections: Some(2),
+        ..ConnectionLimits::default()
+    };
+    let server = KvsServer::new("127.0.0.1:4125".to_owned(), logger(), engine)
+        .with_connection_limits(limits)
+        .with_pool(SharedQueueThreadPool::new(1)?);
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+
+    // The first connection takes the only thread, leaving the second queued
+    let mut busy = KvsClient::new(logger(), "127.0.0.1:4125".to_owned())?;
+    busy.set("key1".to_owned(), "value1".to_owned())?;
+    let queued = TcpStream::connect("127.0.0.1:4125")?;
+    thread::sleep(Duration::from_millis(100));
+    let err = KvsClient::new(logger(), "127.0.0.1:4125".to_owned()).err().expect("third connection was accepted");
+    assert!(is_limit_exceeded(&err), "{}", err);
+
+    // The engine is released once run returns, so the store can be opened
+    shutdown.shutdown();
+    running.join().unwrap()?;
+    drop(queued);
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::io::Result;
+use std::time::Duration;
 
 use kvs::thread_pool::ThreadPool;
 use kvs::thread_pool::shared_queue_thread_pool::SharedQueueThreadPool;
@@ -70,4 +71,12 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
-}
\ No newline at end of file
+}
+
+// Threads should wait for jobs while the queue is empty rather than exit
+#[test]
+fn shared_queue_thread_pool_idle() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    std::thread::sleep(Duration::from_millis(100));
+    spawn_counter(pool)
+}
@@ -0,0 +1,213 @@
+use std::fs;
+use std::io::Result;
+use std::path::PathBuf;
+use std::process::Command as Process;
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use kvs::client::{Command, KvsClient, Reply};
+use kvs::engines::KvStore;
+use kvs::server::{KvsServer, ServerMode};
+use kvs::tls::{ClientTls, ServerTls};
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+fn logger() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+// Certificates and keys written out as PEM files, as an operator would
+// provide them
+struct Pki {
+    dir: TempDir,
+}
+
+impl Pki {
+    fn new() -> Pki {
+        Pki {
+            dir: TempDir::new().expect("unable to create temporary working directory"),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    // Self-signed CA, written to <name>.pem
+    fn ca(&self, name: &str) -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        fs::write(self.path(&format!("{}.pem", name)), ca.pem()).unwrap();
+        ca
+    }
+
+    // Certificate issued by ca for localhost, written to <name>.pem with
+    // its key in <name>-key.pem
+    fn issue(&self, ca: &CertifiedIssuer<'static, KeyPair>, name: &str, usage: ExtendedKeyUsagePurpose) {
+        let mut params = CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca).unwrap();
+        fs::write(self.path(&format!("{}.pem", name)), cert.pem()).unwrap();
+        fs::write(self.path(&format!("{}-key.pem", name)), key.serialize_pem()).unwrap();
+    }
+
+    fn server_tls(&self, client_ca: Option<&str>) -> Result<ServerTls> {
+        let client_ca = client_ca.map(|ca| self.path(&format!("{}.pem", ca)));
+        ServerTls::from_pem_files(&self.path("server.pem"), &self.path("server-key.pem"), client_ca.as_deref())
+    }
+
+    fn client_tls(&self, ca: &str, identity: Option<&str>) -> Result<ClientTls> {
+        let identity = identity.map(|name| (self.path(&format!("{}.pem", name)), self.path(&format!("{}-key.pem", name))));
+        let identity = identity.as_ref().map(|(cert, key)| (cert.as_path(), key.as_path()));
+        ClientTls::from_pem_files(&self.path(&format!("{}.pem", ca)), identity)
+    }
+}
+
+// CA "ca" issuing a server certificate and a client certificate, and an
+// unrelated CA "other" issuing another client certificate
+fn pki() -> Pki {
+    let pki = Pki::new();
+    let ca = pki.ca("ca");
+    pki.issue(&ca, "server", ExtendedKeyUsagePurpose::ServerAuth);
+    pki.issue(&ca, "client", ExtendedKeyUsagePurpose::ClientAuth);
+    let other = pki.ca("other");
+    pki.issue(&other, "stranger", ExtendedKeyUsagePurpose::ClientAuth);
+    pki
+}
+
+fn start_server(addr: &str, mode: ServerMode, tls: ServerTls) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(None, temp_dir.path().to_path_buf())?;
+    let server = KvsServer::new(addr.to_owned(), logger(), engine).with_mode(mode).with_tls(tls);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(200));
+    Ok(temp_dir)
+}
+
+fn check_tls(addr: &str, mode: ServerMode) -> Result<()> {
+    let pki = pki();
+    let _data = start_server(addr, mode, pki.server_tls(None)?)?;
+
+    let tls = pki.client_tls("ca", None)?;
+    let mut client = KvsClient::connect(logger(), addr.to_owned(), Some(&tls))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Pipelined requests and responses cross in both directions at once
+    let replies = client.pipeline(
+        (0..500)
+            .map(|i| Command::Set(format!("key{}", i), "v".repeat(4096)))
+            .collect(),
+    )?;
+    assert!(replies.iter().all(|reply| matches!(reply, Ok(Reply::Set))));
+    assert_eq!(client.get("key499".to_owned())?.map(|v| v.len()), Some(4096));
+
+    // Plaintext clients, and clients not trusting the server's CA, are
+    // refused
+    assert!(KvsClient::new(logger(), addr.to_owned()).is_err());
+    let untrusting = pki.client_tls("other", None)?;
+    assert!(KvsClient::connect(logger(), addr.to_owned(), Some(&untrusting)).is_err());
+    assert_eq!(client.get("key1".to_owned())?.map(|v| v.len()), Some(4096));
+    Ok(())
+}
+
+fn check_mutual_tls(addr: &str, mode: ServerMode) -> Result<()> {
+    let pki = pki();
+    let _data = start_server(addr, mode, pki.server_tls(Some("ca"))?)?;
+
+    let tls = pki.client_tls("ca", Some("client"))?;
+    let mut client = KvsClient::connect(logger(), addr.to_owned(), Some(&tls))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Clients without a certificate, or with one from another CA, are
+    // refused
+    for identity in [None, Some("stranger")] {
+        let tls = pki.client_tls("ca", identity)?;
+        let refused = KvsClient::connect(logger(), addr.to_owned(), Some(&tls))
+            .and_then(|mut client| client.get("key1".to_owned()));
+        assert!(refused.is_err(), "{:?} was accepted", identity);
+    }
+    Ok(())
+}
+
+// Traffic should be encrypted with the server's certificate when the
+// server is given one, in either mode
+#[test]
+fn server_tls() -> Result<()> {
+    check_tls("127.0.0.1:4121", ServerMode::Threaded)?;
+    check_tls("127.0.0.1:4122", ServerMode::Async { worker_threads: 2 })
+}
+
+// Servers given a client CA should only serve clients with a certificate
+// issued by it
+#[test]
+fn server_mutual_tls() -> Result<()> {
+    check_mutual_tls("127.0.0.1:4123", ServerMode::Threaded)?;
+    check_mutual_tls("127.0.0.1:4124", ServerMode::Async { worker_threads: 2 })
+}
+
+// Unreadable certificates and keys should be reported up front
+#[test]
+fn tls_invalid_pem() {
+    let pki = pki();
+    fs::write(pki.path("empty.pem"), "").unwrap();
+    assert!(ServerTls::from_pem_files(&pki.path("empty.pem"), &pki.path("server-key.pem"), None).is_err());
+    assert!(ServerTls::from_pem_files(&pki.path("server.pem"), &pki.path("missing.pem"), None).is_err());
+    assert!(pki.client_tls("empty", None).is_err());
+}
+
+// `kvs-server` and `kvs-client` should talk mutual TLS when given
+// certificates on the command line
+#[test]
+fn cli_tls() {
+    let pki = pki();
+    let temp_dir = TempDir::new().unwrap();
+    let path = |name: &str| pki.path(name).to_str().unwrap().to_owned();
+
+    let mut server = Process::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["127.0.0.1:4008", "--tls-cert", &path("server.pem"), "--tls-key", &path("server-key.pem")])
+        .args(["--tls-ca", &path("ca.pem")])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let tls_args = ["--tls-ca", &path("ca.pem"), "--tls-cert", &path("client.pem"), "--tls-key", &path("client-key.pem")];
+    let set = Process::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4008"])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    let get = Process::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4008"])
+        .args(tls_args)
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    let plaintext = Process::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    assert!(set.status.success(), "{}", String::from_utf8_lossy(&set.stderr));
+    assert!(get.status.success());
+    assert!(String::from_utf8_lossy(&get.stdout).contains("value1"));
+    assert!(!plaintext.status.success());
+}